@@ -1,42 +1,77 @@
 use crate::allocator::bump::BumpAllocator;
+#[cfg(feature = "alloc-debug")]
+use crate::allocator::debug::DebugAllocator;
+#[cfg(feature = "alloc-external")]
+use crate::allocator::external::ExternalAllocator;
 use crate::allocator::fixed_size_block::FixedSizeBlockAllocator;
 use crate::allocator::linked_list::LinkedListAllocator;
+use crate::allocator::stats::HeapStats;
+use crate::memory::vmm::{self, VmError};
+use crate::serial_println;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-use linked_list_allocator::LockedHeap;
-use x86_64::structures::paging::mapper::MapToError;
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::structures::paging::PageTableFlags;
 use x86_64::VirtAddr;
 
 pub mod bump;
+pub mod debug;
+pub mod external;
+pub mod fallible;
 pub mod fixed_size_block;
+pub mod leak;
 pub mod linked_list;
+pub mod slab;
+pub mod stats;
 
+// The global allocator is selected with one of the `alloc-*` cargo features.
+#[cfg(feature = "alloc-bump")]
+type GlobalAllocator = BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+type GlobalAllocator = LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+type GlobalAllocator = FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-external")]
+type GlobalAllocator = ExternalAllocator;
+
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+    feature = "alloc-external"
+)))]
+compile_error!("no global allocator selected, enable one of the `alloc-*` features");
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-bump", feature = "alloc-external"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-external"),
+    all(feature = "alloc-fixed-block", feature = "alloc-external")
+))]
+compile_error!(
+    "only one `alloc-*` feature may be enabled, use `--no-default-features` to replace the default"
+);
+
+#[cfg_attr(not(feature = "alloc-debug"), global_allocator)]
+static ALLOCATOR: Locked<GlobalAllocator> = Locked::new(GlobalAllocator::new());
+
+/// With `alloc-debug`, allocations go through a `DebugAllocator` that checks
+/// for heap corruption. Heap statistics then include its bookkeeping overhead.
+#[cfg(feature = "alloc-debug")]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static DEBUG_ALLOCATOR: DebugAllocator<Locked<GlobalAllocator>> = DebugAllocator::new(&ALLOCATOR);
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+/// Reserves and maps the heap region and initializes the global allocator.
+///
+/// Requires `memory::vmm::init`.
+pub fn init_heap() -> Result<(), VmError> {
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    let region = vmm::reserve_at("heap", heap_start, vmm::pages_for(HEAP_SIZE as u64))?;
+    vmm::map_region(&region, PageTableFlags::WRITABLE)?;
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
@@ -45,6 +80,33 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Serves small allocations from slabs instead of the heap, see
+/// `FixedSizeBlockAllocator::enable_slabs`.
+#[cfg(feature = "alloc-fixed-block")]
+pub fn enable_slabs() {
+    ALLOCATOR.lock().enable_slabs();
+    ALLOCATOR.rebalance_slab_frames();
+}
+
+/// Returns cached blocks to the heap, see `FixedSizeBlockAllocator::reclaim`.
+///
+/// This already happens automatically before an allocation fails. With
+/// `alloc-debug`, the debug allocator's quarantine is flushed first.
+#[cfg(feature = "alloc-fixed-block")]
+pub fn reclaim() -> usize {
+    #[cfg(feature = "alloc-debug")]
+    DEBUG_ALLOCATOR.flush_quarantine();
+    let reclaimed = ALLOCATOR.lock().reclaim();
+    // hand the frames of the released slabs on to the frame allocator
+    ALLOCATOR.rebalance_slab_frames();
+    reclaimed
+}
+
+/// Returns a snapshot of the global allocator's statistics.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -73,6 +135,33 @@ impl<A> Locked<A> {
     }
 }
 
+/// Called when an infallible allocation fails, after the allocator already
+/// tried to reclaim cached memory.
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    let task = crate::task::current_task();
+    let stats = heap_stats();
+    serial_println!(
+        "ALLOCATION ERROR: {:?} in task {:?}\n{}",
+        layout,
+        task,
+        stats
+    );
+    panic!(
+        "allocation error: {:?} in task {:?}\n{}",
+        layout, task, stats
+    )
+}
+
+/// Checks all freed blocks held back by the debug allocator for writes after
+/// free. Always succeeds without `alloc-debug`.
+pub fn verify_heap() -> bool {
+    #[cfg(feature = "alloc-debug")]
+    return DEBUG_ALLOCATOR.verify_quarantine();
+    #[cfg(not(feature = "alloc-debug"))]
+    true
+}
+
 /// Align the given address `addr` upwards to alignment `align`.
 ///
 /// Requires that `align` is a power of two.
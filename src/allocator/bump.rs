@@ -3,6 +3,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    usage: Usage,
 }
 
 impl BumpAllocator {
@@ -13,6 +14,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            usage: Usage::new(),
         }
     }
 
@@ -25,10 +27,24 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    /// Returns a snapshot of the allocator's statistics.
+    pub fn stats(&mut self) -> HeapStats {
+        let free_bytes = self.heap_end - self.next;
+        let free_space = FreeSpaceStats {
+            free_bytes,
+            largest_free_region: Some(free_bytes),
+            free_regions: Some(if free_bytes > 0 { 1 } else { 0 }),
+        };
+        self.usage
+            .stats(self.heap_end - self.heap_start, ArrayVec::new(), free_space)
+    }
 }
 
+use crate::allocator::stats::{FreeSpaceStats, HeapStats, Usage};
 use crate::allocator::{align_up, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
+use arrayvec::ArrayVec;
 use core::ptr;
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
@@ -41,18 +57,21 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             None => return ptr::null_mut(),
         };
 
-        if alloc_end > bump.heap_end {
+        let ptr = if alloc_end > bump.heap_end {
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
             alloc_start as *mut u8
-        }
+        };
+        bump.usage.record_alloc(ptr, layout);
+        ptr
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock(); // get a mutable reference
 
+        bump.usage.record_dealloc(ptr, layout);
         bump.allocations -= 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
@@ -0,0 +1,293 @@
+//! Debug allocator that detects heap corruption.
+//!
+//! Every allocation is surrounded by canaries and prefixed with a header:
+//!
+//! ```text
+//! | padding | Header | front canary | user data | back canary |
+//! ```
+//!
+//! On `dealloc` the header and canaries are checked, the memory is poisoned and
+//! kept in a quarantine for a while, so double frees and writes after free can
+//! be detected before the inner allocator reuses the memory. If the inner
+//! allocator runs out of memory, the quarantine is flushed before giving up.
+
+use crate::allocator::align_up;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem;
+use core::ptr::{self, NonNull};
+use spin::Mutex;
+
+const CANARY_SIZE: usize = 16;
+const CANARY_BYTE: u8 = 0xca;
+const POISON_BYTE: u8 = 0xde;
+/// Number of freed blocks held back before they are really freed.
+const QUARANTINE_SIZE: usize = 64;
+
+const STATE_ALLOCATED: u64 = 0xa110_ca7e_d000_0001;
+const STATE_FREED: u64 = 0xf4ee_d000_0000_0002;
+
+#[repr(C)]
+struct Header {
+    state: u64,
+    size: usize,
+    align: usize,
+    /// Distance from the start of the inner block to the user pointer.
+    prefix: usize,
+}
+
+const PREFIX_MIN: usize = mem::size_of::<Header>() + CANARY_SIZE;
+
+/// A heap corruption detected by `DebugAllocator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    /// The block was freed twice.
+    DoubleFree { addr: usize },
+    /// The pointer was not returned by this allocator, or its header was overwritten.
+    InvalidPointer { addr: usize },
+    /// `dealloc` was called with a different layout than `alloc`.
+    LayoutMismatch {
+        addr: usize,
+        allocated: Layout,
+        freed: Layout,
+    },
+    /// A canary was overwritten, i.e. the allocation was under- or overflowed.
+    CanaryCorrupted { addr: usize, corrupted_at: usize },
+    /// Freed memory was written to while in quarantine.
+    UseAfterFree { addr: usize, corrupted_at: usize },
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeapError::DoubleFree { addr } => write!(f, "double free of {:#x}", addr),
+            HeapError::InvalidPointer { addr } => {
+                write!(f, "free of invalid or corrupted pointer {:#x}", addr)
+            }
+            HeapError::LayoutMismatch {
+                addr,
+                allocated,
+                freed,
+            } => write!(
+                f,
+                "{:#x} allocated as {:?} but freed as {:?}",
+                addr, allocated, freed
+            ),
+            HeapError::CanaryCorrupted { addr, corrupted_at } => write!(
+                f,
+                "canary of {:#x} overwritten at {:#x}",
+                addr, corrupted_at
+            ),
+            HeapError::UseAfterFree { addr, corrupted_at } => write!(
+                f,
+                "{:#x} written at {:#x} after being freed",
+                addr, corrupted_at
+            ),
+        }
+    }
+}
+
+static ERROR_HANDLER: Mutex<fn(&HeapError)> = Mutex::new(default_error_handler);
+
+fn default_error_handler(error: &HeapError) {
+    crate::serial_println!("HEAP CORRUPTION: {}", error);
+    panic!("heap corruption: {}", error);
+}
+
+/// Replaces the function called when corruption is detected.
+///
+/// The default handler reports the error over serial and panics. If a handler
+/// returns, the offending block is leaked rather than freed.
+pub fn set_error_handler(handler: fn(&HeapError)) {
+    *ERROR_HANDLER.lock() = handler;
+}
+
+fn report(error: HeapError) {
+    let handler = *ERROR_HANDLER.lock();
+    handler(&error);
+}
+
+/// Wraps another allocator and checks every allocation for corruption.
+pub struct DebugAllocator<A: 'static> {
+    inner: &'static A,
+    quarantine: Mutex<Quarantine>,
+}
+
+struct Quarantine {
+    blocks: [Option<NonNull<u8>>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+// The quarantined blocks are owned by the allocator and only touched under the lock.
+unsafe impl Send for Quarantine {}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        DebugAllocator {
+            inner,
+            quarantine: Mutex::new(Quarantine {
+                blocks: [None; QUARANTINE_SIZE],
+                next: 0,
+            }),
+        }
+    }
+
+    /// Checks that all quarantined blocks are still fully poisoned.
+    ///
+    /// Returns `false` if a write after free was found (and reported).
+    pub fn verify_quarantine(&self) -> bool {
+        let quarantine = self.quarantine.lock();
+        let mut ok = true;
+        for ptr in quarantine.blocks.iter().flatten() {
+            if let Err(error) = unsafe { check_poison(*ptr) } {
+                report(error);
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// Frees all quarantined blocks, checking them first.
+    ///
+    /// Returns the number of blocks that were in the quarantine.
+    pub fn flush_quarantine(&self) -> usize {
+        let mut quarantine = self.quarantine.lock();
+        let mut flushed = 0;
+        for slot in quarantine.blocks.iter_mut() {
+            if let Some(ptr) = slot.take() {
+                unsafe { self.release(ptr) };
+                flushed += 1;
+            }
+        }
+        flushed
+    }
+
+    /// Checks a block leaving the quarantine and hands it to the inner allocator.
+    unsafe fn release(&self, ptr: NonNull<u8>) {
+        if let Err(error) = check_poison(ptr) {
+            // leak the block, it may still be in use
+            report(error);
+            return;
+        }
+        let header = header(ptr);
+        let block = ptr.as_ptr().sub(header.prefix);
+        self.inner
+            .dealloc(block, inner_layout(header.size, header.align));
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let align = layout.align().max(mem::align_of::<Header>());
+        let prefix = align_up(PREFIX_MIN, align);
+        let inner = inner_layout(layout.size(), layout.align());
+        let mut block = self.inner.alloc(inner);
+        if block.is_null() && self.flush_quarantine() > 0 {
+            // the quarantine was holding on to memory -> retry
+            block = self.inner.alloc(inner);
+        }
+        if block.is_null() {
+            return block;
+        }
+
+        let ptr = block.add(prefix);
+        (ptr.sub(PREFIX_MIN) as *mut Header).write(Header {
+            state: STATE_ALLOCATED,
+            size: layout.size(),
+            align: layout.align(),
+            prefix,
+        });
+        ptr.sub(CANARY_SIZE).write_bytes(CANARY_BYTE, CANARY_SIZE);
+        ptr.add(layout.size()).write_bytes(CANARY_BYTE, CANARY_SIZE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let addr = ptr as usize;
+        let ptr = match NonNull::new(ptr) {
+            Some(ptr) => ptr,
+            None => return report(HeapError::InvalidPointer { addr }),
+        };
+        let header = header(ptr);
+
+        match header.state {
+            STATE_ALLOCATED => {}
+            STATE_FREED => return report(HeapError::DoubleFree { addr }),
+            _ => return report(HeapError::InvalidPointer { addr }),
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            let allocated = Layout::from_size_align_unchecked(header.size, header.align);
+            return report(HeapError::LayoutMismatch {
+                addr,
+                allocated,
+                freed: layout,
+            });
+        }
+        if let Err(error) = check_canaries(ptr, header.size) {
+            return report(error);
+        }
+
+        // poison the user data and canaries, keep the header to detect double frees
+        header.state = STATE_FREED;
+        ptr.as_ptr()
+            .sub(CANARY_SIZE)
+            .write_bytes(POISON_BYTE, header.size + 2 * CANARY_SIZE);
+
+        let evicted = {
+            let mut quarantine = self.quarantine.lock();
+            let next = quarantine.next;
+            quarantine.next = (next + 1) % QUARANTINE_SIZE;
+            quarantine.blocks[next].replace(ptr)
+        };
+        if let Some(evicted) = evicted {
+            self.release(evicted);
+        }
+    }
+}
+
+/// Layout of the inner block holding an allocation of the given size and alignment.
+fn inner_layout(size: usize, align: usize) -> Layout {
+    let align = align.max(mem::align_of::<Header>());
+    let prefix = align_up(PREFIX_MIN, align);
+    Layout::from_size_align(prefix + size + CANARY_SIZE, align).expect("layout overflow")
+}
+
+unsafe fn header<'a>(ptr: NonNull<u8>) -> &'a mut Header {
+    &mut *(ptr.as_ptr().sub(PREFIX_MIN) as *mut Header)
+}
+
+unsafe fn check_canaries(ptr: NonNull<u8>, size: usize) -> Result<(), HeapError> {
+    let front = ptr.as_ptr().sub(CANARY_SIZE);
+    let back = ptr.as_ptr().add(size);
+    for canary in [front, back] {
+        if let Some(offset) = find_mismatch(canary, CANARY_SIZE, CANARY_BYTE) {
+            return Err(HeapError::CanaryCorrupted {
+                addr: ptr.as_ptr() as usize,
+                corrupted_at: canary as usize + offset,
+            });
+        }
+    }
+    Ok(())
+}
+
+unsafe fn check_poison(ptr: NonNull<u8>) -> Result<(), HeapError> {
+    let header = header(ptr);
+    if header.state != STATE_FREED {
+        return Err(HeapError::InvalidPointer {
+            addr: ptr.as_ptr() as usize,
+        });
+    }
+    let start = ptr.as_ptr().sub(CANARY_SIZE);
+    match find_mismatch(start, header.size + 2 * CANARY_SIZE, POISON_BYTE) {
+        Some(offset) => Err(HeapError::UseAfterFree {
+            addr: ptr.as_ptr() as usize,
+            corrupted_at: start as usize + offset,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Returns the offset of the first byte in `start..start + len` that is not `expected`.
+unsafe fn find_mismatch(start: *const u8, len: usize, expected: u8) -> Option<usize> {
+    (0..len).find(|&offset| ptr::read_volatile(start.add(offset)) != expected)
+}
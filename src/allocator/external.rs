@@ -0,0 +1,66 @@
+use crate::allocator::stats::{FreeSpaceStats, HeapStats, Usage};
+use crate::allocator::Locked;
+use arrayvec::ArrayVec;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+/// The allocator from the `linked_list_allocator` crate, with usage tracking.
+///
+/// Used as a reference to compare the in-tree allocators against.
+pub struct ExternalAllocator {
+    heap: linked_list_allocator::Heap,
+    usage: Usage,
+}
+
+impl ExternalAllocator {
+    /// Creates an empty ExternalAllocator.
+    pub const fn new() -> Self {
+        ExternalAllocator {
+            heap: linked_list_allocator::Heap::empty(),
+            usage: Usage::new(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+    }
+
+    /// Returns a snapshot of the allocator's statistics.
+    ///
+    /// The crate keeps its list of free regions private, so only the number
+    /// of free bytes is known.
+    pub fn stats(&mut self) -> HeapStats {
+        let free_space = FreeSpaceStats {
+            free_bytes: self.heap.free(),
+            largest_free_region: None,
+            free_regions: None,
+        };
+        self.usage
+            .stats(self.heap.size(), ArrayVec::new(), free_space)
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<ExternalAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let ptr = match allocator.heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        };
+        allocator.usage.record_alloc(ptr, layout);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.usage.record_dealloc(ptr, layout);
+        allocator
+            .heap
+            .deallocate(NonNull::new(ptr).unwrap(), layout);
+    }
+}
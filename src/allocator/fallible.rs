@@ -0,0 +1,51 @@
+//! Allocation APIs that return an error instead of calling the allocation
+//! error handler, for subsystems that can reject work when the heap is full.
+
+use alloc::alloc::{alloc, Layout};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The heap could not satisfy an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("out of memory")
+    }
+}
+
+/// Fallible version of `Box::new`.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        // zero sized boxes never allocate
+        return Ok(Box::new(value));
+    }
+    let ptr = unsafe { alloc(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err(AllocError);
+    }
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Fallible version of `Vec::with_capacity`.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(capacity).map_err(|_| AllocError)?;
+    Ok(vec)
+}
+
+/// Fallible version of `Vec::push`. Gives the value back if the vector
+/// cannot grow.
+pub fn try_push<T>(vec: &mut Vec<T>, value: T) -> Result<(), T> {
+    if vec.try_reserve(1).is_err() {
+        return Err(value);
+    }
+    vec.push(value);
+    Ok(())
+}
@@ -1,7 +1,11 @@
+use crate::allocator::linked_list::LinkedListAllocator;
+use crate::allocator::slab::{self, RawSlabCache, SlabHooks, SpareFrames};
+use crate::allocator::stats::{HeapStats, SizeClassStats, Usage};
 use crate::allocator::Locked;
+use arrayvec::ArrayVec;
 use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
 use core::ptr::NonNull;
-use core::{mem, ptr};
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -9,9 +13,22 @@ struct ListNode {
 
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+/// Number of size classes (from the smallest) that can be served from slabs.
+/// Larger blocks would leave too much of a 4 KiB slab unused.
+const SLAB_CLASSES: usize = 7;
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap,
+    fallback_allocator: LinkedListAllocator,
+    heap_start: usize,
+    heap_end: usize,
+    /// Slab caches for the small size classes, used once `enable_slabs` is called.
+    slabs: [RawSlabCache; SLAB_CLASSES],
+    /// Frames for new slabs, see `rebalance_slab_frames`.
+    spare_frames: SpareFrames,
+    use_slabs: bool,
+    usage: Usage,
+    class_allocations: [usize; BLOCK_SIZES.len()],
 }
 
 impl FixedSizeBlockAllocator {
@@ -20,7 +37,14 @@ impl FixedSizeBlockAllocator {
         const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty(),
+            fallback_allocator: LinkedListAllocator::new(),
+            heap_start: 0,
+            heap_end: 0,
+            slabs: slab_caches(),
+            spare_frames: SpareFrames::new(),
+            use_slabs: false,
+            usage: Usage::new(),
+            class_allocations: [0; BLOCK_SIZES.len()],
         }
     }
 
@@ -31,17 +55,132 @@ impl FixedSizeBlockAllocator {
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+    }
+
+    /// Serves the small size classes from slabs carved out of frames from the
+    /// global frame allocator, which gets completely empty slabs back.
+    ///
+    /// Blocks already in the free lists stay there and are still used if the
+    /// frame allocator runs out. Requires `memory::init_frame_allocator`, and
+    /// `rebalance_slab_frames` once the allocator is unlocked.
+    pub fn enable_slabs(&mut self) {
+        self.use_slabs = true;
+    }
+
+    /// Returns whether `rebalance_slab_frames` should be called once the
+    /// allocator is unlocked.
+    fn needs_rebalance(&self) -> bool {
+        self.use_slabs && self.spare_frames.needs_rebalance()
+    }
+
+    /// Allocates a block of the given size class, preferring slabs if enabled.
+    fn block_alloc(&mut self, index: usize) -> *mut u8 {
+        if self.use_slabs && index < SLAB_CLASSES {
+            if let Some(ptr) = self.slabs[index].alloc(&mut self.spare_frames) {
+                return ptr.as_ptr();
+            }
+        }
+
+        match self.list_heads[index].take() {
+            Some(node) => {
+                self.list_heads[index] = node.next.take();
+                node as *mut ListNode as *mut u8
+            }
+            None => {
+                // no block exists in list => allocate new block
+                let block_size = BLOCK_SIZES[index];
+                // only works if all block sizes are a power of 2
+                let block_align = block_size;
+                let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                self.fallback_alloc(layout)
+            }
+        }
+    }
+
+    /// Allocates from the size class matching `layout` or the fallback allocator.
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => {
+                let ptr = self.block_alloc(index);
+                if !ptr.is_null() {
+                    self.class_allocations[index] += 1;
+                }
+                ptr
+            }
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    /// Returns all blocks in the free lists to the fallback allocator, where
+    /// they can be merged into larger regions, and releases empty slabs.
+    ///
+    /// Returns the number of bytes given back to the fallback allocator.
+    pub fn reclaim(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            // every block in the lists was originally allocated with this layout
+            let layout = Layout::from_size_align(block_size, block_size).unwrap();
+            while let Some(node) = self.list_heads[index].take() {
+                self.list_heads[index] = node.next.take();
+                let ptr = node as *mut ListNode as *mut u8;
+                unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                reclaimed += block_size;
+            }
+        }
+        for slab in self.slabs.iter_mut() {
+            slab.shrink(&mut self.spare_frames);
+        }
+        reclaimed
     }
 
     /// Allocates using the fallback allocator.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        self.fallback_allocator.allocate(layout)
+    }
+
+    /// Returns the number of blocks in the free list for the given size class.
+    fn free_blocks(&self, index: usize) -> usize {
+        let mut count = 0;
+        let mut current = self.list_heads[index].as_deref();
+        while let Some(node) = current {
+            count += 1;
+            current = node.next.as_deref();
         }
+        count
+    }
+
+    /// Returns a snapshot of the allocator's statistics.
+    pub fn stats(&mut self) -> HeapStats {
+        let size_classes: ArrayVec<SizeClassStats, _> = BLOCK_SIZES
+            .iter()
+            .enumerate()
+            .map(|(index, &block_size)| SizeClassStats {
+                block_size,
+                allocations: self.class_allocations[index],
+                free_blocks: self.free_blocks(index),
+            })
+            .collect();
+        let free_space = self.fallback_allocator.free_space();
+        self.usage
+            .stats(self.heap_end - self.heap_start, size_classes, free_space)
     }
 }
 
+/// Creates one slab cache per slab-backed size class.
+const fn slab_caches() -> [RawSlabCache; SLAB_CLASSES] {
+    const EMPTY: RawSlabCache = RawSlabCache::new(8, 8, SlabHooks::NONE);
+    let mut caches = [EMPTY; SLAB_CLASSES];
+    let mut index = 0;
+    while index < SLAB_CLASSES {
+        let block_size = BLOCK_SIZES[index];
+        caches[index] = RawSlabCache::new(block_size, block_size, SlabHooks::NONE);
+        index += 1;
+    }
+    caches
+}
+
 /// Choose an appropriate block size for the given layout.
 ///
 /// Returns an index into the `BLOCK_SIZES` array.
@@ -50,33 +189,45 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+impl Locked<FixedSizeBlockAllocator> {
+    /// Refills or drains the frames that new slabs are carved out of.
+    ///
+    /// The frame allocator is only called from here, with the allocator
+    /// unlocked, so the allocator's lock never nests around its lock.
+    pub fn rebalance_slab_frames(&self) {
+        slab::rebalance(|frame| self.lock().spare_frames.step(frame));
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        // no block exists in list => allocate new block
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are a power of 2
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
-                }
-            }
-            None => allocator.fallback_alloc(layout),
+        let mut ptr = allocator.allocate(layout);
+        if ptr.is_null() && allocator.reclaim() > 0 {
+            // the free lists were holding on to memory -> retry on the merged heap
+            ptr = allocator.allocate(layout);
+        }
+        allocator.usage.record_alloc(ptr, layout);
+        let needs_rebalance = allocator.needs_rebalance();
+        drop(allocator);
+        if needs_rebalance {
+            self.rebalance_slab_frames();
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
+        let mut guard = self.lock();
+        let allocator = &mut *guard;
+        allocator.usage.record_dealloc(ptr, layout);
         match list_index(&layout) {
+            // blocks outside of the heap can only come from a slab
+            Some(index)
+                if !(allocator.heap_start..allocator.heap_end).contains(&(ptr as usize)) =>
+            {
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.slabs[index].dealloc(ptr, &mut allocator.spare_frames);
+            }
             Some(index) => {
                 let new_node = ListNode {
                     next: allocator.list_heads[index].take(),
@@ -88,10 +239,12 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 new_node_ptr.write(new_node);
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
             }
-            None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
-            }
+            None => allocator.fallback_allocator.deallocate(ptr, layout),
+        }
+        let needs_rebalance = allocator.needs_rebalance();
+        drop(guard);
+        if needs_rebalance {
+            self.rebalance_slab_frames();
         }
     }
 }
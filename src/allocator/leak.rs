@@ -0,0 +1,180 @@
+//! Optional tracking of live heap allocations, used to find leaks.
+//!
+//! When enabled, every allocation is recorded together with the return
+//! addresses of its callers. The table is a fixed size array because the
+//! tracker runs inside the global allocator and must not allocate itself.
+//!
+//! Call sites are found by walking the `rbp` chain, so they are only accurate
+//! when the kernel is built with frame pointers (see `.cargo/config.toml`).
+
+use crate::memory::stack;
+use core::alloc::Layout;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+/// Maximum number of live allocations that can be tracked at once.
+pub const MAX_TRACKED: usize = 256;
+/// Number of return addresses recorded per allocation.
+pub const CALL_SITE_DEPTH: usize = 4;
+
+/// A live allocation recorded by the tracker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveAllocation {
+    pub addr: usize,
+    pub size: usize,
+    pub align: usize,
+    /// Return addresses of the innermost callers, `0` where unknown.
+    pub call_sites: [usize; CALL_SITE_DEPTH],
+    /// Value of the generation counter when the allocation was made.
+    pub generation: u64,
+}
+
+struct Tracker {
+    entries: [Option<LiveAllocation>; MAX_TRACKED],
+    generation: u64,
+    /// Allocations that could not be recorded because the table was full.
+    dropped: usize,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    entries: [None; MAX_TRACKED],
+    generation: 0,
+    dropped: 0,
+});
+
+/// Starts recording live allocations, clearing any previous records.
+pub fn enable() {
+    let mut tracker = TRACKER.lock();
+    tracker.entries = [None; MAX_TRACKED];
+    tracker.dropped = 0;
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops recording live allocations. Existing records are kept.
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Advances the generation counter and returns the new generation.
+///
+/// Allocations made before a checkpoint that are still alive afterwards are
+/// leak candidates, see [`for_each_since`].
+pub fn checkpoint() -> u64 {
+    let mut tracker = TRACKER.lock();
+    tracker.generation += 1;
+    tracker.generation
+}
+
+/// Number of allocations that were not recorded because the table was full.
+pub fn dropped() -> usize {
+    TRACKER.lock().dropped
+}
+
+/// Calls `f` for every recorded live allocation made in `generation` or later.
+///
+/// `f` must not allocate, as the tracker is locked while it runs.
+pub fn for_each_since(generation: u64, f: impl FnMut(&LiveAllocation)) {
+    let tracker = TRACKER.lock();
+    tracker
+        .entries
+        .iter()
+        .flatten()
+        .filter(|a| a.generation >= generation)
+        .for_each(f);
+}
+
+/// Prints every recorded live allocation made in `generation` or later to serial.
+pub fn report_since(generation: u64) {
+    for_each_since(generation, |a| {
+        crate::serial_println!(
+            "live allocation {:#x} ({} bytes, align {}) from {:x?}",
+            a.addr,
+            a.size,
+            a.align,
+            a.call_sites
+        );
+    });
+}
+
+/// Called by the allocators for every successful allocation.
+pub(crate) fn track(ptr: *mut u8, layout: Layout) {
+    if !is_enabled() {
+        return;
+    }
+    let call_sites = call_sites();
+    let mut tracker = TRACKER.lock();
+    let generation = tracker.generation;
+    match tracker.entries.iter_mut().find(|e| e.is_none()) {
+        Some(entry) => {
+            *entry = Some(LiveAllocation {
+                addr: ptr as usize,
+                size: layout.size(),
+                align: layout.align(),
+                call_sites,
+                generation,
+            })
+        }
+        None => tracker.dropped += 1,
+    }
+}
+
+/// Called by the allocators for every deallocation.
+pub(crate) fn untrack(ptr: *mut u8) {
+    if !is_enabled() {
+        return;
+    }
+    let mut tracker = TRACKER.lock();
+    if let Some(entry) = tracker
+        .entries
+        .iter_mut()
+        .find(|e| matches!(e, Some(a) if a.addr == ptr as usize))
+    {
+        *entry = None;
+    }
+}
+
+/// Walks the frame pointer chain and returns the innermost return addresses.
+///
+/// Only frames on the current stack are read, so nothing is recorded on stacks
+/// that `stack::allocate_stack` did not create, such as the bootloader's.
+fn call_sites() -> [usize; CALL_SITE_DEPTH] {
+    let mut sites = [0; CALL_SITE_DEPTH];
+    let (mut rbp, rsp): (usize, usize);
+    unsafe {
+        asm!(
+            "mov {}, rbp",
+            "mov {}, rsp",
+            out(reg) rbp,
+            out(reg) rsp,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+    let Some(stack) = stack::containing(VirtAddr::new(rsp as u64)) else {
+        return sites;
+    };
+    let top = stack.top.as_u64() as usize;
+
+    for site in sites.iter_mut() {
+        // saved rbp at [rbp], return address at [rbp + 8], both on the
+        // used part of the stack
+        if rbp < rsp || rbp > top - 16 || !rbp.is_multiple_of(8) {
+            break;
+        }
+        let frame = rbp as *const usize;
+        let (next, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+        *site = return_address;
+        // the stack grows downwards, so the caller's frame must be above ours
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    sites
+}
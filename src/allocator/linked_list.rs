@@ -1,6 +1,8 @@
+use crate::allocator::stats::{FreeSpaceStats, HeapStats, Usage};
 use crate::allocator::{align_up, Locked};
+use arrayvec::ArrayVec;
 use core::alloc::{GlobalAlloc, Layout};
-use core::{mem, ptr};
+use core::{iter, mem, ptr};
 
 struct ListNode {
     size: usize,
@@ -21,15 +23,36 @@ impl ListNode {
     }
 }
 
+/// How `LinkedListAllocator` picks a free region for an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    /// Use the lowest addressed region that is large enough.
+    FirstFit,
+    /// Use the smallest region that is large enough, keeping large regions intact.
+    BestFit,
+}
+
 pub struct LinkedListAllocator {
+    /// Dummy head of the free list, which is sorted by address.
     head: ListNode,
+    strategy: FitStrategy,
+    heap_size: usize,
+    usage: Usage,
 }
 
 impl LinkedListAllocator {
-    /// Creates an empty LinkedListAllocator.
+    /// Creates an empty first-fit LinkedListAllocator.
     pub const fn new() -> Self {
+        Self::with_strategy(FitStrategy::FirstFit)
+    }
+
+    /// Creates an empty LinkedListAllocator using the given strategy.
+    pub const fn with_strategy(strategy: FitStrategy) -> Self {
         Self {
             head: ListNode::new(0),
+            strategy,
+            heap_size: 0,
+            usage: Usage::new(),
         }
     }
 
@@ -39,21 +62,128 @@ impl LinkedListAllocator {
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_size = heap_size;
         self.add_free_region(heap_start, heap_size);
     }
 
-    /// Adds the given memory region to the front of the list.
+    pub fn strategy(&self) -> FitStrategy {
+        self.strategy
+    }
+
+    /// Changes the strategy used for subsequent allocations.
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Returns a snapshot of the allocator's statistics.
+    pub fn stats(&mut self) -> HeapStats {
+        let free_space = self.free_space();
+        self.usage
+            .stats(self.heap_size, ArrayVec::new(), free_space)
+    }
+
+    /// Walks the free list and sums up the free regions.
+    pub(crate) fn free_space(&self) -> FreeSpaceStats {
+        let mut free_space = FreeSpaceStats {
+            free_bytes: 0,
+            largest_free_region: Some(0),
+            free_regions: Some(0),
+        };
+        for region in self.regions() {
+            free_space.free_bytes += region.size;
+            free_space.largest_free_region = free_space.largest_free_region.max(Some(region.size));
+            free_space.free_regions = free_space.free_regions.map(|n| n + 1);
+        }
+        free_space
+    }
+
+    /// Allocates a block for `layout`, returning null if no region fits.
+    ///
+    /// Unlike `alloc`, this does not record the allocation in the usage
+    /// counters, for allocators that use this one as their fallback.
+    pub(crate) fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        // perform layout adjustments
+        let (size, align) = Self::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            unsafe {
+                if alloc_start > region_start {
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+                if region_end > alloc_end {
+                    self.add_free_region(alloc_end, region_end - alloc_end);
+                }
+            }
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    /// Frees a block returned by `allocate`, without recording it in the
+    /// usage counters.
+    ///
+    /// ## Safety
+    ///
+    /// `ptr` must have been returned by `allocate` with the same `layout`.
+    pub(crate) unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        // perform layout adjustments
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size)
+    }
+
+    /// Returns an iterator over the free regions, in address order.
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        iter::successors(self.head.next.as_deref(), |region| region.next.as_deref())
+    }
+
+    /// Adds the given memory region to the list, merging it with adjacent
+    /// free regions.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
+        // find the last node before `addr` (possibly the dummy head)
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // create a new list node and insert it after `current`
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = current.next.take();
         let node_ptr = addr as *mut ListNode;
         node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        let node = &mut *node_ptr;
+
+        // merge with the following region if it starts where the new one ends
+        let end = node.end_addr();
+        if node
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() == end)
+        {
+            let next = node.next.take().unwrap();
+            node.size += next.size;
+            node.next = next.next.take();
+        }
+
+        // merge with the preceding region if it ends where the new one starts
+        // (the dummy head has size 0 and is never merged)
+        if current.size > 0 && current.end_addr() == addr {
+            current.size += node.size;
+            current.next = node.next.take();
+        } else {
+            current.next = Some(node);
+        }
     }
 
     /// Looks for a free region with the given size and alignment and removes
@@ -61,24 +191,48 @@ impl LinkedListAllocator {
     ///
     /// Returns a tuple of the list node and the start address of the allocation.
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
-        // reference to current list node, updated for each iteration
-        let mut current = &mut self.head;
-        // look for a large enough memory region in linked list
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
-                // region suitable for allocation -> remove node from list
-                let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), alloc_start));
-                current.next = next;
-                return ret;
-            } else {
-                // region not suitable -> continue with next region
-                current = current.next.as_mut().unwrap();
+        let fits = |region: &ListNode| Self::alloc_from_region(region, size, align).is_ok();
+        let index = match self.strategy {
+            FitStrategy::FirstFit => self.regions().position(fits)?,
+            FitStrategy::BestFit => {
+                self.regions()
+                    .enumerate()
+                    .filter(|(_, region)| fits(region))
+                    // prefer the lowest address among equally sized regions
+                    .min_by_key(|&(index, region)| (region.size, index))?
+                    .0
             }
+        };
+
+        // walk to the node before the chosen region and unlink it
+        let mut previous = &mut self.head;
+        for _ in 0..index {
+            previous = previous.next.as_mut().unwrap();
+        }
+        let region = previous.next.take().unwrap();
+        previous.next = region.next.take();
+
+        let alloc_start = Self::alloc_from_region(region, size, align).unwrap();
+        Some((region, alloc_start))
+    }
+
+    /// Removes the free region starting exactly at `addr` from the list.
+    fn take_region_at(&mut self, addr: usize) -> Option<&'static mut ListNode> {
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
         }
 
-        // no suitable region found
-        None
+        if current.next.as_ref()?.start_addr() != addr {
+            return None;
+        }
+        let region = current.next.take().unwrap();
+        current.next = region.next.take();
+        Some(region)
     }
 
     /// Try to use the given region for an allocation with given size and
@@ -86,7 +240,12 @@ impl LinkedListAllocator {
     ///
     /// Returns the allocation start address on success.
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let front_padding = alloc_start - region.start_addr();
+        if front_padding > 0 && front_padding < mem::size_of::<ListNode>() {
+            // padding too small to be returned to the list as its own region
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -105,6 +264,53 @@ impl LinkedListAllocator {
         Ok(alloc_start)
     }
 
+    /// Resizes the allocated block at `addr` from `old_size` to `new_size`
+    /// bytes without moving it.
+    ///
+    /// Both sizes must already be adjusted by `size_align`. Returns `false` if
+    /// the block cannot be resized in place, in which case nothing is changed.
+    unsafe fn resize_in_place(&mut self, addr: usize, old_size: usize, new_size: usize) -> bool {
+        let old_end = addr + old_size;
+        let new_end = addr + new_size;
+
+        if new_size <= old_size {
+            let tail = old_size - new_size;
+            if tail == 0 {
+                return true;
+            }
+            if tail >= mem::size_of::<ListNode>() {
+                self.add_free_region(new_end, tail);
+                return true;
+            }
+            // the tail is too small on its own, but can join a following free region
+            return match self.take_region_at(old_end) {
+                Some(next) => {
+                    let next_size = next.size;
+                    self.add_free_region(new_end, tail + next_size);
+                    true
+                }
+                None => false,
+            };
+        }
+
+        // growing requires a free region directly after the block
+        let needed = new_size - old_size;
+        let Some(next) = self.take_region_at(old_end) else {
+            return false;
+        };
+        let next_size = next.size;
+        if next_size == needed {
+            true
+        } else if next_size > needed && next_size - needed >= mem::size_of::<ListNode>() {
+            self.add_free_region(new_end, next_size - needed);
+            true
+        } else {
+            // not usable -> put it back
+            self.add_free_region(old_end, next_size);
+            false
+        }
+    }
+
     /// Adjust the given layout so that the resulting allocated memory
     /// region is also capable of storing a `ListNode`.
     ///
@@ -121,26 +327,37 @@ impl LinkedListAllocator {
 
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // perform layout adjustments
-        let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
-
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
-            }
-            alloc_start as *mut u8
-        } else {
-            ptr::null_mut()
-        }
+        let ptr = allocator.allocate(layout);
+        allocator.usage.record_alloc(ptr, layout);
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // perform layout adjustments
-        let (size, _) = LinkedListAllocator::size_align(layout);
+        let mut allocator = self.lock();
+        allocator.usage.record_dealloc(ptr, layout);
+        allocator.deallocate(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old_block_size, _) = LinkedListAllocator::size_align(layout);
+        let (new_block_size, _) = LinkedListAllocator::size_align(new_layout);
 
-        self.lock().add_free_region(ptr as usize, size)
+        let mut allocator = self.lock();
+        if allocator.resize_in_place(ptr as usize, old_block_size, new_block_size) {
+            allocator.usage.record_dealloc(ptr, layout);
+            allocator.usage.record_alloc(ptr, new_layout);
+            return ptr;
+        }
+        drop(allocator);
+
+        // no room next to the block -> move it
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
@@ -0,0 +1,479 @@
+//! Slab allocator for fixed-size objects.
+//!
+//! Each slab is a single 4 KiB frame from the global frame allocator, accessed
+//! through the physical memory mapping. The start of the slab holds a header
+//! with a bitmap of used objects, so free objects are never written to and
+//! stay in whatever state the constructor hook left them in.
+//!
+//! Caches never call the frame allocator themselves. They carve new slabs out
+//! of `SpareFrames` and put released ones back there, and their owner refills
+//! or drains the spare frames with `rebalance` after dropping its lock. So the
+//! global allocator's lock is never held while the frame allocator's is taken.
+
+use crate::memory::{self, GlobalFrameAllocator};
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+const SLAB_SIZE: usize = 4096;
+/// Maximum number of objects in a slab, limited by the size of the bitmap.
+const MAX_OBJECTS: usize = 512;
+/// Number of completely empty slabs a cache keeps around before returning
+/// them to its spare frames.
+const EMPTY_SLABS_KEPT: usize = 1;
+/// Number of spare frames `rebalance` leaves, once there are none left or more
+/// than `MAX_SPARE_FRAMES`.
+const SPARE_FRAMES: usize = 2;
+const MAX_SPARE_FRAMES: usize = 4;
+
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+    /// Object size of the owning cache, to catch frees into the wrong cache.
+    object_size: usize,
+    in_use: usize,
+    /// Bit set -> object in use. Bits past the slab's capacity are always set.
+    used: [u64; MAX_OBJECTS / 64],
+}
+
+/// Hooks run on an object's memory when its slab is created or released.
+#[derive(Clone, Copy)]
+pub struct SlabHooks {
+    /// Called for every object when a new slab is carved out.
+    pub constructor: Option<fn(*mut u8)>,
+    /// Called for every object when an empty slab is given back.
+    pub destructor: Option<fn(*mut u8)>,
+}
+
+impl SlabHooks {
+    pub const NONE: SlabHooks = SlabHooks {
+        constructor: None,
+        destructor: None,
+    };
+}
+
+/// Statistics of a single slab cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub empty_slabs: usize,
+    pub objects_in_use: usize,
+}
+
+/// Frames set aside for new slabs, in a list threaded through the frames.
+#[derive(Default)]
+pub struct SpareFrames {
+    head: Option<PhysFrame>,
+    len: usize,
+}
+
+// The frames are only reachable through the list, which is always behind a lock.
+unsafe impl Send for SpareFrames {}
+
+/// What `SpareFrames::step` asks `rebalance` to do next.
+pub enum Step {
+    Done,
+    /// Allocate a frame and pass it to the next step.
+    Grow,
+    /// Give this frame back to the frame allocator.
+    Shrink(PhysFrame),
+}
+
+impl SpareFrames {
+    pub const fn new() -> Self {
+        SpareFrames { head: None, len: 0 }
+    }
+
+    /// Returns whether the owner should call `rebalance` once it dropped its lock.
+    pub fn needs_rebalance(&self) -> bool {
+        self.len == 0 || self.len > MAX_SPARE_FRAMES
+    }
+
+    /// Adds `frame`, if any, and returns what `rebalance` should do next.
+    pub fn step(&mut self, frame: Option<PhysFrame>) -> Step {
+        if let Some(frame) = frame {
+            self.push(frame);
+        }
+        match self.len {
+            len if len < SPARE_FRAMES => Step::Grow,
+            len if len > SPARE_FRAMES => Step::Shrink(self.pop().unwrap()),
+            _ => Step::Done,
+        }
+    }
+
+    fn push(&mut self, frame: PhysFrame) {
+        // the first 8 bytes of a spare frame hold the address of the next one
+        let next: *mut u64 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        unsafe { next.write(self.head.map_or(0, |f| f.start_address().as_u64())) };
+        self.head = Some(frame);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<PhysFrame> {
+        let frame = self.head?;
+        let next: *const u64 = memory::phys_to_virt(frame.start_address()).as_ptr();
+        let next = unsafe { next.read() };
+        self.head = (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+        self.len -= 1;
+        Some(frame)
+    }
+}
+
+/// Refills or drains spare frames through the frame allocator until `step`,
+/// which locks them and calls `SpareFrames::step`, returns `Step::Done`.
+///
+/// Must be called without the lock of the spare frames held. Stops early if
+/// the frame allocator runs out of frames.
+pub fn rebalance(mut step: impl FnMut(Option<PhysFrame>) -> Step) {
+    let mut frame = None;
+    loop {
+        match step(frame.take()) {
+            Step::Done => return,
+            Step::Grow => match GlobalFrameAllocator.allocate_frame() {
+                Some(new) => frame = Some(new),
+                None => return,
+            },
+            Step::Shrink(old) => unsafe { GlobalFrameAllocator.deallocate_frame(old) },
+        }
+    }
+}
+
+/// An untyped cache of equally sized objects.
+pub struct RawSlabCache {
+    object_size: usize,
+    /// Offset of the first object from the start of a slab.
+    first_object: usize,
+    objects_per_slab: usize,
+    hooks: SlabHooks,
+    slabs: Option<NonNull<SlabHeader>>,
+    slab_count: usize,
+    empty_slabs: usize,
+    objects_in_use: usize,
+}
+
+// The slabs are only reachable through the cache, which is always behind a lock.
+unsafe impl Send for RawSlabCache {}
+
+impl RawSlabCache {
+    /// Creates an empty cache for objects of the given size and alignment.
+    ///
+    /// `align` must be a power of two.
+    pub const fn new(size: usize, align: usize, hooks: SlabHooks) -> Self {
+        let size = if size == 0 { 1 } else { size };
+        let object_size = align_up(size, align);
+        let first_object = align_up(mem::size_of::<SlabHeader>(), align);
+        assert!(
+            first_object + object_size <= SLAB_SIZE,
+            "object too large for a slab"
+        );
+        let objects_per_slab = (SLAB_SIZE - first_object) / object_size;
+        let objects_per_slab = if objects_per_slab > MAX_OBJECTS {
+            MAX_OBJECTS
+        } else {
+            objects_per_slab
+        };
+
+        RawSlabCache {
+            object_size,
+            first_object,
+            objects_per_slab,
+            hooks,
+            slabs: None,
+            slab_count: 0,
+            empty_slabs: 0,
+            objects_in_use: 0,
+        }
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            object_size: self.object_size,
+            objects_per_slab: self.objects_per_slab,
+            slabs: self.slab_count,
+            empty_slabs: self.empty_slabs,
+            objects_in_use: self.objects_in_use,
+        }
+    }
+
+    /// Allocates an object, creating a new slab from `spare` if all slabs are full.
+    ///
+    /// Returns `None` if no slab has room and no spare frame is left.
+    pub fn alloc(&mut self, spare: &mut SpareFrames) -> Option<NonNull<u8>> {
+        let mut slab = self.slabs;
+        while let Some(mut header) = slab {
+            let header = unsafe { header.as_mut() };
+            if header.in_use < self.objects_per_slab {
+                return Some(self.alloc_from(header));
+            }
+            slab = header.next;
+        }
+
+        let mut header = self.new_slab(spare.pop()?);
+        Some(self.alloc_from(unsafe { header.as_mut() }))
+    }
+
+    /// Returns an object to the cache.
+    ///
+    /// Empty slabs beyond `EMPTY_SLABS_KEPT` are released to `spare`.
+    ///
+    /// ## Safety
+    ///
+    /// `ptr` must have been returned by `alloc` on this cache and not been freed since.
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, spare: &mut SpareFrames) {
+        let slab_addr = ptr.as_ptr() as usize & !(SLAB_SIZE - 1);
+        let header = &mut *(slab_addr as *mut SlabHeader);
+        assert_eq!(
+            header.object_size, self.object_size,
+            "object freed into wrong slab cache"
+        );
+
+        let index = (ptr.as_ptr() as usize - slab_addr - self.first_object) / self.object_size;
+        let (word, bit) = (index / 64, index % 64);
+        assert!(
+            header.used[word] & (1 << bit) != 0,
+            "slab object freed twice"
+        );
+        header.used[word] &= !(1 << bit);
+        header.in_use -= 1;
+        self.objects_in_use -= 1;
+
+        if header.in_use == 0 {
+            if self.empty_slabs >= EMPTY_SLABS_KEPT {
+                self.release_slab(NonNull::from(header), spare);
+            } else {
+                self.empty_slabs += 1;
+            }
+        }
+    }
+
+    /// Releases all completely empty slabs to `spare`.
+    pub fn shrink(&mut self, spare: &mut SpareFrames) {
+        let mut slab = self.slabs;
+        while let Some(header) = slab {
+            unsafe {
+                slab = header.as_ref().next;
+                if header.as_ref().in_use == 0 {
+                    self.release_slab(header, spare);
+                    self.empty_slabs -= 1;
+                }
+            }
+        }
+    }
+
+    fn alloc_from(&mut self, header: &mut SlabHeader) -> NonNull<u8> {
+        let (word, bits) = header
+            .used
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != u64::MAX)
+            .expect("slab with free objects has a clear bit");
+        let bit = bits.trailing_ones() as usize;
+        *bits |= 1 << bit;
+
+        if header.in_use == 0 {
+            self.empty_slabs -= 1;
+        }
+        header.in_use += 1;
+        self.objects_in_use += 1;
+
+        let index = word * 64 + bit;
+        let addr =
+            header as *mut SlabHeader as usize + self.first_object + index * self.object_size;
+        NonNull::new(addr as *mut u8).unwrap()
+    }
+
+    /// Carves a new slab out of `frame` and adds it to the front of the list.
+    fn new_slab(&mut self, frame: PhysFrame) -> NonNull<SlabHeader> {
+        let slab_addr = memory::phys_to_virt(frame.start_address()).as_u64() as usize;
+
+        let mut used = [0; MAX_OBJECTS / 64];
+        // mark the slots past the capacity as permanently used
+        for index in self.objects_per_slab..MAX_OBJECTS {
+            used[index / 64] |= 1 << (index % 64);
+        }
+
+        let header_ptr = slab_addr as *mut SlabHeader;
+        unsafe {
+            header_ptr.write(SlabHeader {
+                next: self.slabs,
+                object_size: self.object_size,
+                in_use: 0,
+                used,
+            })
+        };
+        if let Some(constructor) = self.hooks.constructor {
+            for index in 0..self.objects_per_slab {
+                constructor((slab_addr + self.first_object + index * self.object_size) as *mut u8);
+            }
+        }
+
+        let header = NonNull::new(header_ptr).unwrap();
+        self.slabs = Some(header);
+        self.slab_count += 1;
+        self.empty_slabs += 1;
+        header
+    }
+
+    /// Unlinks an empty slab and puts its frame into `spare`.
+    ///
+    /// Does not touch `empty_slabs`, callers adjust it as needed.
+    unsafe fn release_slab(&mut self, slab: NonNull<SlabHeader>, spare: &mut SpareFrames) {
+        let mut link = &mut self.slabs;
+        while *link != Some(slab) {
+            link = &mut link.expect("slab not in cache").as_mut().next;
+        }
+        *link = slab.as_ref().next;
+        self.slab_count -= 1;
+
+        let slab_addr = slab.as_ptr() as usize;
+        if let Some(destructor) = self.hooks.destructor {
+            for index in 0..self.objects_per_slab {
+                destructor((slab_addr + self.first_object + index * self.object_size) as *mut u8);
+            }
+        }
+
+        let phys = memory::virt_to_phys(VirtAddr::new(slab_addr as u64));
+        spare.push(PhysFrame::containing_address(phys));
+    }
+}
+
+/// A cache of objects of type `T`, e.g. `static TASKS: SlabCache<Task>`.
+pub struct SlabCache<T> {
+    inner: Mutex<Inner>,
+    _type: PhantomData<T>,
+}
+
+struct Inner {
+    cache: RawSlabCache,
+    spare: SpareFrames,
+}
+
+// The cache only hands out memory, the values in it belong to their `SlabBox`.
+unsafe impl<T> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    pub const fn new() -> Self {
+        Self::with_hooks(SlabHooks::NONE)
+    }
+
+    /// Creates a cache whose hooks run on the raw memory of each object slot.
+    ///
+    /// A fresh `T` is written into the slot on every `alloc`, so the hooks are
+    /// meant for per-slot setup and teardown rather than initializing `T`.
+    pub const fn with_hooks(hooks: SlabHooks) -> Self {
+        SlabCache {
+            inner: Mutex::new(Inner {
+                cache: RawSlabCache::new(mem::size_of::<T>(), mem::align_of::<T>(), hooks),
+                spare: SpareFrames::new(),
+            }),
+            _type: PhantomData,
+        }
+    }
+
+    /// Moves `value` into the cache. Gives it back if no memory is available.
+    pub fn alloc(&self, value: T) -> Result<SlabBox<'_, T>, T> {
+        let mut ptr = self.with_inner(|inner| inner.cache.alloc(&mut inner.spare));
+        if ptr.is_none() {
+            // out of spare frames, which `with_inner` has refilled if it could
+            ptr = self.with_inner(|inner| inner.cache.alloc(&mut inner.spare));
+        }
+        let Some(ptr) = ptr else {
+            return Err(value);
+        };
+        let ptr = ptr.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(SlabBox { ptr, cache: self })
+    }
+
+    /// Releases all completely empty slabs.
+    pub fn shrink(&self) {
+        self.with_inner(|inner| inner.cache.shrink(&mut inner.spare));
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.inner.lock().cache.stats()
+    }
+
+    /// Frees a slot returned by `alloc`.
+    unsafe fn dealloc(&self, ptr: NonNull<T>) {
+        self.with_inner(|inner| inner.cache.dealloc(ptr.cast(), &mut inner.spare));
+    }
+
+    /// Runs `f` with the cache locked, then rebalances the spare frames if
+    /// needed.
+    fn with_inner<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        let (result, needs_rebalance) = {
+            let mut inner = self.inner.lock();
+            let result = f(&mut inner);
+            (result, inner.spare.needs_rebalance())
+        };
+        if needs_rebalance {
+            rebalance(|frame| self.inner.lock().spare.step(frame));
+        }
+        result
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An owned `T` living in a `SlabCache`, returned to it on drop.
+pub struct SlabBox<'a, T> {
+    ptr: NonNull<T>,
+    cache: &'a SlabCache<T>,
+}
+
+impl<T> SlabBox<'_, T> {
+    /// Moves the value out and frees its slot.
+    pub fn into_inner(this: Self) -> T {
+        let this = mem::ManuallyDrop::new(this);
+        unsafe {
+            let value = ptr::read(this.ptr.as_ptr());
+            this.cache.dealloc(this.ptr);
+            value
+        }
+    }
+}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.dealloc(self.ptr);
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for SlabBox<'_, T> {}
+unsafe impl<T: Sync> Sync for SlabBox<'_, T> {}
+
+/// Const version of `allocator::align_up`.
+const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
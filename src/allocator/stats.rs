@@ -0,0 +1,164 @@
+use crate::allocator::leak;
+use arrayvec::ArrayVec;
+use core::alloc::Layout;
+use core::fmt;
+
+/// Maximum number of size classes an allocator can report.
+pub const MAX_SIZE_CLASSES: usize = 16;
+
+/// A snapshot of the state of a heap allocator, as returned by
+/// [`heap_stats`](crate::allocator::heap_stats).
+#[derive(Debug, Clone)]
+pub struct HeapStats {
+    /// Total size of the heap in bytes.
+    pub heap_size: usize,
+    /// Bytes currently handed out to callers (as requested in their layouts).
+    pub bytes_allocated: usize,
+    /// Highest value `bytes_allocated` has reached since the heap was initialized.
+    pub peak_bytes_allocated: usize,
+    /// Number of `alloc` calls that succeeded.
+    pub allocations: usize,
+    /// Number of `dealloc` calls.
+    pub deallocations: usize,
+    /// Number of `alloc` calls that returned null.
+    pub failed_allocations: usize,
+    /// Per size class statistics. Empty for allocators without size classes.
+    pub size_classes: ArrayVec<SizeClassStats, MAX_SIZE_CLASSES>,
+    /// Free space in the underlying (fallback) heap.
+    pub free_space: FreeSpaceStats,
+}
+
+impl HeapStats {
+    /// Number of allocations that are currently live.
+    pub fn live_allocations(&self) -> usize {
+        self.allocations - self.deallocations
+    }
+}
+
+/// Statistics for a single size class of a block based allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    /// Number of allocations served by this size class.
+    pub allocations: usize,
+    /// Number of blocks currently sitting in the free list.
+    pub free_blocks: usize,
+}
+
+/// Free space and fragmentation of a heap region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FreeSpaceStats {
+    pub free_bytes: usize,
+    /// Size of the largest single allocation that could currently succeed, if
+    /// the allocator can tell.
+    pub largest_free_region: Option<usize>,
+    /// Number of separate free regions, if the allocator can tell.
+    pub free_regions: Option<usize>,
+}
+
+impl FreeSpaceStats {
+    /// External fragmentation in percent: how much of the free space is not
+    /// part of the largest free region.
+    pub fn fragmentation(&self) -> Option<usize> {
+        let largest_free_region = self.largest_free_region?;
+        if self.free_bytes == 0 {
+            return Some(0);
+        }
+        Some(100 - (largest_free_region * 100 / self.free_bytes))
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "heap: {} / {} bytes used (peak {})",
+            self.bytes_allocated, self.heap_size, self.peak_bytes_allocated
+        )?;
+        writeln!(
+            f,
+            "allocations: {} live, {} total, {} failed",
+            self.live_allocations(),
+            self.allocations,
+            self.failed_allocations
+        )?;
+        for class in &self.size_classes {
+            writeln!(
+                f,
+                "  {:>5} B: {} allocations, {} free blocks",
+                class.block_size, class.allocations, class.free_blocks
+            )?;
+        }
+        write!(f, "free: {} bytes", self.free_space.free_bytes)?;
+        match (
+            self.free_space.largest_free_region,
+            self.free_space.fragmentation(),
+        ) {
+            (Some(largest), Some(fragmentation)) => write!(
+                f,
+                ", largest region {} bytes, {}% fragmented",
+                largest, fragmentation
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Usage counters shared by all allocator implementations.
+pub(crate) struct Usage {
+    bytes_allocated: usize,
+    peak_bytes_allocated: usize,
+    allocations: usize,
+    deallocations: usize,
+    failed_allocations: usize,
+}
+
+impl Usage {
+    pub const fn new() -> Self {
+        Usage {
+            bytes_allocated: 0,
+            peak_bytes_allocated: 0,
+            allocations: 0,
+            deallocations: 0,
+            failed_allocations: 0,
+        }
+    }
+
+    /// Records the result of an `alloc` call.
+    pub fn record_alloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            self.failed_allocations += 1;
+            return;
+        }
+        self.allocations += 1;
+        self.bytes_allocated += layout.size();
+        self.peak_bytes_allocated = self.peak_bytes_allocated.max(self.bytes_allocated);
+        leak::track(ptr, layout);
+    }
+
+    /// Records a `dealloc` call.
+    pub fn record_dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.deallocations += 1;
+        self.bytes_allocated -= layout.size();
+        leak::untrack(ptr);
+    }
+
+    /// Creates a `HeapStats` from the counters and the given allocator specific parts.
+    pub fn stats(
+        &self,
+        heap_size: usize,
+        size_classes: ArrayVec<SizeClassStats, MAX_SIZE_CLASSES>,
+        free_space: FreeSpaceStats,
+    ) -> HeapStats {
+        HeapStats {
+            heap_size,
+            bytes_allocated: self.bytes_allocated,
+            peak_bytes_allocated: self.peak_bytes_allocated,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+            failed_allocations: self.failed_allocations,
+            size_classes,
+            free_space,
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Parsing of statically linked ELF64 executables for x86_64.
+//!
+//! Only what the loader needs is read: the file header and the `PT_LOAD`
+//! program headers. Everything is validated up front, so the segments of a
+//! parsed `Elf` can be loaded without further checks.
+
+use core::fmt;
+use core::ops::Range;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3e;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+
+/// End of the lower half of the canonical address space.
+const LOWER_HALF_END: u64 = 1 << 47;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Why an ELF file was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file is shorter than the headers it declares.
+    Truncated,
+    BadMagic,
+    /// Not a 64-bit, little-endian, version 1 file.
+    UnsupportedFormat,
+    /// Not an executable for x86_64, e.g. a relocatable or shared object.
+    NotExecutable,
+    /// A segment's file contents are out of bounds or larger than in memory,
+    /// or the segment is not in the lower half.
+    BadSegment {
+        index: usize,
+    },
+    /// Two segments share a page.
+    OverlappingSegments {
+        first: usize,
+        second: usize,
+    },
+    /// The entry point is not in an executable segment.
+    BadEntryPoint,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "file is truncated"),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::UnsupportedFormat => write!(f, "not a little-endian ELF64 file"),
+            ElfError::NotExecutable => write!(f, "not an x86_64 executable"),
+            ElfError::BadSegment { index } => write!(f, "segment {} is malformed", index),
+            ElfError::OverlappingSegments { first, second } => {
+                write!(f, "segments {} and {} overlap", first, second)
+            }
+            ElfError::BadEntryPoint => write!(f, "entry point is not executable"),
+        }
+    }
+}
+
+/// A loadable segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// Virtual address of the first byte.
+    pub address: u64,
+    /// Size in memory. The bytes after `file_range` are zeroed.
+    pub mem_size: u64,
+    /// The part of the file that is copied to `address`.
+    pub file_range: Range<usize>,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    /// End (exclusive) of the segment in memory.
+    pub fn end(&self) -> u64 {
+        self.address + self.mem_size
+    }
+
+    fn contains(&self, address: u64) -> bool {
+        (self.address..self.end()).contains(&address)
+    }
+
+    /// The page-aligned range covering the segment.
+    fn page_range(&self) -> Range<u64> {
+        let page_size = crate::memory::vmm::PAGE_SIZE;
+        let start = self.address / page_size * page_size;
+        start..self.end().div_ceil(page_size) * page_size
+    }
+}
+
+/// A validated ELF executable.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_headers: usize,
+    program_header_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Validates the headers and segments of `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64 || data[5] != DATA_LITTLE_ENDIAN || data[6] != VERSION_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if read_u16(data, 16) != TYPE_EXECUTABLE || read_u16(data, 18) != MACHINE_X86_64 {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 54) as usize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::UnsupportedFormat);
+        }
+
+        let program_headers =
+            usize::try_from(read_u64(data, 32)).map_err(|_| ElfError::Truncated)?;
+        let program_header_count = read_u16(data, 56) as usize;
+        let end = program_header_count
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| program_headers.checked_add(size))
+            .ok_or(ElfError::Truncated)?;
+        if end > data.len() {
+            return Err(ElfError::Truncated);
+        }
+
+        let elf = Elf {
+            data,
+            entry: read_u64(data, 24),
+            program_headers,
+            program_header_count,
+        };
+        elf.validate_segments()?;
+        Ok(elf)
+    }
+
+    /// Address of the first instruction.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// The non-empty `PT_LOAD` segments.
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        (0..self.program_header_count).filter_map(move |index| self.segment(index).ok().flatten())
+    }
+
+    /// Returns the loadable segment described by program header `index`, if
+    /// it is one.
+    fn segment(&self, index: usize) -> Result<Option<Segment>, ElfError> {
+        let header = &self.data[self.program_headers + index * PROGRAM_HEADER_SIZE..];
+        if read_u32(header, 0) != PT_LOAD {
+            return Ok(None);
+        }
+        let flags = read_u32(header, 4);
+        let offset = read_u64(header, 8);
+        let address = read_u64(header, 16);
+        let file_size = read_u64(header, 32);
+        let mem_size = read_u64(header, 40);
+        if mem_size == 0 {
+            return Ok(None);
+        }
+
+        let bad_segment = ElfError::BadSegment { index };
+        let file_end = offset.checked_add(file_size).ok_or(bad_segment)?;
+        let in_lower_half = address
+            .checked_add(mem_size)
+            .is_some_and(|end| end <= LOWER_HALF_END);
+        if file_end > self.data.len() as u64 || file_size > mem_size || !in_lower_half {
+            return Err(bad_segment);
+        }
+        Ok(Some(Segment {
+            address,
+            mem_size,
+            file_range: offset as usize..file_end as usize,
+            writable: flags & PF_W != 0,
+            executable: flags & PF_X != 0,
+        }))
+    }
+
+    /// Returns the file contents of `segment`.
+    pub fn segment_data(&self, segment: &Segment) -> &'a [u8] {
+        &self.data[segment.file_range.clone()]
+    }
+
+    fn validate_segments(&self) -> Result<(), ElfError> {
+        for index in 0..self.program_header_count {
+            let Some(segment) = self.segment(index)? else {
+                continue;
+            };
+            for other in 0..index {
+                let Some(previous) = self.segment(other)? else {
+                    continue;
+                };
+                let (pages, previous_pages) = (segment.page_range(), previous.page_range());
+                if pages.start < previous_pages.end && previous_pages.start < pages.end {
+                    return Err(ElfError::OverlappingSegments {
+                        first: other,
+                        second: index,
+                    });
+                }
+            }
+        }
+
+        let entry_is_executable = self
+            .segments()
+            .any(|segment| segment.executable && segment.contains(self.entry));
+        if !entry_is_executable {
+            return Err(ElfError::BadEntryPoint);
+        }
+        Ok(())
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
@@ -1,52 +1,168 @@
+use crate::memory::stack;
+use crate::memory::vmm::VmError;
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    // until `init_stacks` is called, the IST entries point to static stacks
+    // that have no guard pages
+    for (index, stack) in unsafe { (*addr_of!(BOOT_STACKS)).iter().enumerate() } {
+        let stack_start = VirtAddr::from_ptr(stack);
+        let stack_end = stack_start + BOOT_STACK_SIZE as u64;
+        unsafe { set_interrupt_stack(index as u16, stack_end) };
+    }
+
+    unsafe { load(&GDT.0, &GDT.1) };
+}
+
+/// Loads `gdt` and its segments and TSS on the current CPU.
+///
+/// ## Safety
+///
+/// `selectors` must belong to `gdt`.
+unsafe fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    use x86_64::instructions::segmentation::{Segment, CS, DS, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
-    unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
-    }
+    gdt.load();
+    CS::set_reg(selectors.kernel_code);
+    SS::set_reg(selectors.kernel_data);
+    DS::set_reg(selectors.kernel_data);
+    load_tss(selectors.tss);
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Names and sizes (in pages) of the interrupt stacks, by IST index.
+const INTERRUPT_STACKS: [(&str, u64); 3] = [("double fault", 5), ("NMI", 2), ("machine check", 2)];
+
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+static mut BOOT_STACKS: [[u8; BOOT_STACK_SIZE]; INTERRUPT_STACKS.len()] =
+    [[0; BOOT_STACK_SIZE]; INTERRUPT_STACKS.len()];
+
+/// Replaces the static interrupt stacks with stacks that have guard pages.
+///
+/// Must be called after `init`, once `memory::vmm` is set up.
+pub fn init_stacks() -> Result<(), VmError> {
+    for (index, &(name, pages)) in INTERRUPT_STACKS.iter().enumerate() {
+        let stack = stack::allocate_stack(name, pages)?;
+        unsafe { set_interrupt_stack(index as u16, stack.top) };
+    }
+    Ok(())
+}
+
+/// Points the interrupt stack table entry `index` at the stack ending at `top`.
+///
+/// The CPU reads the entry on every interrupt that uses it, so no reload is needed.
+///
+/// ## Safety
+///
+/// `top` must be the end of a mapped stack that is not used for anything else.
+/// Must not be called while an interrupt is running on the entry's old stack.
+unsafe fn set_interrupt_stack(index: u16, top: VirtAddr) {
+    (*addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = top;
+}
+
+/// Returns the stack pointer loaded on entering the kernel from user mode,
+/// zero while no user program runs.
+pub(crate) fn privilege_stack() -> VirtAddr {
+    unsafe { (*addr_of!(TSS)).privilege_stack_table[0] }
+}
+
+/// Sets the stack pointer loaded on entering the kernel from user mode.
+///
+/// ## Safety
+///
+/// `top` must be zero or the end of unused stack space of the current thread.
+pub(crate) unsafe fn set_privilege_stack(top: VirtAddr) {
+    (*addr_of_mut!(TSS)).privilege_stack_table[0] = top;
+}
+
+/// TSS of the bootstrap processor, the only one that runs user mode.
+///
+/// Mutable because the interrupt stacks are replaced once paging is set up,
+/// and the privilege stack whenever threads are switched.
+pub(crate) static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Offset in `TSS` of the stack pointer loaded on entering the kernel from
+/// user mode, for the `syscall` entry which has to load it itself.
+pub(crate) const PRIVILEGE_STACK_OFFSET: usize =
+    core::mem::offset_of!(TaskStateSegment, privilege_stack_table);
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    /// GDT of the bootstrap processor.
+    static ref GDT: (GlobalDescriptorTable, Selectors) =
+        build(unsafe { Descriptor::tss_segment_unchecked(addr_of!(TSS)) });
+}
+
+/// Builds a GDT with the kernel and user segments and the TSS `tss`.
+///
+/// Every CPU's GDT is built here, so the selectors are the same on all of them.
+fn build(tss: Descriptor) -> (GlobalDescriptorTable, Selectors) {
+    // the order of the kernel and user segments is fixed by `syscall`/`sysret`
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.append(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.append(Descriptor::kernel_data_segment());
+    let user_data = gdt.append(Descriptor::user_data_segment());
+    let user_code = gdt.append(Descriptor::user_code_segment());
+    let tss = gdt.append(tss);
+    (
+        gdt,
+        Selectors {
+            kernel_code,
+            kernel_data,
+            user_code,
+            user_data,
+            tss,
+        },
+    )
+}
+
+/// GDT and TSS of an application processor.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+}
+
+impl CpuTables {
+    /// Allocates the tables for another CPU, with interrupt stacks of its own.
+    ///
+    /// There is no privilege stack, application processors never run user
+    /// mode. The tables are never freed, CPUs are not taken offline.
+    pub fn allocate() -> Result<&'static CpuTables, VmError> {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        for (index, &(name, pages)) in INTERRUPT_STACKS.iter().enumerate() {
+            tss.interrupt_stack_table[index] = stack::allocate_stack(name, pages)?.top;
+        }
+        let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+        let (gdt, selectors) = build(Descriptor::tss_segment(tss));
+        Ok(Box::leak(Box::new(CpuTables { gdt, selectors })))
+    }
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
-        tss
-    };
+    /// Loads the tables on the current CPU, which must be the one they were
+    /// allocated for.
+    pub fn load(&'static self) {
+        unsafe { load(&self.gdt, &self.selectors) };
+    }
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
-}
-
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Segment selectors of the GDT. The user selectors have RPL 3.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+/// Returns the selectors, which are the same in every CPU's GDT.
+pub fn selectors() -> Selectors {
+    GDT.1
 }
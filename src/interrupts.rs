@@ -1,6 +1,9 @@
-use crate::{exit_qemu, gdt, hlt_loop, print, println, QemuExitCode};
+use crate::{exit_qemu, gdt, print, println, QemuExitCode};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
+use crate::memory::{stack, vmm};
+use crate::smp::{self, apic};
+use crate::usermode::{self, Exception};
 use crate::vga_buffer::{blink, scroll_down, scroll_up, WRITER};
 use lazy_static::lazy_static;
 use pc_keyboard::KeyCode;
@@ -33,34 +36,55 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        // ! Page faults stay on the interrupted stack, so that a fault inside
+        // ! the handler does not overwrite its frame. A kernel stack overflow
+        // ! faults again while pushing the frame and ends up in the double
+        // ! fault handler, which reports the guard page hit
         idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
             // ! Need to use a known-good stack for double faults as
             // ! the base fault could be a stack overflow
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
         }
         idt[InterruptIndex::Timer.as_u8()]
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_u8()]
             .set_handler_fn(keyboard_interrupt_handler);
+        idt[apic::WAKEUP_VECTOR].set_handler_fn(wakeup_interrupt_handler);
+        idt[apic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    enter_kernel(&stack_frame);
     // print!(".");
+    crate::time::tick();
     blink();
 
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    // may switch threads, so the end of interrupt has to be sent first
+    crate::thread::tick();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
+    enter_kernel(&stack_frame);
+
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode); // new
@@ -71,11 +95,33 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+/// Restores the kernel state that a user program can change, if the
+/// interrupt came from user mode. Called first by every handler.
+fn enter_kernel(stack_frame: &InterruptStackFrame) {
+    if usermode::from_user_mode(stack_frame) {
+        smp::restore_gs_base();
+    }
+}
+
+/// Loads the IDT on the current CPU. All CPUs share it.
 pub fn init_idt() {
     IDT.load();
 }
 
+extern "x86-interrupt" fn wakeup_interrupt_handler(stack_frame: InterruptStackFrame) {
+    enter_kernel(&stack_frame);
+    // returning from the interrupt ends the `hlt`, which is all the sender wants
+    smp::flush_stale_tlb();
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    enter_kernel(&stack_frame);
+    // spurious interrupts are not acknowledged
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    enter_kernel(&stack_frame);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
@@ -85,19 +131,146 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    enter_kernel(&stack_frame);
+
+    if let Ok(addr) = Cr2::read() {
+        if vmm::handle_page_fault(addr, error_code) {
+            return;
+        }
+        if usermode::from_user_mode(&stack_frame) {
+            usermode::kill(
+                Exception::PageFault,
+                &stack_frame,
+                Some(error_code.bits()),
+                Some(addr),
+            );
+        }
+        if let Some(stack) = stack::guard_page_owner(addr) {
+            panic!(
+                "EXCEPTION: PAGE FAULT\nstack overflow in stack {} (accessed {:?})\n{:#?}",
+                stack.name, addr, stack_frame
+            );
+        }
+    }
+
+    panic!(
+        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}",
+        Cr2::read(),
+        error_code,
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    enter_kernel(&stack_frame);
+    if usermode::from_user_mode(&stack_frame) {
+        usermode::kill(Exception::DivideError, &stack_frame, None, None);
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    enter_kernel(&stack_frame);
+    if usermode::from_user_mode(&stack_frame) {
+        usermode::kill(Exception::InvalidOpcode, &stack_frame, None, None);
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    enter_kernel(&stack_frame);
+    if usermode::from_user_mode(&stack_frame) {
+        usermode::kill(
+            Exception::SegmentNotPresent,
+            &stack_frame,
+            Some(error_code),
+            None,
+        );
+    }
+    panic!(
+        "EXCEPTION: SEGMENT NOT PRESENT\nError Code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    enter_kernel(&stack_frame);
+    if usermode::from_user_mode(&stack_frame) {
+        usermode::kill(
+            Exception::StackSegmentFault,
+            &stack_frame,
+            Some(error_code),
+            None,
+        );
+    }
+    panic!(
+        "EXCEPTION: STACK SEGMENT FAULT\nError Code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    enter_kernel(&stack_frame);
+    if usermode::from_user_mode(&stack_frame) {
+        usermode::kill(
+            Exception::GeneralProtectionFault,
+            &stack_frame,
+            Some(error_code),
+            None,
+        );
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT\nError Code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    enter_kernel(&stack_frame);
+
+    // a fault on a guard page of a stack without its own IST entry
+    if let Some(stack) = Cr2::read().ok().and_then(stack::guard_page_owner) {
+        panic!(
+            "EXCEPTION: DOUBLE FAULT\nstack overflow in stack {}\n{:#?}",
+            stack.name, stack_frame
+        );
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    use core::fmt::Write;
+
+    enter_kernel(&stack_frame);
+
+    // NMIs cannot be masked, so the interrupted code may hold the port
+    if let Some(mut serial) = crate::serial::SERIAL1.try_lock() {
+        let _ = writeln!(
+            serial,
+            "EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}",
+            stack_frame
+        );
+    }
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    enter_kernel(&stack_frame);
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
@@ -2,19 +2,27 @@
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
 pub mod allocator;
+pub mod elf;
 pub mod gdt;
 pub mod interrupts;
 pub mod lipsum;
 pub mod memory;
 pub mod other;
 pub mod serial;
+pub mod smp;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod usermode;
 pub mod vga_buffer;
 
 #[cfg(test)]
@@ -57,7 +65,17 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 /// Entry point for `cargo test`
 #[cfg(test)]
 fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
     init();
+    // the task tests need a heap
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    memory::vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
     test_main();
     hlt_loop();
 }
@@ -71,6 +89,9 @@ pub fn hlt_loop() -> ! {
 pub fn init() {
     interrupts::init_idt();
     gdt::init();
+    smp::init_bsp();
+    syscall::init();
+    memory::mmio::init();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
 }
@@ -9,9 +9,11 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use slate::lipsum::LipsumIterator;
 use slate::memory::BootInfoFrameAllocator;
+use slate::memory::{stack, vmm};
 use slate::task::executor::Executor;
-use slate::task::{keyboard, Task};
-use slate::{allocator, hlt_loop, memory, print, println, serial_println};
+use slate::task::keyboard;
+use slate::usermode::{loader, programs};
+use slate::{allocator, gdt, hlt_loop, memory, print, println, serial_println, smp, thread};
 use x86_64::VirtAddr;
 use slate::other::arbitrary_delay;
 
@@ -23,18 +25,45 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     slate::init();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+    gdt::init_stacks().expect("interrupt stack setup failed");
+    let kernel_stack =
+        stack::allocate_stack("kernel", KERNEL_STACK_PAGES).expect("kernel stack setup failed");
+    #[cfg(feature = "alloc-fixed-block")]
+    allocator::enable_slabs();
+
+    // leave the bootloader's stack for one with a guard page
+    unsafe { stack::switch_to(kernel_stack, kernel_main_on_stack) }
+}
+
+const KERNEL_STACK_PAGES: u64 = 32;
+
+fn kernel_main_on_stack() -> ! {
+    // the executor below runs on the main thread
+    thread::init().expect("thread setup failed");
 
     #[cfg(test)]
     test_main();
 
     println!("Before");
 
-    let mut executor = Executor::new();
-    executor.spawn(Task::new(keyboard::print_keypresses())); // new
-    // executor.spawn(Task::new(main()));
+    match loader::exec(programs::HELLO, &["hello"]) {
+        Ok(exit) => println!("hello {}", exit),
+        Err(error) => println!("failed to run hello: {}", error),
+    }
+
+    match smp::init() {
+        Ok(cpus) => println!("{} CPUs online", cpus),
+        Err(error) => println!("failed to start the other CPUs: {}", error),
+    }
+
+    let mut executor = Executor::new().with_work_stealing();
+    executor.spawn_named("keyboard", keyboard::print_keypresses()); // new
+    // executor.spawn_with_priority(Priority::Background, main());
     executor.run();
 
     println!("After");
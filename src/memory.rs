@@ -1,13 +1,33 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
+pub mod address_space;
+pub mod mmio;
+pub mod stack;
+pub mod vmm;
+
+/// Virtual address at which the bootloader mapped the complete physical memory.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// Frame allocator shared by the whole kernel, see `init_frame_allocator`.
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// Deallocated frames are kept in a list that is threaded through the frames
+/// themselves, using the physical memory mapping.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free_list: Option<PhysFrame>,
+    /// Usable frame below 1 MiB that is never handed out, see `low_frame`.
+    low_frame: Option<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -20,6 +40,8 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_list: None,
+            low_frame: find_low_frame(memory_map),
         }
     }
 
@@ -33,34 +55,119 @@ impl BootInfoFrameAllocator {
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        let frames = frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
+        let low_frame = self.low_frame;
+        frames.filter(move |&frame| Some(frame) != low_frame)
     }
 }
 
+/// Returns the first usable frame between 4 KiB and 1 MiB. The first frame
+/// is skipped, it holds the real-mode interrupt vector table.
+fn find_low_frame(memory_map: &MemoryMap) -> Option<PhysFrame> {
+    const START: u64 = 0x1000;
+    const END: u64 = 0x10_0000;
+    memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+        .find_map(|r| {
+            let start = r.range.start_addr().max(START).next_multiple_of(4096);
+            let end = r.range.end_addr().min(END);
+            (start + 4096 <= end).then(|| PhysFrame::containing_address(PhysAddr::new(start)))
+        })
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_list {
+            // the first 8 bytes of a free frame hold the address of the next one
+            let next: *const u64 = phys_to_virt(frame.start_address()).as_ptr();
+            let next = unsafe { next.read() };
+            self.free_list =
+                (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
-/// Creates an example mapping for the given page to frame `0xb8000`.
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    use x86_64::structures::paging::PageTableFlags as Flags;
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next: *mut u64 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        next.write(self.free_list.map_or(0, |f| f.start_address().as_u64()));
+        self.free_list = Some(frame);
+    }
+}
+
+/// Hands the frame allocator over to the kernel, making it available through
+/// `GlobalFrameAllocator`.
+///
+/// Must be called after `init`.
+pub fn init_frame_allocator(frame_allocator: BootInfoFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Returns a free frame below 1 MiB, which the frame allocator never hands
+/// out, for code that has to run in real mode like the SMP trampoline.
+///
+/// `None` if the memory map has no such frame or no frame allocator has been
+/// registered.
+pub fn low_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_ref()?.low_frame
+}
+
+/// Handle to the frame allocator registered with `init_frame_allocator`.
+///
+/// Allocation fails if no frame allocator has been registered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // the page fault handler allocates frames, and so do holders of the
+        // `vmm` lock, which cannot be preempted
+        interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR
+                .lock()
+                .as_mut()
+                .expect("frame allocator not initialized")
+                .deallocate_frame(frame)
+        })
+    }
+}
 
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+/// Returns the virtual address through which the given physical address can
+/// be accessed.
+///
+/// Panics if called before `init`.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .try_get()
+        .expect("memory::init not called");
+    *offset + addr.as_u64()
+}
+
+/// Returns the virtual address at which the complete physical memory is mapped.
+///
+/// Panics if called before `init`.
+pub fn physical_memory_offset() -> VirtAddr {
+    phys_to_virt(PhysAddr::zero())
+}
 
-    let map_to_result = unsafe {
-        // FIXME: this is not safe, we do it only for testing
-        mapper.map_to(page, frame, flags, frame_allocator)
-    };
-    map_to_result.expect("map_to failed").flush();
+/// Inverse of `phys_to_virt`, for addresses inside the physical memory mapping.
+pub fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .try_get()
+        .expect("memory::init not called");
+    PhysAddr::new(addr - *offset)
 }
 
 /// Initialize a new OffsetPageTable.
@@ -70,6 +177,7 @@ pub fn create_example_mapping(
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.init_once(|| physical_memory_offset);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
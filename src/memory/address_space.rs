@@ -0,0 +1,389 @@
+//! Separate address spaces for user programs.
+//!
+//! Every `AddressSpace` has its own level 4 table. The kernel is not in the
+//! upper half here (the bootloader maps it, the physical memory and its own
+//! data at low addresses), so instead of sharing a fixed half, a new table
+//! shares every kernel entry except those covering the user range
+//! `USER_SPACE_START..USER_SPACE_END`. The kernel never maps anything in
+//! that range, so user mappings cannot collide with kernel ones.
+//!
+//! If the CPU supports PCIDs, each address space gets its own one, so
+//! switching between them does not throw away the TLB entries of the others.
+
+use crate::memory::vmm::{self, VmError};
+use crate::memory::{self, GlobalFrameAllocator};
+use conquer_once::spin::OnceCell;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::{Cr3, Cr4, Cr4Flags};
+use x86_64::structures::paging::mapper::{TranslateResult, UnmapError};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{
+    FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+    Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Start of the range available to user programs.
+pub const USER_SPACE_START: u64 = 0x_4000_0000_0000;
+/// End (exclusive) of the user range, where the kernel window starts.
+pub const USER_SPACE_END: u64 = vmm::KERNEL_SPACE_START;
+
+/// PCID of the kernel's own page table.
+const KERNEL_PCID: u16 = 0;
+const PCID_COUNT: usize = 4096;
+/// Bit 63 of CR3 keeps the TLB entries of the new PCID when switching.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+/// Marks a PCID as never flushed.
+const NEVER_FLUSHED: u64 = u64::MAX;
+
+/// The bootloader's level 4 table, which the kernel keeps running on.
+static KERNEL_P4: OnceCell<PhysFrame> = OnceCell::uninit();
+/// `vmm::generation` when the kernel's TLB entries were last flushed.
+static KERNEL_FLUSHED: AtomicU64 = AtomicU64::new(NEVER_FLUSHED);
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Bit set -> PCID in use. The kernel's PCID is always in use.
+static PCIDS: Mutex<[u64; PCID_COUNT / 64]> = Mutex::new({
+    let mut pcids = [0; PCID_COUNT / 64];
+    pcids[KERNEL_PCID as usize] = 1;
+    pcids
+});
+
+/// Records the kernel's level 4 table and enables PCIDs if available.
+///
+/// Called by `vmm::init`.
+pub(crate) fn init() {
+    let (frame, _) = Cr3::read();
+    KERNEL_P4.init_once(|| frame);
+
+    let has_pcid = __cpuid(1).ecx & (1 << 17) != 0;
+    if has_pcid {
+        // CR3 still holds PCID 0 (the kernel's), as required for enabling them
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+        PCID_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switches back to the kernel's own page table.
+pub fn activate_kernel() {
+    unsafe { switch(kernel_p4(), KERNEL_PCID, Some(&KERNEL_FLUSHED)) };
+}
+
+/// Returns the kernel's level 4 table.
+pub(crate) fn kernel_p4() -> PhysFrame {
+    *KERNEL_P4.try_get().expect("vmm::init not called")
+}
+
+/// Returns the level 4 table in CR3 and its PCID, for `restore`.
+pub(crate) fn active() -> (PhysFrame, u16) {
+    Cr3::read_raw()
+}
+
+/// Returns what `active` returns while the kernel's own page table is loaded.
+pub(crate) fn kernel_active() -> (PhysFrame, u16) {
+    (kernel_p4(), KERNEL_PCID)
+}
+
+/// Loads a page table returned by `active` back into CR3, flushing the TLB
+/// entries of its PCID, unless it is still loaded.
+///
+/// For the scheduler, which keeps the page table of each thread.
+///
+/// ## Safety
+///
+/// Like `AddressSpace::activate`, and the page table must still exist.
+pub(crate) unsafe fn restore((p4, pcid): (PhysFrame, u16)) {
+    if active() == (p4, pcid) {
+        return;
+    }
+    if pcid == KERNEL_PCID && p4 != kernel_p4() {
+        // an address space without a PCID of its own, see `activate`
+        KERNEL_FLUSHED.store(NEVER_FLUSHED, Ordering::Relaxed);
+    }
+    switch(p4, pcid, None);
+}
+
+/// Moves an application processor from the page table it was started with
+/// to the kernel's, enabling PCIDs on it if `init` did so on the bootstrap
+/// processor.
+///
+/// `KERNEL_FLUSHED` only tracks the bootstrap processor, so this always
+/// flushes.
+pub(crate) fn init_ap() {
+    if PCID_ENABLED.load(Ordering::Relaxed) {
+        // CR3 holds a page table without PCID, as required for enabling them
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+    }
+    unsafe { switch(kernel_p4(), KERNEL_PCID, None) };
+}
+
+/// Returns whether the `len` bytes at `start` are mapped for user mode in the
+/// active address space, and writable if `write` is set.
+///
+/// System calls check user pointers with this before following them.
+pub fn is_user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(end) = start.as_u64().checked_add(len) else {
+        return false;
+    };
+    if start.as_u64() < USER_SPACE_START || end > USER_SPACE_END {
+        return false;
+    }
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let (frame, _) = Cr3::read();
+    let table =
+        unsafe { OffsetPageTable::new(&mut *table_ptr(frame), memory::physical_memory_offset()) };
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(Page::containing_address(start), last).all(|page| {
+        match table.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(required),
+            _ => false,
+        }
+    })
+}
+
+/// A level 4 page table with its own user mappings.
+///
+/// All user pages are backed by frames owned by the address space, which are
+/// freed together with the page tables on drop.
+pub struct AddressSpace {
+    p4: PhysFrame,
+    /// `None` if PCIDs are disabled or all are in use.
+    pcid: Option<u16>,
+    /// `vmm::generation` when this space's TLB entries were last flushed.
+    flushed: AtomicU64,
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel mapped and no user pages.
+    pub fn new() -> Result<AddressSpace, VmError> {
+        let kernel_p4 = *KERNEL_P4.try_get().map_err(|_| VmError::NotInitialized)?;
+        let p4 = vmm::zeroed_frame().ok_or(VmError::FrameAllocationFailed)?;
+        let kernel_table = unsafe { &*table_ptr(kernel_p4) };
+        let table = unsafe { &mut *table_ptr(p4) };
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if is_user_entry(index) {
+                assert!(entry.is_unused(), "kernel mapping in user range");
+            } else {
+                table[index] = entry.clone();
+            }
+        }
+
+        Ok(AddressSpace {
+            p4,
+            pcid: allocate_pcid(),
+            flushed: AtomicU64::new(NEVER_FLUSHED),
+        })
+    }
+
+    /// The frame holding the level 4 table.
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4
+    }
+
+    /// Returns whether this address space is loaded in CR3.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4
+    }
+
+    /// Maps `pages` to fresh zeroed frames accessible from user mode.
+    ///
+    /// `PRESENT` and `USER_ACCESSIBLE` are added to `flags`. On failure, the
+    /// pages mapped so far are unmapped again.
+    pub fn map_user(&mut self, pages: PageRange, flags: PageTableFlags) -> Result<(), VmError> {
+        check_user_range(pages)?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if let Err((error, failed)) = self.try_map(pages, flags) {
+            self.unmap_user(Page::range(pages.start, failed))?;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Maps `pages`, returning the page that could not be mapped on failure.
+    fn try_map(&mut self, pages: PageRange, flags: PageTableFlags) -> Result<(), (VmError, Page)> {
+        let mut mapper = unsafe { self.mapper() };
+        for page in pages {
+            let frame = vmm::zeroed_frame().ok_or((VmError::FrameAllocationFailed, page))?;
+            match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+                // unmapped pages are not cached in the TLB, so no flush is needed
+                Ok(flush) => flush.ignore(),
+                Err(error) => {
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                    return Err((vmm::map_error(error, page), page));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps the mapped pages in `pages` and frees their frames.
+    pub fn unmap_user(&mut self, pages: PageRange) -> Result<(), VmError> {
+        check_user_range(pages)?;
+        let active = self.is_active();
+        let mut mapper = unsafe { self.mapper() };
+        for page in pages {
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    if active {
+                        flush.flush();
+                    } else {
+                        flush.ignore();
+                    }
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(error) => panic!("failed to unmap {:?}: {:?}", page, error),
+            }
+        }
+        if !active {
+            // the entries may still be cached under our PCID
+            self.flushed.store(NEVER_FLUSHED, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to the already mapped user memory at `addr`, regardless
+    /// of the page flags and without activating the address space.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), VmError> {
+        let mut written = 0;
+        while written < data.len() {
+            let addr = addr + written as u64;
+            let phys = self.translate(addr).ok_or(VmError::NotMapped { addr })?;
+            let in_page = (vmm::PAGE_SIZE - u64::from(addr.page_offset())) as usize;
+            let len = in_page.min(data.len() - written);
+            let dest: *mut u8 = memory::phys_to_virt(phys).as_mut_ptr();
+            unsafe { dest.copy_from_nonoverlapping(data[written..].as_ptr(), len) };
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Returns the physical address `addr` is mapped to in this address space.
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        unsafe { self.mapper() }.translate_addr(addr)
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// ## Safety
+    ///
+    /// The code, stack and data currently in use must be mapped in this
+    /// address space too, i.e. live in the kernel's mappings.
+    pub unsafe fn activate(&self) {
+        match self.pcid {
+            Some(pcid) => switch(self.p4, pcid, Some(&self.flushed)),
+            None => {
+                // borrow the kernel's PCID, whose entries get replaced by ours
+                switch(self.p4, KERNEL_PCID, None);
+                KERNEL_FLUSHED.store(NEVER_FLUSHED, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// ## Safety
+    ///
+    /// The returned mapper must be the only reference to the page tables.
+    unsafe fn mapper(&mut self) -> OffsetPageTable<'_> {
+        OffsetPageTable::new(&mut *table_ptr(self.p4), memory::physical_memory_offset())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            activate_kernel();
+        }
+        unsafe {
+            let table = &mut *table_ptr(self.p4);
+            for (index, entry) in table.iter_mut().enumerate() {
+                if is_user_entry(index) && !entry.is_unused() {
+                    free_table(entry.frame().expect("huge page in user range"), 3);
+                }
+            }
+            GlobalFrameAllocator.deallocate_frame(self.p4);
+        }
+        if let Some(pcid) = self.pcid {
+            PCIDS.lock()[pcid as usize / 64] &= !(1 << (pcid % 64));
+        }
+    }
+}
+
+/// Loads `p4` into CR3, flushing the TLB entries of `pcid` only if kernel
+/// mappings were removed since `flushed` was last updated. Always flushes
+/// without `flushed`.
+unsafe fn switch(p4: PhysFrame, pcid: u16, flushed: Option<&AtomicU64>) {
+    let mut value = p4.start_address().as_u64();
+    if PCID_ENABLED.load(Ordering::Relaxed) {
+        let generation = vmm::generation();
+        value |= pcid as u64;
+        let up_to_date =
+            flushed.is_some_and(|flushed| flushed.swap(generation, Ordering::AcqRel) == generation);
+        if up_to_date {
+            value |= CR3_NO_FLUSH;
+        }
+    }
+    asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+}
+
+fn allocate_pcid() -> Option<u16> {
+    if !PCID_ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut pcids = PCIDS.lock();
+    let (word, bits) = pcids
+        .iter_mut()
+        .enumerate()
+        .find(|(_, bits)| **bits != u64::MAX)?;
+    let bit = bits.trailing_ones() as usize;
+    *bits |= 1 << bit;
+    Some((word * 64 + bit) as u16)
+}
+
+/// Returns whether the level 4 entry `index` belongs to the user range.
+fn is_user_entry(index: usize) -> bool {
+    let first = u16::from(VirtAddr::new(USER_SPACE_START).p4_index()) as usize;
+    let last = u16::from(VirtAddr::new(USER_SPACE_END - 1).p4_index()) as usize;
+    (first..=last).contains(&index)
+}
+
+fn check_user_range(pages: PageRange) -> Result<(), VmError> {
+    let start = pages.start.start_address().as_u64();
+    let end = pages.end.start_address().as_u64();
+    if start < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(VmError::OutOfBounds);
+    }
+    Ok(())
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Frees the table in `frame` and everything mapped through it.
+///
+/// `level` is 3 for a level 3 table, down to 1 for a table mapping pages.
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    let table = &mut *table_ptr(frame);
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        let child = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            assert!(
+                !entry.flags().contains(PageTableFlags::HUGE_PAGE),
+                "huge page in user range"
+            );
+            free_table(child, level - 1);
+        } else {
+            GlobalFrameAllocator.deallocate_frame(child);
+        }
+    }
+    GlobalFrameAllocator.deallocate_frame(frame);
+}
@@ -0,0 +1,214 @@
+//! Mapping of memory-mapped device registers.
+//!
+//! `ioremap` maps a physical range into a fresh kernel region with caching
+//! disabled (or relaxed to write-through/write-combining) and returns an
+//! `Mmio` handle. All accesses through the handle are volatile and checked
+//! against the mapped length. The mapping is removed when the handle is dropped.
+
+use crate::memory::vmm::{self, Region, VmError};
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+const IA32_PAT: u32 = 0x277;
+
+/// PAT memory types.
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+
+/// Flags selecting the PAT entry `init` turns into write-combining.
+///
+/// It replaces the UC- entry selected by PCD alone, which no mapping uses.
+/// The entries for WB, WT and UC keep their reset values, so the other modes
+/// behave as without PAT. The upper half of the PAT is out of reach: in a
+/// 4 KiB page table entry it is selected by the same bit as `HUGE_PAGE`,
+/// which the page table code does not allow.
+const WRITE_COMBINING_FLAGS: PageTableFlags = PageTableFlags::NO_CACHE;
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Reprograms the PAT to make write-combining available, if the CPU supports it.
+///
+/// Must be called on every CPU before the first `CacheMode::WriteCombining`
+/// mapping is created.
+pub fn init() {
+    let has_pat = __cpuid(1).edx & (1 << 16) != 0;
+    if !has_pat {
+        return;
+    }
+
+    let entries = [
+        PAT_WB,
+        PAT_WT,
+        PAT_WC,
+        PAT_UC,
+        PAT_WB,
+        PAT_WT,
+        PAT_UC_MINUS,
+        PAT_UC,
+    ];
+    let value = entries
+        .iter()
+        .enumerate()
+        .fold(0, |value, (index, &entry)| value | entry << (index * 8));
+    interrupts::without_interrupts(|| unsafe {
+        Msr::new(IA32_PAT).write(value);
+        // cached lines and TLB entries may still carry the old memory type
+        asm!("wbinvd", options(nostack, preserves_flags));
+        let (frame, cr3) = Cr3::read_raw();
+        Cr3::write_raw(frame, cr3);
+    });
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// How accesses to a device mapping are cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// No caching at all, for device registers.
+    Uncached,
+    /// Reads are cached, writes go straight to the device.
+    WriteThrough,
+    /// Writes are buffered and combined, for frame buffers. Falls back to
+    /// `Uncached` if the CPU has no PAT.
+    WriteCombining,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if PAT_ENABLED.load(Ordering::Relaxed) => {
+                WRITE_COMBINING_FLAGS
+            }
+            CacheMode::WriteCombining => CacheMode::Uncached.flags(),
+        }
+    }
+}
+
+/// Values that can be read from and written to device memory.
+pub trait MmioValue: Copy + private::Sealed {}
+
+impl MmioValue for u8 {}
+impl MmioValue for u16 {}
+impl MmioValue for u32 {}
+impl MmioValue for u64 {}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+/// A mapped range of device memory, unmapped on drop.
+#[derive(Debug)]
+pub struct Mmio {
+    region: Region,
+    phys: PhysAddr,
+    base: VirtAddr,
+    len: usize,
+}
+
+/// Maps `len` bytes of device memory starting at `phys`.
+///
+/// `phys` does not need to be page aligned.
+///
+/// ## Safety
+///
+/// `phys..phys + len` must be device memory (or otherwise not in use as RAM),
+/// since reads and writes through the handle can have side effects.
+pub unsafe fn ioremap(phys: PhysAddr, len: usize, mode: CacheMode) -> Result<Mmio, VmError> {
+    assert!(len > 0, "empty MMIO range");
+    let phys_start = phys.align_down(vmm::PAGE_SIZE);
+    let offset = phys - phys_start;
+    let pages = vmm::pages_for(offset + len as u64);
+
+    let region = vmm::reserve("mmio", pages)?;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | mode.flags();
+    if let Err(error) = vmm::map_region_to(&region, phys_start, flags) {
+        vmm::release(&region).expect("failed to release MMIO region");
+        return Err(error);
+    }
+
+    Ok(Mmio {
+        region,
+        phys,
+        base: region.start + offset,
+        len,
+    })
+}
+
+impl Mmio {
+    /// Physical address the handle starts at.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Virtual address the device memory is mapped at.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the value at `offset` bytes from the start.
+    ///
+    /// Panics if the access is out of bounds or misaligned.
+    pub fn read<T: MmioValue>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Writes `value` at `offset` bytes from the start.
+    ///
+    /// Panics if the access is out of bounds or misaligned.
+    pub fn write<T: MmioValue>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    /// Reads the value at `offset`, passes it to `f` and writes back the result.
+    pub fn update<T: MmioValue>(&self, offset: usize, f: impl FnOnce(T) -> T) {
+        self.write(offset, f(self.read(offset)));
+    }
+
+    fn ptr<T: MmioValue>(&self, offset: usize) -> *mut T {
+        let size = mem::size_of::<T>();
+        assert!(
+            offset.checked_add(size).is_some_and(|end| end <= self.len),
+            "MMIO access of {} bytes at offset {:#x} out of bounds (length {:#x})",
+            size,
+            offset,
+            self.len
+        );
+        let ptr: *mut T = (self.base + offset as u64).as_mut_ptr();
+        assert!(
+            ptr.is_aligned(),
+            "misaligned MMIO access at offset {:#x}",
+            offset
+        );
+        ptr
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        vmm::release(&self.region).expect("failed to unmap MMIO region");
+    }
+}
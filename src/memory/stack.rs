@@ -0,0 +1,107 @@
+//! Kernel stacks with guard pages.
+//!
+//! Each stack is a region of kernel virtual memory whose lowest page is left
+//! unmapped as a guard page, so overflowing it causes a page fault that
+//! `guard_page_owner` can attribute to the stack instead of silently
+//! corrupting whatever lies below.
+
+use crate::memory::vmm::{self, VmError};
+use arrayvec::ArrayVec;
+use core::arch::asm;
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Maximum number of stacks that can be registered.
+pub const MAX_STACKS: usize = 96;
+
+/// A stack allocated by `allocate_stack`.
+#[derive(Debug, Clone, Copy)]
+pub struct StackInfo {
+    pub name: &'static str,
+    /// Unmapped page directly below the stack.
+    pub guard: Page,
+    pub bottom: VirtAddr,
+    pub top: VirtAddr,
+}
+
+static STACKS: Mutex<ArrayVec<StackInfo, MAX_STACKS>> = Mutex::new(ArrayVec::new_const());
+
+/// Maps a new stack of `pages` pages above an unmapped guard page and returns it.
+pub fn allocate_stack(name: &'static str, pages: u64) -> Result<StackInfo, VmError> {
+    let mut stacks = STACKS.lock();
+    assert!(!stacks.is_full(), "too many stacks");
+
+    let region = vmm::reserve(name, pages + 1)?;
+    let guard = Page::containing_address(region.start);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if let Err(error) = vmm::map_pages(Page::range(guard + 1, guard + pages + 1), flags) {
+        vmm::release(&region).expect("failed to release stack region");
+        return Err(error);
+    }
+
+    let stack = StackInfo {
+        name,
+        guard,
+        bottom: (guard + 1).start_address(),
+        top: region.end(),
+    };
+    stacks.push(stack);
+    Ok(stack)
+}
+
+/// Unmaps a stack returned by `allocate_stack`, including its guard page.
+///
+/// ## Safety
+///
+/// Nothing may run on the stack or reference memory on it anymore.
+pub unsafe fn free_stack(stack: StackInfo) {
+    let mut stacks = STACKS.lock();
+    let index = stacks
+        .iter()
+        .position(|s| s.guard == stack.guard)
+        .expect("stack was not allocated by allocate_stack");
+    stacks.remove(index);
+    let region = vmm::region_containing(stack.guard.start_address()).expect("stack region missing");
+    vmm::release(&region).expect("failed to release stack region");
+}
+
+/// Returns the stack whose guard page contains `addr`.
+///
+/// Called from fault handlers, so it gives up instead of spinning if the
+/// stack list is locked.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<StackInfo> {
+    let stacks = STACKS.try_lock()?;
+    let page = Page::<Size4KiB>::containing_address(addr);
+    stacks.iter().find(|s| s.guard == page).copied()
+}
+
+/// Returns the stack whose mapped pages contain `addr`.
+///
+/// Gives up instead of spinning if the stack list is locked, like
+/// `guard_page_owner`, because the allocator calls this as well.
+pub fn containing(addr: VirtAddr) -> Option<StackInfo> {
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .find(|s| (s.bottom..s.top).contains(&addr))
+        .copied()
+}
+
+/// Switches to the given stack and calls `f` on it.
+///
+/// ## Safety
+///
+/// `stack` must be mapped and unused. Everything on the current stack is
+/// abandoned, so no references into it may be passed to `f`.
+pub unsafe fn switch_to(stack: StackInfo, f: fn() -> !) -> ! {
+    asm!(
+        "mov rsp, {top}",
+        "xor rbp, rbp",
+        "call {f}",
+        "ud2",
+        top = in(reg) stack.top.as_u64(),
+        f = in(reg) f,
+        options(noreturn)
+    )
+}
@@ -0,0 +1,591 @@
+//! Kernel virtual memory manager.
+//!
+//! Kernel mappings live in a dedicated window of the address space. Regions of
+//! it are reserved by name (the heap, stacks, device memory, ...) and are
+//! guaranteed not to overlap. Reserving only claims the virtual range; pages
+//! are mapped and unmapped with `map_region`/`unmap_region`, which also take
+//! care of flushing the TLB.
+//!
+//! The window spans a handful of level 4 entries whose level 3 tables are
+//! created up front by `init`, so address spaces created later can share
+//! them and see every future kernel mapping.
+//!
+//! Regions reserved with `reserve_lazy` are committed on demand instead: their
+//! pages are only backed by a zeroed frame when they are first touched, which
+//! `handle_page_fault` takes care of.
+//!
+//! The manager is locked with interrupts disabled, so the page fault handler
+//! can always wait for the lock unless the faulting code holds it itself.
+
+use crate::memory::{self, GlobalFrameAllocator};
+use crate::smp;
+use arrayvec::ArrayVec;
+use core::fmt;
+use core::hint::spin_loop;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult, UnmapError};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+    Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Start of the window of kernel virtual memory managed here.
+pub const KERNEL_SPACE_START: u64 = 0x_4400_0000_0000;
+/// End (exclusive) of the kernel window, 4 level 4 entries (2 TiB) after the start.
+pub const KERNEL_SPACE_END: u64 = 0x_4600_0000_0000;
+/// Maximum number of regions that can be reserved at the same time.
+pub const MAX_REGIONS: usize = 128;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Returns the number of pages needed to hold `size` bytes.
+pub const fn pages_for(size: u64) -> u64 {
+    size.div_ceil(PAGE_SIZE)
+}
+
+/// A reserved range of kernel virtual memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub pages: u64,
+}
+
+impl Region {
+    /// First address after the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.pages * PAGE_SIZE
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    pub fn page_range(&self) -> PageRange {
+        let start = Page::containing_address(self.start);
+        Page::range(start, start + self.pages)
+    }
+}
+
+/// Errors returned by the virtual memory manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// `init` has not been called yet.
+    NotInitialized,
+    /// No free range of the requested size is left in the kernel window.
+    OutOfVirtualSpace,
+    /// All `MAX_REGIONS` region slots are in use.
+    TooManyRegions,
+    /// The requested range is not inside the kernel window.
+    OutOfBounds,
+    /// The requested range overlaps an already reserved region.
+    Overlap { existing: Region },
+    /// The pages are not inside a single reserved region.
+    NotReserved,
+    /// The frame allocator ran out of frames.
+    FrameAllocationFailed,
+    /// A page of the range was already mapped.
+    AlreadyMapped { page: Page },
+    /// An address that needs to be mapped is not.
+    NotMapped { addr: VirtAddr },
+    /// A page table on the way is a huge page.
+    HugePage,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::NotInitialized => write!(f, "virtual memory manager not initialized"),
+            VmError::OutOfVirtualSpace => write!(f, "out of kernel virtual address space"),
+            VmError::TooManyRegions => write!(f, "too many regions"),
+            VmError::OutOfBounds => write!(f, "range outside of the kernel window"),
+            VmError::Overlap { existing } => write!(
+                f,
+                "range overlaps region {} at {:?}",
+                existing.name, existing.start
+            ),
+            VmError::NotReserved => write!(f, "pages not inside a reserved region"),
+            VmError::FrameAllocationFailed => write!(f, "out of physical frames"),
+            VmError::AlreadyMapped { page } => write!(f, "{:?} already mapped", page),
+            VmError::NotMapped { addr } => write!(f, "{:?} not mapped", addr),
+            VmError::HugePage => write!(f, "range covered by a huge page"),
+        }
+    }
+}
+
+/// Where the frames of a region come from, which decides whether unmapping
+/// gives them back to the frame allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backing {
+    Unmapped,
+    /// Fresh frames from the frame allocator, owned by the region.
+    Allocated,
+    /// A fixed physical range (e.g. device memory) that is not owned.
+    Physical,
+    /// Fresh frames mapped with the given flags on first access, owned by the region.
+    Lazy(PageTableFlags),
+}
+
+impl Backing {
+    fn owns_frames(self) -> bool {
+        matches!(self, Backing::Allocated | Backing::Lazy(_))
+    }
+}
+
+struct Entry {
+    region: Region,
+    backing: Backing,
+}
+
+struct Vmm {
+    mapper: Option<OffsetPageTable<'static>>,
+    /// Sorted by start address.
+    regions: ArrayVec<Entry, MAX_REGIONS>,
+}
+
+static VMM: Mutex<Vmm> = Mutex::new(Vmm {
+    mapper: None,
+    regions: ArrayVec::new_const(),
+});
+
+/// Index of the CPU holding the `VMM` lock, or `NO_OWNER`.
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+const NO_OWNER: usize = usize::MAX;
+
+/// Maximum number of frames `unmap` holds back until the other CPUs have
+/// flushed their TLBs.
+const UNMAP_BATCH: usize = 64;
+
+/// Incremented whenever a kernel page is unmapped, see `generation`.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Hands the kernel page table over to the virtual memory manager.
+///
+/// Must be called after `memory::init`, `memory::init_frame_allocator` and
+/// `smp::init_bsp`.
+pub fn init(mut mapper: OffsetPageTable<'static>) {
+    let level_4_table = mapper.level_4_table_mut();
+    let first = VirtAddr::new(KERNEL_SPACE_START).p4_index();
+    let last = VirtAddr::new(KERNEL_SPACE_END - 1).p4_index();
+    for index in u16::from(first)..=u16::from(last) {
+        let entry = &mut level_4_table[index as usize];
+        if entry.is_unused() {
+            let frame = zeroed_frame().expect("no frame for kernel page table");
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+
+    lock().mapper = Some(mapper);
+    memory::address_space::init();
+}
+
+/// Returns a counter that changes whenever a kernel mapping is removed.
+///
+/// TLB entries of other address spaces may be stale if it changed since they
+/// were last flushed.
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Reserves a free range of `pages` pages anywhere in the kernel window.
+pub fn reserve(name: &'static str, pages: u64) -> Result<Region, VmError> {
+    assert!(pages > 0, "empty region");
+    let mut vmm = lock();
+    let start = vmm.find_free(pages).ok_or(VmError::OutOfVirtualSpace)?;
+    vmm.insert(Region { name, start, pages }, Backing::Unmapped)
+}
+
+/// Reserves a free range of `pages` pages whose pages are mapped to zeroed
+/// frames with `flags` when they are first accessed.
+///
+/// Nothing is mapped up front, so this is cheap even for large regions.
+pub fn reserve_lazy(
+    name: &'static str,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmError> {
+    assert!(pages > 0, "empty region");
+    let mut vmm = lock();
+    let start = vmm.find_free(pages).ok_or(VmError::OutOfVirtualSpace)?;
+    let flags = flags | PageTableFlags::PRESENT;
+    vmm.insert(Region { name, start, pages }, Backing::Lazy(flags))
+}
+
+/// Reserves the range of `pages` pages starting at `start`, which must be
+/// page aligned.
+pub fn reserve_at(name: &'static str, start: VirtAddr, pages: u64) -> Result<Region, VmError> {
+    assert!(pages > 0, "empty region");
+    assert!(start.is_aligned(PAGE_SIZE), "region start not page aligned");
+    let region = Region { name, start, pages };
+    let end = start.as_u64().checked_add(region.size());
+    if start.as_u64() < KERNEL_SPACE_START || end.is_none_or(|end| end > KERNEL_SPACE_END) {
+        return Err(VmError::OutOfBounds);
+    }
+
+    let mut vmm = lock();
+    let overlapping = vmm
+        .regions
+        .iter()
+        .find(|entry| entry.region.start < region.end() && region.start < entry.region.end());
+    if let Some(entry) = overlapping {
+        return Err(VmError::Overlap {
+            existing: entry.region,
+        });
+    }
+    vmm.insert(region, Backing::Unmapped)
+}
+
+/// Unmaps everything in the region and makes its range available again.
+pub fn release(region: &Region) -> Result<(), VmError> {
+    let mut vmm = lock();
+    vmm.unmap_pages(region.page_range())?;
+    let index = vmm
+        .regions
+        .iter()
+        .position(|entry| entry.region == *region)
+        .ok_or(VmError::NotReserved)?;
+    vmm.regions.remove(index);
+    Ok(())
+}
+
+/// Maps the whole region to freshly allocated, zeroed frames.
+///
+/// `PRESENT` is added to `flags`. The frames are freed again when the region
+/// is unmapped.
+pub fn map_region(region: &Region, flags: PageTableFlags) -> Result<(), VmError> {
+    map_pages(region.page_range(), flags)
+}
+
+/// Like `map_region`, but only maps `pages`, which must lie inside a single
+/// reserved region.
+pub fn map_pages(pages: PageRange, flags: PageTableFlags) -> Result<(), VmError> {
+    lock().map_pages(pages, flags, Backing::Allocated, |_| zeroed_frame())
+}
+
+/// Maps the region to the physical range starting at `phys`, e.g. to access
+/// device memory.
+///
+/// The frames are not owned by the region and are not freed on unmap.
+pub fn map_region_to(
+    region: &Region,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), VmError> {
+    assert!(
+        phys.is_aligned(PAGE_SIZE),
+        "physical address not page aligned"
+    );
+    let first_page = Page::containing_address(region.start);
+    lock().map_pages(region.page_range(), flags, Backing::Physical, |page| {
+        let offset = (page - first_page) * PAGE_SIZE;
+        Some(PhysFrame::containing_address(phys + offset))
+    })
+}
+
+/// Unmaps all mapped pages of the region, which stays reserved.
+pub fn unmap_region(region: &Region) -> Result<(), VmError> {
+    unmap_pages(region.page_range())
+}
+
+/// Unmaps the mapped pages in `pages`, which must lie inside a single
+/// reserved region. Pages that are not mapped are skipped.
+pub fn unmap_pages(pages: PageRange) -> Result<(), VmError> {
+    lock().unmap_pages(pages)
+}
+
+/// Commits the page containing `addr` if it belongs to a lazily committed
+/// region and the fault was caused by the page not being present.
+///
+/// Called by the page fault handler. Returns `false` if the fault could not
+/// be resolved and is a real error.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let mut vmm = loop {
+        if let Some(vmm) = try_lock() {
+            break vmm;
+        }
+        // the faulting code may hold the lock itself, then it never gets released
+        if OWNER.load(Ordering::Relaxed) == smp::current().index() {
+            return false;
+        }
+        // the holder may be waiting for this CPU in `smp::shoot_down_tlbs`
+        smp::flush_stale_tlb();
+        spin_loop();
+    };
+    let Some(entry) = vmm.regions.iter().find(|entry| entry.region.contains(addr)) else {
+        return false;
+    };
+    let Backing::Lazy(flags) = entry.backing else {
+        return false;
+    };
+    if error_code.contains(PageFaultErrorCode::USER_MODE)
+        && !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    {
+        return false;
+    }
+    let Some(mapper) = vmm.mapper.as_mut() else {
+        return false;
+    };
+    let Some(frame) = zeroed_frame() else {
+        return false;
+    };
+
+    let page = Page::containing_address(addr);
+    match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
+
+/// Returns the physical address `addr` is mapped to.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    lock().mapper.as_ref()?.translate_addr(addr)
+}
+
+/// Returns the flags of the page table entry mapping `addr`.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    match lock().mapper.as_ref()?.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+    }
+}
+
+/// Returns the reserved region containing `addr`.
+pub fn region_containing(addr: VirtAddr) -> Option<Region> {
+    let vmm = lock();
+    vmm.regions
+        .iter()
+        .map(|entry| entry.region)
+        .find(|region| region.contains(addr))
+}
+
+/// Returns all reserved regions, sorted by address.
+pub fn regions() -> ArrayVec<Region, MAX_REGIONS> {
+    lock().regions.iter().map(|entry| entry.region).collect()
+}
+
+/// The `VMM` lock, see `lock`.
+struct VmmGuard {
+    guard: ManuallyDrop<MutexGuard<'static, Vmm>>,
+    interrupts_were_enabled: bool,
+}
+
+/// Locks `VMM` with interrupts disabled until the guard is dropped.
+///
+/// So the holder is never preempted and releases the lock soon, which lets
+/// the page fault handler wait for it.
+fn lock() -> VmmGuard {
+    loop {
+        if let Some(vmm) = try_lock() {
+            return vmm;
+        }
+        // the holder may be waiting for this CPU in `smp::shoot_down_tlbs`
+        smp::flush_stale_tlb();
+        spin_loop();
+    }
+}
+
+fn try_lock() -> Option<VmmGuard> {
+    let interrupts_were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    let Some(guard) = VMM.try_lock() else {
+        // interrupts stay enabled while waiting
+        if interrupts_were_enabled {
+            interrupts::enable();
+        }
+        return None;
+    };
+    OWNER.store(smp::current().index(), Ordering::Relaxed);
+    Some(VmmGuard {
+        guard: ManuallyDrop::new(guard),
+        interrupts_were_enabled,
+    })
+}
+
+impl Deref for VmmGuard {
+    type Target = Vmm;
+
+    fn deref(&self) -> &Vmm {
+        &self.guard
+    }
+}
+
+impl DerefMut for VmmGuard {
+    fn deref_mut(&mut self) -> &mut Vmm {
+        &mut self.guard
+    }
+}
+
+impl Drop for VmmGuard {
+    fn drop(&mut self) {
+        OWNER.store(NO_OWNER, Ordering::Relaxed);
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Vmm {
+    /// Returns the start of the first gap in the kernel window that fits `pages`.
+    fn find_free(&self, pages: u64) -> Option<VirtAddr> {
+        let size = pages.checked_mul(PAGE_SIZE)?;
+        let mut start = KERNEL_SPACE_START;
+        for entry in &self.regions {
+            if entry.region.start.as_u64() - start >= size {
+                break;
+            }
+            start = entry.region.end().as_u64();
+        }
+        (KERNEL_SPACE_END - start >= size).then(|| VirtAddr::new(start))
+    }
+
+    /// Adds a region that is known not to overlap any other.
+    fn insert(&mut self, region: Region, backing: Backing) -> Result<Region, VmError> {
+        if self.regions.is_full() {
+            return Err(VmError::TooManyRegions);
+        }
+        let index = self
+            .regions
+            .iter()
+            .position(|entry| entry.region.start > region.start)
+            .unwrap_or(self.regions.len());
+        self.regions.insert(index, Entry { region, backing });
+        Ok(region)
+    }
+
+    fn entry_mut(&mut self, pages: PageRange) -> Result<&mut Entry, VmError> {
+        let start = pages.start.start_address();
+        let end = pages.end.start_address();
+        self.regions
+            .iter_mut()
+            .find(|entry| entry.region.start <= start && end <= entry.region.end())
+            .ok_or(VmError::NotReserved)
+    }
+
+    /// Maps every page in `pages` to the frame returned by `frame_for`.
+    ///
+    /// On failure, the pages mapped so far are unmapped again.
+    fn map_pages(
+        &mut self,
+        pages: PageRange,
+        flags: PageTableFlags,
+        backing: Backing,
+        mut frame_for: impl FnMut(Page) -> Option<PhysFrame>,
+    ) -> Result<(), VmError> {
+        let entry = self.entry_mut(pages)?;
+        assert!(
+            entry.backing == Backing::Unmapped || entry.backing == backing,
+            "region {} mixes different kinds of frames",
+            entry.region.name
+        );
+        entry.backing = backing;
+
+        let mapper = self.mapper.as_mut().ok_or(VmError::NotInitialized)?;
+        let flags = flags | PageTableFlags::PRESENT;
+        for page in pages {
+            let result = frame_for(page)
+                .ok_or(VmError::FrameAllocationFailed)
+                .and_then(|frame| {
+                    let result =
+                        unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) };
+                    result.map_err(|error| {
+                        if backing.owns_frames() {
+                            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                        }
+                        map_error(error, page)
+                    })
+                });
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    unmap(mapper, Page::range(pages.start, page), backing);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn unmap_pages(&mut self, pages: PageRange) -> Result<(), VmError> {
+        let backing = self.entry_mut(pages)?.backing;
+        // without a mapper, nothing can have been mapped yet
+        if let Some(mapper) = self.mapper.as_mut() {
+            unmap(mapper, pages, backing);
+        }
+        Ok(())
+    }
+}
+
+/// Unmaps the mapped pages in `pages`, freeing their frames if the region owns them.
+///
+/// Returns once no CPU has the pages in its TLB anymore, so the range can be
+/// reused right away.
+fn unmap(mapper: &mut OffsetPageTable<'static>, pages: PageRange, backing: Backing) {
+    let mut unmapped = false;
+    let mut frames = ArrayVec::new();
+    for page in pages {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                unmapped = true;
+                if backing.owns_frames() {
+                    frames.push(frame);
+                }
+                if frames.is_full() {
+                    shoot_down(&mut frames);
+                }
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(error) => panic!("failed to unmap {:?}: {:?}", page, error),
+        }
+    }
+    if unmapped {
+        shoot_down(&mut frames);
+    }
+}
+
+/// Flushes unmapped pages from the TLBs of all CPUs, then frees `frames`,
+/// which they were mapped to.
+fn shoot_down(frames: &mut ArrayVec<PhysFrame, UNMAP_BATCH>) {
+    GENERATION.fetch_add(1, Ordering::Release);
+    smp::shoot_down_tlbs();
+    for frame in frames.drain(..) {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+}
+
+/// Allocates a frame and fills it with zeros.
+pub(crate) fn zeroed_frame() -> Option<PhysFrame> {
+    let frame = GlobalFrameAllocator.allocate_frame()?;
+    let ptr: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { ptr.write_bytes(0, PAGE_SIZE as usize) };
+    Some(frame)
+}
+
+pub(crate) fn map_error(error: MapToError<Size4KiB>, page: Page) -> VmError {
+    match error {
+        MapToError::FrameAllocationFailed => VmError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => VmError::HugePage,
+        MapToError::PageAlreadyMapped(_) => VmError::AlreadyMapped { page },
+    }
+}
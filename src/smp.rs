@@ -0,0 +1,236 @@
+//! Starting the other CPUs.
+//!
+//! The bootstrap processor (BSP) finds the application processors (APs) in
+//! the ACPI MADT and starts them one at a time with the INIT-SIPI-SIPI
+//! sequence. Each AP starts in real mode in the `trampoline`, which enters
+//! long mode and calls `ap_main` on a stack the BSP allocated for it. There
+//! it loads its own GDT and TSS (see `gdt::CpuTables`), the shared IDT and
+//! its `PerCpu` data, and runs an `Executor` that steals tasks from the
+//! other CPUs' executors.
+//!
+//! Device interrupts, the PIT, the threads and user mode all stay on the
+//! BSP. The APs only run tasks, and halt while they have none. Whoever gives
+//! an idle CPU work, by waking one of its tasks or spawning a task it could
+//! steal, interrupts its `hlt` with a wakeup IPI, see `wake`.
+//!
+//! Before the frames or addresses of unmapped kernel pages are reused,
+//! `shoot_down_tlbs` has every other CPU flush its TLB, using the wakeup IPI.
+
+use crate::memory::stack;
+use crate::memory::vmm::{self, VmError};
+use crate::task::executor::Executor;
+use crate::{gdt, interrupts, memory, time};
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use trampoline::Trampoline;
+use x86_64::registers::control::Cr3;
+
+pub mod acpi;
+pub mod apic;
+mod percpu;
+mod trampoline;
+
+pub use percpu::{current, restore_gs_base, PerCpu};
+
+/// Maximum number of CPUs that are used, including the BSP.
+pub const MAX_CPUS: usize = 16;
+
+/// Size in pages of the stack an AP runs its executor on.
+const AP_STACK_PAGES: u64 = 32;
+
+/// Timer ticks to wait after the INIT IPI. Only 10 ms are needed, but the
+/// first tick may come right away.
+const INIT_DELAY_TICKS: u64 = 2;
+/// Timer ticks to wait for an AP to come online after each startup IPI.
+const STARTUP_TIMEOUT_TICKS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    /// There is no ACPI MADT listing the processors.
+    NoMadt,
+    /// The memory map has no page below 1 MiB for the trampoline.
+    NoLowMemory,
+    /// An AP did not come online after two startup IPIs.
+    Timeout {
+        apic_id: u8,
+    },
+    Vm(VmError),
+}
+
+impl From<VmError> for SmpError {
+    fn from(error: VmError) -> Self {
+        SmpError::Vm(error)
+    }
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmpError::NoMadt => write!(f, "no ACPI MADT found"),
+            SmpError::NoLowMemory => write!(f, "no memory below 1 MiB for the trampoline"),
+            SmpError::Timeout { apic_id } => {
+                write!(f, "CPU with local APIC {} did not start", apic_id)
+            }
+            SmpError::Vm(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Sets up the `PerCpu` data of the BSP, so that `current` works.
+///
+/// Called by `crate::init`.
+pub fn init_bsp() {
+    let cpu = percpu::get(0);
+    cpu.set_apic_id((__cpuid(1).ebx >> 24) as u8);
+    unsafe { percpu::install(cpu) };
+    cpu.set_online();
+}
+
+/// Starts the APs listed in the MADT and returns the number of CPUs online.
+///
+/// Must be called once, on the BSP, with interrupts enabled and after the
+/// heap is set up. If an AP does not start, the ones started before it keep
+/// running and the remaining ones are not started.
+pub fn init() -> Result<usize, SmpError> {
+    assert!(
+        x86_64::instructions::interrupts::are_enabled(),
+        "smp::init needs the timer interrupt"
+    );
+    let madt = acpi::find_madt().ok_or(SmpError::NoMadt)?;
+    apic::init(madt.local_apic)?;
+    let page = memory::low_frame().ok_or(SmpError::NoLowMemory)?;
+    let trampoline = unsafe { Trampoline::install(page) }?;
+
+    let bsp = current().apic_id();
+    for (index, &apic_id) in madt
+        .apic_ids
+        .iter()
+        .filter(|&&apic_id| apic_id != bsp)
+        .enumerate()
+    {
+        start_ap(&trampoline, index + 1, apic_id)?;
+    }
+    Ok(cpu_count())
+}
+
+/// Starts the AP with local APIC `apic_id` as CPU `index`.
+fn start_ap(trampoline: &Trampoline, index: usize, apic_id: u8) -> Result<(), SmpError> {
+    let cpu = percpu::get(index);
+    cpu.set_apic_id(apic_id);
+    let tables = gdt::CpuTables::allocate()?;
+    let stack = stack::allocate_stack("AP kernel", AP_STACK_PAGES)?;
+    let args = [cpu as *const PerCpu as u64, tables as *const _ as u64];
+    trampoline.prepare(stack.top, ap_main as *const () as usize, args);
+
+    apic::send_init(apic_id);
+    wait_for(INIT_DELAY_TICKS, || false);
+    // the second startup IPI is only needed if the first one got lost
+    for _ in 0..2 {
+        apic::send_startup(apic_id, trampoline.page_number());
+        if wait_for(STARTUP_TIMEOUT_TICKS, || cpu.is_online()) {
+            return Ok(());
+        }
+    }
+    // the stack and tables are leaked, the AP might still start
+    Err(SmpError::Timeout { apic_id })
+}
+
+/// Waits until `done` returns `true` or `ticks` timer ticks have passed.
+/// Returns the last result of `done`.
+fn wait_for(ticks: u64, done: impl Fn() -> bool) -> bool {
+    let deadline = time::ticks() + ticks;
+    while time::ticks() < deadline {
+        if done() {
+            return true;
+        }
+        spin_loop();
+    }
+    done()
+}
+
+/// Called by the trampoline on a freshly started AP.
+extern "C" fn ap_main(cpu: &'static PerCpu, tables: &'static gdt::CpuTables) -> ! {
+    memory::address_space::init_ap();
+    unsafe { percpu::install(cpu) };
+    tables.load();
+    interrupts::init_idt();
+    apic::init_ap();
+    memory::mmio::init();
+    cpu.set_online();
+    x86_64::instructions::interrupts::enable();
+
+    Executor::new().with_work_stealing().run()
+}
+
+/// Returns the number of CPUs online.
+pub fn cpu_count() -> usize {
+    percpu::online().count()
+}
+
+/// Returns the CPUs that are online, the BSP first.
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    percpu::online()
+}
+
+/// Makes CPU `index` look for new work if it is idle, by interrupting its
+/// `hlt` with a wakeup IPI.
+///
+/// Safe to call from interrupt handlers. Returns whether the CPU was idle;
+/// the current CPU never counts as idle.
+pub fn wake(index: usize) -> bool {
+    // pairs with the fence in `PerCpu::enter_idle`: either the CPU sees the
+    // new work before halting, or we see it idle
+    fence(Ordering::SeqCst);
+    let cpu = percpu::get(index);
+    if index == current().index() || !cpu.is_online() || !cpu.is_idle() {
+        return false;
+    }
+    apic::send_ipi(cpu.apic_id(), apic::WAKEUP_VECTOR);
+    true
+}
+
+/// Wakes one idle CPU other than the current one, so it can steal work.
+///
+/// Returns whether there was an idle CPU.
+pub fn wake_any() -> bool {
+    (0..MAX_CPUS).any(wake)
+}
+
+/// Makes every other CPU flush its TLB if kernel pages were unmapped since it
+/// last did, and waits until they all have.
+///
+/// Called by `vmm` after unmapping kernel pages and before their frames and
+/// addresses are reused. The caller may hold the `vmm` lock: CPUs waiting
+/// for it flush while they wait.
+pub fn shoot_down_tlbs() {
+    let generation = vmm::generation();
+    let stale = || {
+        percpu::online().filter(move |cpu| {
+            cpu.tlb_generation.load(Ordering::Acquire) < generation && !ptr::eq(*cpu, current())
+        })
+    };
+    for cpu in stale() {
+        apic::send_ipi(cpu.apic_id(), apic::WAKEUP_VECTOR);
+    }
+    while stale().next().is_some() {
+        spin_loop();
+    }
+}
+
+/// Flushes the current CPU's TLB if kernel pages were unmapped since it was
+/// last flushed, possibly on another CPU.
+pub fn flush_stale_tlb() {
+    let cpu = current();
+    let generation = vmm::generation();
+    if cpu.tlb_generation.load(Ordering::Acquire) < generation {
+        // reloading CR3 drops the entries of the current PCID, the others
+        // are flushed when they are switched to
+        let (frame, value) = Cr3::read_raw();
+        unsafe { Cr3::write_raw(frame, value) };
+        // an interrupt in between may have flushed for a later generation
+        cpu.tlb_generation.fetch_max(generation, Ordering::Release);
+    }
+}
@@ -0,0 +1,139 @@
+//! Just enough ACPI to find the processors in the MADT.
+//!
+//! The RSDP is searched for where BIOS firmware has to put it: the first KiB
+//! of the EBDA and the read-only BIOS area below 1 MiB. All tables are read
+//! through the physical memory mapping and checked against their checksum.
+
+use super::MAX_CPUS;
+use crate::memory;
+use arrayvec::ArrayVec;
+use core::ops::Range;
+use core::slice;
+use x86_64::PhysAddr;
+
+/// Where the BIOS data area stores the real-mode segment of the EBDA.
+const EBDA_SEGMENT: u64 = 0x40e;
+const BIOS_AREA: Range<u64> = 0xe_0000..0x10_0000;
+
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+/// Length of the RSDP of ACPI 1.0, which the checksum covers.
+const RSDP_LENGTH: usize = 20;
+const MADT_SIGNATURE: &[u8] = b"APIC";
+
+/// Length of the header every system description table starts with.
+const HEADER_LENGTH: usize = 36;
+
+/// MADT entry types.
+const LOCAL_APIC: u8 = 0;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Flag of a local APIC entry whose processor can be started.
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+
+/// The processors listed in the MADT.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    /// Physical address of the local APIC registers.
+    pub local_apic: PhysAddr,
+    /// Local APIC IDs of the processors that can be started, including the
+    /// bootstrap processor. Processors beyond `MAX_CPUS` are left out.
+    pub apic_ids: ArrayVec<u8, MAX_CPUS>,
+}
+
+/// Finds and parses the MADT.
+///
+/// Returns `None` if there is no valid RSDP or MADT.
+pub fn find_madt() -> Option<Madt> {
+    let rsdp = find_rsdp()?;
+    let revision: u8 = read(rsdp + 15u64);
+    let xsdt: u64 = read(rsdp + 24u64);
+    // the XSDT holds 64-bit pointers, the RSDT of ACPI 1.0 32-bit ones
+    let (root, entry_size) = if revision >= 2 && xsdt != 0 {
+        (PhysAddr::new(xsdt), 8)
+    } else {
+        (PhysAddr::new(read::<u32>(rsdp + 16u64).into()), 4)
+    };
+
+    let length = table_length(root)?;
+    let entries = (length - HEADER_LENGTH) / entry_size;
+    (0..entries)
+        .map(|index| {
+            let entry = root + (HEADER_LENGTH + index * entry_size) as u64;
+            match entry_size {
+                8 => PhysAddr::new(read(entry)),
+                _ => PhysAddr::new(read::<u32>(entry).into()),
+            }
+        })
+        .find(|&table| bytes(table, MADT_SIGNATURE.len()) == MADT_SIGNATURE)
+        .and_then(parse_madt)
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(read::<u16>(PhysAddr::new(EBDA_SEGMENT))) << 4;
+    let ebda = (ebda != 0).then_some(ebda..ebda + 1024);
+    ebda.into_iter()
+        .chain([BIOS_AREA])
+        // the RSDP is aligned to 16 bytes
+        .flat_map(|area| area.step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| {
+            bytes(addr, RSDP_SIGNATURE.len()) == RSDP_SIGNATURE && is_valid(addr, RSDP_LENGTH)
+        })
+}
+
+fn parse_madt(madt: PhysAddr) -> Option<Madt> {
+    let length = table_length(madt)?;
+    let mut local_apic = PhysAddr::new(read::<u32>(madt + HEADER_LENGTH as u64).into());
+    let mut apic_ids = ArrayVec::new();
+
+    // the entries follow the local APIC address and the flags
+    let mut offset = HEADER_LENGTH + 8;
+    while offset + 2 <= length {
+        let entry = madt + offset as u64;
+        let kind: u8 = read(entry);
+        let entry_length = usize::from(read::<u8>(entry + 1u64));
+        if entry_length < 2 {
+            break;
+        }
+        match kind {
+            LOCAL_APIC => {
+                let apic_id: u8 = read(entry + 3u64);
+                let flags: u32 = read(entry + 4u64);
+                if flags & PROCESSOR_ENABLED != 0 && !apic_ids.is_full() {
+                    apic_ids.push(apic_id);
+                }
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => local_apic = PhysAddr::new(read(entry + 4u64)),
+            _ => {}
+        }
+        offset += entry_length;
+    }
+    Some(Madt {
+        local_apic,
+        apic_ids,
+    })
+}
+
+/// Returns the length of the table at `table` if its checksum is valid.
+fn table_length(table: PhysAddr) -> Option<usize> {
+    let length = read::<u32>(table + 4u64) as usize;
+    (length >= HEADER_LENGTH && is_valid(table, length)).then_some(length)
+}
+
+/// Returns whether the `length` bytes at `addr` add up to zero, as every ACPI
+/// structure's do.
+fn is_valid(addr: PhysAddr, length: usize) -> bool {
+    let sum = bytes(addr, length)
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    sum == 0
+}
+
+fn read<T: Copy>(addr: PhysAddr) -> T {
+    let ptr: *const T = memory::phys_to_virt(addr).as_ptr();
+    unsafe { ptr.read_unaligned() }
+}
+
+fn bytes(addr: PhysAddr, length: usize) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(memory::phys_to_virt(addr).as_ptr(), length) }
+}
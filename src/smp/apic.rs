@@ -0,0 +1,101 @@
+//! The local APICs, in xAPIC mode.
+//!
+//! Every CPU sees its own local APIC at the same physical address, so one
+//! mapping serves all of them. They are only used for inter-processor
+//! interrupts: device interrupts still come from the PIC, which stays wired
+//! to the bootstrap processor's LINT0 pin.
+
+use crate::memory::mmio::{ioremap, CacheMode, Mmio};
+use crate::memory::vmm::VmError;
+use conquer_once::spin::OnceCell;
+use core::hint::spin_loop;
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+
+/// Vector of the IPI that wakes an idle CPU, see `smp::wake`.
+pub const WAKEUP_VECTOR: u8 = 0xf0;
+/// Vector the local APIC delivers spurious interrupts to.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Register offsets.
+const EOI: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+const REGISTERS_LENGTH: usize = 0x400;
+
+/// Bit of the spurious interrupt register that enables the local APIC.
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// Fields of the LVT and interrupt command registers.
+const MASKED: u32 = 1 << 16;
+const DELIVERY_FIXED: u32 = 0b000 << 8;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_EXTINT: u32 = 0b111 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+static LOCAL_APIC: OnceCell<Mmio> = OnceCell::uninit();
+
+/// Maps the local APIC registers at `phys` and enables the bootstrap
+/// processor's, which keeps receiving the PIC's interrupts through LINT0.
+pub(super) fn init(phys: PhysAddr) -> Result<(), VmError> {
+    let registers = unsafe { ioremap(phys, REGISTERS_LENGTH, CacheMode::Uncached) }?;
+    LOCAL_APIC.init_once(|| registers);
+    enable(DELIVERY_EXTINT);
+    Ok(())
+}
+
+/// Enables the local APIC of an application processor.
+pub(super) fn init_ap() {
+    enable(MASKED);
+}
+
+fn enable(lint0: u32) {
+    let apic = registers();
+    // the LVT entries cannot be unmasked while the APIC is disabled
+    apic.write::<u32>(SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+    apic.write::<u32>(LVT_LINT0, lint0);
+    apic.write::<u32>(LVT_LINT1, DELIVERY_NMI);
+}
+
+/// Acknowledges the interrupt being handled, for interrupts that came from
+/// the local APIC.
+pub fn end_of_interrupt() {
+    registers().write::<u32>(EOI, 0);
+}
+
+/// Sends the interrupt `vector` to the CPU whose local APIC has `apic_id`.
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send(apic_id, DELIVERY_FIXED | LEVEL_ASSERT | u32::from(vector));
+}
+
+pub(super) fn send_init(apic_id: u8) {
+    send(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI, which starts the CPU in real mode at the start of
+/// physical page `page`.
+pub(super) fn send_startup(apic_id: u8, page: u8) {
+    send(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | u32::from(page));
+}
+
+fn send(apic_id: u8, command: u32) {
+    let apic = registers();
+    // an IPI sent by an interrupt handler in between would change the destination
+    interrupts::without_interrupts(|| {
+        apic.write::<u32>(ICR_HIGH, u32::from(apic_id) << 24);
+        apic.write::<u32>(ICR_LOW, command);
+        while apic.read::<u32>(ICR_LOW) & DELIVERY_PENDING != 0 {
+            spin_loop();
+        }
+    });
+}
+
+fn registers() -> &'static Mmio {
+    LOCAL_APIC.try_get().expect("local APIC not initialized")
+}
@@ -0,0 +1,148 @@
+//! Data private to each CPU, reached through the GS base.
+//!
+//! The GS base of every CPU points to its `PerCpu`, whose first field holds
+//! its own address, so `current` is a single load relative to GS. The kernel
+//! never loads the GS register, but user programs can, which changes the GS
+//! base. So everything entered from ring 3 calls `restore_gs_base` first.
+
+use super::MAX_CPUS;
+use crate::memory::vmm;
+use crate::task::{self, executor::Spawner};
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+/// The data of one CPU.
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this struct, read by `current`. Must stay the first field.
+    this: AtomicPtr<PerCpu>,
+    index: usize,
+    apic_id: AtomicU8,
+    online: AtomicBool,
+    /// Set while the CPU halts for lack of work, see `enter_idle`.
+    idle: AtomicBool,
+    /// `vmm::generation` when the TLB was last flushed, see `smp::flush_stale_tlb`.
+    pub(super) tlb_generation: AtomicU64,
+    /// Id of the task being polled on this CPU, see `task::current_task`.
+    pub(crate) current_task: AtomicU64,
+    /// Spawner of the executor that ran tasks on this CPU last, see `task::spawn`.
+    pub(crate) spawner: Mutex<Option<Spawner>>,
+}
+
+static CPUS: [PerCpu; MAX_CPUS] = {
+    let mut cpus = [const { PerCpu::new() }; MAX_CPUS];
+    let mut index = 0;
+    while index < MAX_CPUS {
+        cpus[index].index = index;
+        index += 1;
+    }
+    cpus
+};
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            this: AtomicPtr::new(ptr::null_mut()),
+            index: 0,
+            apic_id: AtomicU8::new(0),
+            online: AtomicBool::new(false),
+            idle: AtomicBool::new(false),
+            tlb_generation: AtomicU64::new(0),
+            current_task: AtomicU64::new(task::NO_TASK),
+            spawner: Mutex::new(None),
+        }
+    }
+
+    /// Number of the CPU, 0 for the bootstrap processor and counting up in
+    /// the order the others were started.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// ID of the CPU's local APIC.
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_apic_id(&self, apic_id: u8) {
+        self.apic_id.store(apic_id, Ordering::Relaxed);
+    }
+
+    pub(super) fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+
+    /// Marks the CPU as about to halt.
+    ///
+    /// Whoever makes work available after this sees the flag and sends a
+    /// wakeup IPI, so the caller has to check for work once more before
+    /// halting, with interrupts disabled.
+    pub fn enter_idle(&self) {
+        self.idle.store(true, Ordering::Relaxed);
+        // pairs with the fence in `smp::wake`
+        fence(Ordering::SeqCst);
+    }
+
+    pub fn leave_idle(&self) {
+        self.idle.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Returns the data of the current CPU.
+///
+/// Must not be called before `smp::init_bsp`.
+pub fn current() -> &'static PerCpu {
+    let cpu: *const PerCpu;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) cpu,
+            options(nostack, preserves_flags, readonly, pure)
+        );
+        &*cpu
+    }
+}
+
+/// Points the GS base back at the current CPU's `PerCpu`, after user mode may
+/// have changed it.
+pub fn restore_gs_base() {
+    // `install` keeps a copy in the kernel GS base, which `swapgs` would
+    // exchange with the GS base but is not used otherwise
+    GsBase::write(KernelGsBase::read());
+}
+
+/// Returns the data of CPU `index`, whether it is online or not.
+pub(super) fn get(index: usize) -> &'static PerCpu {
+    &CPUS[index]
+}
+
+/// Returns the CPUs that are online.
+pub(super) fn online() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.iter().filter(|cpu| cpu.is_online())
+}
+
+/// Points the GS base of the current CPU at `cpu`.
+///
+/// ## Safety
+///
+/// Must be called once on every CPU, each with a `PerCpu` of its own.
+pub(super) unsafe fn install(cpu: &'static PerCpu) {
+    let this = ptr::from_ref(cpu).cast_mut();
+    cpu.this.store(this, Ordering::Relaxed);
+    cpu.tlb_generation
+        .store(vmm::generation(), Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(this));
+    KernelGsBase::write(VirtAddr::from_ptr(this));
+}
@@ -0,0 +1,222 @@
+//! The code application processors start in.
+//!
+//! A startup IPI starts a CPU in real mode at the start of a page below
+//! 1 MiB. `Trampoline::install` copies the code below to such a page. It
+//! switches straight from real mode to long mode, enabling paging with a
+//! copy of the kernel's page table that also identity maps the trampoline
+//! page, and calls the entry point set with `Trampoline::prepare` on the
+//! stack set there. The entry point has to switch to the kernel's page table.
+//!
+//! The code runs at whatever address it was copied to, so it computes the
+//! addresses the CPU needs absolute from CS in real mode and uses
+//! RIP-relative addressing in long mode.
+
+use crate::memory::vmm::{self, VmError};
+use crate::memory::{self, address_space, GlobalFrameAllocator};
+use arrayvec::ArrayVec;
+use core::arch::global_asm;
+use core::ptr::{self, addr_of};
+use x86_64::structures::paging::{
+    FrameDeallocator, PageTable, PageTableFlags, PageTableIndex, PhysFrame,
+};
+use x86_64::VirtAddr;
+
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".code16",
+    ".global ap_trampoline_start",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    // the physical address the code was copied to
+    "movzx ebx, ax",
+    "shl ebx, 4",
+    "lea eax, [ebx + GDT_OFFSET]",
+    "mov dword ptr [GDTR_OFFSET + 2], eax",
+    "lea eax, [ebx + LONG_MODE_OFFSET]",
+    "mov dword ptr [FAR_JUMP_OFFSET], eax",
+    "lgdt [GDTR_OFFSET]",
+    // PAE
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, dword ptr [DATA_OFFSET]",
+    "mov cr3, eax",
+    // long mode and no-execute in the EFER
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    // paging, write protection and protected mode at once
+    "mov eax, cr0",
+    "or eax, (1 << 31) | (1 << 16) | 1",
+    "mov cr0, eax",
+    "jmp fword ptr ds:[FAR_JUMP_OFFSET]",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "xor eax, eax",
+    "mov ds, eax",
+    "mov es, eax",
+    "mov ss, eax",
+    "mov rsp, qword ptr [rip + ap_trampoline_data + 8]",
+    "mov rax, qword ptr [rip + ap_trampoline_data + 16]",
+    "mov rdi, qword ptr [rip + ap_trampoline_data + 24]",
+    "mov rsi, qword ptr [rip + ap_trampoline_data + 32]",
+    "xor ebp, ebp",
+    "call rax",
+    "ud2",
+    ".p2align 3",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    // 64-bit kernel code segment, at the same selector as in the kernel's GDT
+    ".quad 0x00209a0000000000",
+    "ap_trampoline_gdtr:",
+    ".short ap_trampoline_gdtr - ap_trampoline_gdt - 1",
+    ".long 0",
+    "ap_trampoline_far_jump:",
+    ".long 0",
+    ".short 8",
+    // `Data`
+    ".p2align 3",
+    ".global ap_trampoline_data",
+    "ap_trampoline_data:",
+    ".quad 0, 0, 0, 0, 0",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+    ".set GDT_OFFSET, ap_trampoline_gdt - ap_trampoline_start",
+    ".set GDTR_OFFSET, ap_trampoline_gdtr - ap_trampoline_start",
+    ".set LONG_MODE_OFFSET, ap_trampoline_long_mode - ap_trampoline_start",
+    ".set FAR_JUMP_OFFSET, ap_trampoline_far_jump - ap_trampoline_start",
+    ".set DATA_OFFSET, ap_trampoline_data - ap_trampoline_start",
+    ".popsection",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// What the trampoline code reads at `ap_trampoline_data`.
+#[repr(C)]
+struct Data {
+    /// Level 4 table to enable paging with, must be below 4 GiB.
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    /// Passed to `entry` in the first two argument registers.
+    args: [u64; 2],
+}
+
+/// The trampoline code installed in a page below 1 MiB, with the page table
+/// it uses.
+pub(super) struct Trampoline {
+    page: PhysFrame,
+    /// The tables of the page table copy, level 4 first.
+    tables: ArrayVec<PhysFrame, 4>,
+}
+
+impl Trampoline {
+    /// Copies the trampoline code to `page` and builds its page table.
+    ///
+    /// ## Safety
+    ///
+    /// `page` must be below 1 MiB and must not be used for anything else
+    /// while the trampoline exists.
+    pub(super) unsafe fn install(page: PhysFrame) -> Result<Trampoline, VmError> {
+        assert!(
+            page.start_address().as_u64() < 0x10_0000,
+            "trampoline page not below 1 MiB"
+        );
+        let start = addr_of!(ap_trampoline_start);
+        let length = addr_of!(ap_trampoline_end) as usize - start as usize;
+        let code: *mut u8 = memory::phys_to_virt(page.start_address()).as_mut_ptr();
+        ptr::copy_nonoverlapping(start, code, length);
+
+        let mut trampoline = Trampoline {
+            page,
+            tables: ArrayVec::new(),
+        };
+        trampoline.build_page_table()?;
+        Ok(trampoline)
+    }
+
+    /// Page number to send in the startup IPI.
+    pub(super) fn page_number(&self) -> u8 {
+        (self.page.start_address().as_u64() >> 12) as u8
+    }
+
+    /// Sets the stack and entry point for the next CPU to start.
+    ///
+    /// `entry` must be an `extern "C"` function that never returns, taking
+    /// `args` as its two arguments.
+    pub(super) fn prepare(&self, stack_top: VirtAddr, entry: usize, args: [u64; 2]) {
+        let data = Data {
+            cr3: self.tables[0].start_address().as_u64(),
+            stack_top: stack_top.as_u64(),
+            entry: entry as u64,
+            args,
+        };
+        let offset = addr_of!(ap_trampoline_data) as u64 - addr_of!(ap_trampoline_start) as u64;
+        let ptr: *mut Data =
+            (memory::phys_to_virt(self.page.start_address()) + offset).as_mut_ptr();
+        unsafe { ptr.write_volatile(data) };
+    }
+
+    /// Copies the kernel's page table down to the level 1 table covering the
+    /// trampoline page, and maps the page to itself in the copy.
+    ///
+    /// Kernel tables that are missing on the way (or huge pages) are replaced
+    /// by empty ones, the trampoline page is the only one the CPU uses in
+    /// that range before switching to the kernel's page table.
+    fn build_page_table(&mut self) -> Result<(), VmError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let addr = VirtAddr::new(self.page.start_address().as_u64());
+        let indices = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+
+        let mut kernel_table = Some(address_space::kernel_p4());
+        let mut parent: Option<(*mut PageTable, PageTableIndex)> = None;
+        for index in indices {
+            let frame = vmm::zeroed_frame().ok_or(VmError::FrameAllocationFailed)?;
+            self.tables.push(frame);
+            let table = table_ptr(frame);
+            unsafe {
+                if let Some(kernel_table) = kernel_table {
+                    table.write((*table_ptr(kernel_table)).clone());
+                }
+                if let Some((parent, parent_index)) = parent {
+                    (&mut *parent)[parent_index].set_frame(frame, flags);
+                }
+                kernel_table = (&*table)[index].frame().ok();
+            }
+            parent = Some((table, index));
+        }
+
+        let (level_1, index) = parent.expect("no level 1 table");
+        unsafe { (&mut *level_1)[index].set_frame(self.page, flags) };
+        assert!(
+            self.tables[0].start_address().as_u64() < 1 << 32,
+            "trampoline level 4 table above 4 GiB"
+        );
+        Ok(())
+    }
+}
+
+impl Drop for Trampoline {
+    fn drop(&mut self) {
+        for &frame in &self.tables {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
@@ -0,0 +1,214 @@
+//! System calls from user mode, using `syscall`/`sysret`.
+//!
+//! The call number goes in `rax` and up to three arguments in `rdi`, `rsi`
+//! and `rdx`. The result is returned in `rax`, with errors encoded as by
+//! `SyscallError::encode`. The instructions themselves clobber `rcx` and
+//! `r11`; all other registers are preserved.
+//!
+//! The entry stub switches to the same kernel stack the CPU uses for
+//! interrupts from user mode, `privilege_stack_table[0]` of the TSS, and
+//! keeps interrupts disabled until a call enables them itself.
+
+pub mod user;
+
+use crate::memory::address_space;
+use crate::time;
+use crate::usermode::{self, Exit};
+use crate::{gdt, print, smp, thread};
+use core::arch::naked_asm;
+use core::fmt;
+use core::time::Duration;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// The system calls, by number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Syscall {
+    /// `write(ptr, len)`: prints `len` bytes of UTF-8 at `ptr` to the
+    /// console and returns `len`.
+    Write = 0,
+    /// `read_key()`: returns the next typed character, or
+    /// `SyscallError::WouldBlock` if there is none.
+    ReadKey = 1,
+    /// `sleep(ms)`: waits for at least `ms` milliseconds, at most
+    /// `MAX_SLEEP_MS`, while other threads run.
+    Sleep = 2,
+    /// `exit(code)`: ends the program. Does not return.
+    Exit = 3,
+    /// `uptime()`: returns the milliseconds since boot.
+    Uptime = 4,
+}
+
+/// Errors returned by system calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// There is no system call with the given number.
+    UnknownCall = 1,
+    /// A pointer argument is not accessible from user mode.
+    BadAddress = 2,
+    /// An argument is out of range or malformed.
+    InvalidArgument = 3,
+    /// The call would have to wait for input.
+    WouldBlock = 4,
+}
+
+impl SyscallError {
+    const ALL: [SyscallError; 4] = [
+        SyscallError::UnknownCall,
+        SyscallError::BadAddress,
+        SyscallError::InvalidArgument,
+        SyscallError::WouldBlock,
+    ];
+
+    /// Encodes the error as the negated error number, which no successful
+    /// call returns.
+    pub fn encode(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+
+    /// Decodes a value returned in `rax`.
+    pub fn decode(value: u64) -> Result<u64, SyscallError> {
+        match SyscallError::ALL
+            .into_iter()
+            .find(|error| error.encode() == value)
+        {
+            Some(error) => Err(error),
+            None => Ok(value),
+        }
+    }
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyscallError::UnknownCall => write!(f, "unknown system call"),
+            SyscallError::BadAddress => write!(f, "bad address"),
+            SyscallError::InvalidArgument => write!(f, "invalid argument"),
+            SyscallError::WouldBlock => write!(f, "operation would block"),
+        }
+    }
+}
+
+/// Longest sleep a program can ask for, in milliseconds. Longer ones fail
+/// with `SyscallError::InvalidArgument`.
+pub const MAX_SLEEP_MS: u64 = 60_000;
+
+type Handler = fn(u64, u64, u64) -> Result<u64, SyscallError>;
+
+/// Handlers, indexed by `Syscall` number.
+static TABLE: [Handler; 5] = [sys_write, sys_read_key, sys_sleep, sys_exit, sys_uptime];
+
+/// User stack pointer, saved while the entry stub switches stacks.
+static mut USER_RSP: u64 = 0;
+
+/// Enables `syscall`/`sysret` and points them at the entry stub.
+///
+/// Must be called after `gdt::init`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT segment order does not match syscall/sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Entered by `syscall`, with the user's return address in `rcx`, its flags
+/// in `r11` and its stack still loaded.
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() {
+    naked_asm!(
+        "mov [rip + {user_rsp}], rsp",
+        "mov rsp, [rip + {tss} + {rsp0}]",
+        "push qword ptr [rip + {user_rsp}]",
+        "push rcx",
+        "push r11",
+        // caller-saved registers the handler may clobber
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push r8",
+        "push r9",
+        "push r10",
+        // align the stack to 16 bytes for the call
+        "sub rsp, 8",
+        "mov rcx, rdx",
+        "mov rdx, rsi",
+        "mov rsi, rdi",
+        "mov rdi, rax",
+        "call {dispatch}",
+        "add rsp, 8",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop r11",
+        "pop rcx",
+        "pop rsp",
+        "sysretq",
+        user_rsp = sym USER_RSP,
+        tss = sym gdt::TSS,
+        rsp0 = const gdt::PRIVILEGE_STACK_OFFSET,
+        dispatch = sym dispatch,
+    )
+}
+
+extern "C" fn dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    smp::restore_gs_base();
+    let result = match TABLE.get(number as usize) {
+        Some(handler) => handler(arg0, arg1, arg2),
+        None => Err(SyscallError::UnknownCall),
+    };
+    match result {
+        Ok(value) => value,
+        Err(error) => error.encode(),
+    }
+}
+
+fn sys_write(ptr: u64, len: u64, _: u64) -> Result<u64, SyscallError> {
+    let start = VirtAddr::try_new(ptr).map_err(|_| SyscallError::BadAddress)?;
+    if !address_space::is_user_accessible(start, len, false) {
+        return Err(SyscallError::BadAddress);
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), len as usize) };
+    let text = core::str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    print!("{}", text);
+    Ok(len)
+}
+
+fn sys_read_key(_: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    crate::task::keyboard::try_read_key()
+        .map(u64::from)
+        .ok_or(SyscallError::WouldBlock)
+}
+
+fn sys_sleep(ms: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    if ms > MAX_SLEEP_MS {
+        return Err(SyscallError::InvalidArgument);
+    }
+    thread::sleep(Duration::from_millis(ms));
+    Ok(0)
+}
+
+fn sys_exit(code: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    usermode::exit(Exit::Exited(code))
+}
+
+fn sys_uptime(_: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    Ok(time::uptime().as_millis() as u64)
+}
@@ -0,0 +1,60 @@
+//! System call wrappers for programs running in user mode.
+//!
+//! Calling these from the kernel returns to ring 3, so they only work in
+//! code that already runs there.
+
+use super::{Syscall, SyscallError, MAX_SLEEP_MS};
+use core::arch::asm;
+use core::time::Duration;
+
+/// Makes the system call `call` and decodes its result.
+fn syscall(call: Syscall, arg0: u64, arg1: u64, arg2: u64) -> Result<u64, SyscallError> {
+    let result: u64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") call as u64 => result,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            in("rdx") arg2,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    SyscallError::decode(result)
+}
+
+/// Prints `text` to the console.
+pub fn write(text: &str) -> Result<(), SyscallError> {
+    syscall(Syscall::Write, text.as_ptr() as u64, text.len() as u64, 0)?;
+    Ok(())
+}
+
+/// Returns the next typed character, if any, without waiting.
+pub fn read_key() -> Option<char> {
+    let key = syscall(Syscall::ReadKey, 0, 0, 0).ok()?;
+    char::from_u32(key as u32)
+}
+
+/// Waits for at least `duration`, in steps of at most `MAX_SLEEP_MS`.
+pub fn sleep(duration: Duration) {
+    let mut ms = duration.as_millis() as u64;
+    while ms > 0 {
+        let step = ms.min(MAX_SLEEP_MS);
+        syscall(Syscall::Sleep, step, 0, 0).expect("sleep failed");
+        ms -= step;
+    }
+}
+
+/// Ends the program with exit code `code`.
+pub fn exit(code: u64) -> ! {
+    let _ = syscall(Syscall::Exit, code, 0, 0);
+    unreachable!("exit returned");
+}
+
+/// Returns the time since boot, in whole milliseconds.
+pub fn uptime() -> Duration {
+    let ms = syscall(Syscall::Uptime, 0, 0, 0).expect("uptime failed");
+    Duration::from_millis(ms)
+}
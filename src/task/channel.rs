@@ -0,0 +1,214 @@
+//! Channels for passing values between tasks, and from interrupt handlers
+//! to tasks.
+//!
+//! - `mpsc`: many senders, one receiver, bounded or unbounded.
+//! - `oneshot`: a single value from one sender to one receiver.
+//! - `broadcast`: every value to every receiver.
+
+use core::fmt;
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+
+/// The value could not be sent because there are no receivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("channel closed")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at its capacity.
+    Full(T),
+    /// There are no receivers.
+    Closed(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("channel full"),
+            TrySendError::Closed(_) => f.write_str("channel closed"),
+        }
+    }
+}
+
+/// Errors of `mpsc` and `oneshot` receivers, see `broadcast::RecvError` for
+/// broadcast receivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// All senders were dropped and no values are left.
+    Closed,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvError::Closed => f.write_str("channel closed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No value is waiting.
+    Empty,
+    /// All senders were dropped and no values are left.
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("channel empty"),
+            TryRecvError::Closed => f.write_str("channel closed"),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{executor::Executor, yield_now};
+#[cfg(test)]
+use alloc::vec::Vec;
+
+#[test_case]
+fn mpsc_bounded_waits_for_space() {
+    let (sender, mut receiver) = mpsc::channel(4);
+    let mut executor = Executor::new();
+    executor.spawn(async move {
+        for n in 0..50 {
+            sender.send(n).await.unwrap();
+        }
+    });
+    let received = executor.block_on(async move {
+        let mut received = Vec::new();
+        while let Some(n) = receiver.recv().await {
+            received.push(n);
+            yield_now().await;
+        }
+        received
+    });
+    assert_eq!(received, (0..50).collect::<Vec<_>>());
+}
+
+#[test_case]
+fn mpsc_try_send() {
+    let (sender, mut receiver) = mpsc::channel(2);
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Ok(()));
+    assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(sender.try_send(3), Ok(()));
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.try_send(4), Err(TrySendError::Closed(4)));
+}
+
+#[test_case]
+fn mpsc_closes_when_senders_are_dropped() {
+    let (sender, mut receiver) = mpsc::unbounded();
+    let senders: Vec<_> = (0..3).map(|_| sender.clone()).collect();
+    drop(sender);
+    let mut executor = Executor::new();
+    for (n, sender) in senders.into_iter().enumerate() {
+        executor.spawn(async move {
+            for _ in 0..100 {
+                sender.send(n).await.unwrap();
+            }
+        });
+    }
+    let count = executor.block_on(async move {
+        let mut count = 0;
+        while receiver.recv().await.is_some() {
+            count += 1;
+        }
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+        count
+    });
+    assert_eq!(count, 300);
+}
+
+#[test_case]
+fn mpsc_blocked_sender_fails_when_receiver_is_dropped() {
+    let (sender, receiver) = mpsc::channel(1);
+    sender.try_send(0).unwrap();
+    let mut executor = Executor::new();
+    let blocked = executor.spawn(async move { sender.send(1).await });
+    executor.block_on(yield_now());
+    assert!(!blocked.is_finished());
+    drop(receiver);
+    assert_eq!(executor.block_on(blocked), Ok(Err(SendError(1))));
+}
+
+#[test_case]
+fn oneshot_delivers_value() {
+    let (sender, receiver) = oneshot::channel();
+    let mut executor = Executor::new();
+    executor.spawn(async move {
+        yield_now().await;
+        sender.send("value").unwrap();
+    });
+    assert_eq!(executor.block_on(receiver), Ok("value"));
+
+    let (sender, receiver) = oneshot::channel::<()>();
+    drop(sender);
+    assert_eq!(executor.block_on(receiver), Err(RecvError::Closed));
+
+    let (sender, receiver) = oneshot::channel();
+    drop(receiver);
+    assert_eq!(sender.send(5), Err(5));
+}
+
+#[test_case]
+fn broadcast_reaches_every_receiver() {
+    let (sender, mut first) = broadcast::channel(8);
+    let mut second = sender.subscribe();
+    let mut executor = Executor::new();
+    let handles: Vec<_> = [first.clone(), second.clone()]
+        .into_iter()
+        .map(|mut receiver| {
+            executor.spawn(async move {
+                let mut sum = 0;
+                while let Ok(n) = receiver.recv().await {
+                    sum += n;
+                }
+                sum
+            })
+        })
+        .collect();
+    executor.block_on(yield_now());
+    assert_eq!(sender.send(1), Ok(4));
+    assert_eq!(sender.send(2), Ok(4));
+    assert_eq!(first.try_recv(), Ok(1));
+    assert_eq!(second.try_recv(), Ok(1));
+    drop(sender);
+    let sums = executor.block_on(async move {
+        let mut sums = Vec::new();
+        for handle in handles {
+            sums.push(handle.await.unwrap());
+        }
+        sums
+    });
+    assert_eq!(sums, [3, 3]);
+    assert_eq!(first.try_recv(), Ok(2));
+    assert_eq!(first.try_recv(), Err(broadcast::TryRecvError::Closed));
+}
+
+#[test_case]
+fn broadcast_reports_lag() {
+    let (sender, mut receiver) = broadcast::channel(2);
+    for n in 0..5 {
+        sender.send(n).unwrap();
+    }
+    assert_eq!(receiver.try_recv(), Err(broadcast::TryRecvError::Lagged(3)));
+    assert_eq!(receiver.try_recv(), Ok(3));
+    assert_eq!(receiver.try_recv(), Ok(4));
+    assert_eq!(receiver.try_recv(), Err(broadcast::TryRecvError::Empty));
+    drop(receiver);
+    assert_eq!(sender.send(5), Err(SendError(5)));
+}
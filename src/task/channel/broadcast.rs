@@ -0,0 +1,222 @@
+//! Channels that deliver every value to every receiver.
+
+use super::SendError;
+use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc};
+use core::fmt;
+use core::future::poll_fn;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// All senders were dropped and no values are left.
+    Closed,
+    /// The receiver fell behind and missed this many values.
+    Lagged(u64),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvError::Closed => f.write_str("channel closed"),
+            RecvError::Lagged(missed) => write!(f, "receiver lagged by {} values", missed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No value is waiting.
+    Empty,
+    /// All senders were dropped and no values are left.
+    Closed,
+    /// The receiver fell behind and missed this many values.
+    Lagged(u64),
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("channel empty"),
+            TryRecvError::Closed => f.write_str("channel closed"),
+            TryRecvError::Lagged(missed) => write!(f, "receiver lagged by {} values", missed),
+        }
+    }
+}
+
+struct State<T> {
+    /// The last `capacity` values sent.
+    buffer: VecDeque<T>,
+    capacity: usize,
+    /// Sequence number of the oldest value in `buffer`.
+    first: u64,
+    senders: usize,
+    receivers: usize,
+    /// Wakers of receivers waiting for the next value, by receiver id.
+    wakers: BTreeMap<u64, Waker>,
+    next_receiver: u64,
+}
+
+impl<T> State<T> {
+    /// Sequence number the next value is sent with.
+    fn end(&self) -> u64 {
+        self.first + self.buffer.len() as u64
+    }
+
+    fn new_receiver(&mut self, shared: &Arc<Mutex<State<T>>>) -> Receiver<T> {
+        let next = self.end();
+        self.receiver_at(shared, next)
+    }
+
+    fn receiver_at(&mut self, shared: &Arc<Mutex<State<T>>>, next: u64) -> Receiver<T> {
+        let id = self.next_receiver;
+        self.next_receiver += 1;
+        self.receivers += 1;
+        Receiver {
+            shared: shared.clone(),
+            id,
+            next,
+        }
+    }
+}
+
+/// Creates a channel that keeps the last `capacity` values for receivers
+/// that fall behind.
+///
+/// Receivers that miss values get `RecvError::Lagged` and continue with the
+/// oldest value still kept.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel needs a capacity");
+    let shared = Arc::new(Mutex::new(State {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        first: 0,
+        senders: 1,
+        receivers: 0,
+        wakers: BTreeMap::new(),
+        next_receiver: 0,
+    }));
+    let receiver = shared.lock().new_receiver(&shared);
+    (Sender { shared }, receiver)
+}
+
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends `value` to all current receivers and returns how many there
+    /// are, or gives the value back if there are none.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let (receivers, wakers) = {
+            let mut state = self.shared.lock();
+            if state.receivers == 0 {
+                return Err(SendError(value));
+            }
+            if state.buffer.len() == state.capacity {
+                state.buffer.pop_front();
+                state.first += 1;
+            }
+            state.buffer.push_back(value);
+            (state.receivers, core::mem::take(&mut state.wakers))
+        };
+        for waker in wakers.into_values() {
+            waker.wake();
+        }
+        Ok(receivers)
+    }
+
+    /// Creates a receiver for the values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        self.shared.lock().new_receiver(&self.shared)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut state = self.shared.lock();
+            state.senders -= 1;
+            if state.senders > 0 {
+                return;
+            }
+            core::mem::take(&mut state.wakers)
+        };
+        for waker in wakers.into_values() {
+            waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+    id: u64,
+    /// Sequence number of the next value to receive.
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Waits for the next value.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|context| self.poll_recv(context)).await
+    }
+
+    pub fn poll_recv(&mut self, context: &mut Context) -> Poll<Result<T, RecvError>> {
+        let mut state = self.shared.lock();
+        match take(&mut self.next, &state) {
+            Err(TryRecvError::Empty) => {
+                state.wakers.insert(self.id, context.waker().clone());
+                Poll::Pending
+            }
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+            Err(TryRecvError::Lagged(missed)) => Poll::Ready(Err(RecvError::Lagged(missed))),
+            Ok(value) => Poll::Ready(Ok(value)),
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.shared.lock();
+        take(&mut self.next, &state)
+    }
+}
+
+/// Clones the value with sequence number `next` and advances `next`.
+fn take<T: Clone>(next: &mut u64, state: &State<T>) -> Result<T, TryRecvError> {
+    if *next < state.first {
+        let missed = state.first - *next;
+        *next = state.first;
+        return Err(TryRecvError::Lagged(missed));
+    }
+    match state.buffer.get((*next - state.first) as usize) {
+        Some(value) => {
+            *next += 1;
+            Ok(value.clone())
+        }
+        None if state.senders == 0 => Err(TryRecvError::Closed),
+        None => Err(TryRecvError::Empty),
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    /// Creates a receiver at the same position in the channel.
+    fn clone(&self) -> Self {
+        self.shared.lock().receiver_at(&self.shared, self.next)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receivers -= 1;
+        state.wakers.remove(&self.id);
+    }
+}
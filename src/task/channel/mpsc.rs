@@ -0,0 +1,189 @@
+//! Multi-producer, single-consumer channels.
+
+use super::{SendError, TryRecvError, TrySendError};
+use crate::task::sync::Notify;
+use alloc::sync::Arc;
+use core::future::poll_fn;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use futures_util::task::AtomicWaker;
+use futures_util::Stream;
+
+enum Queue<T> {
+    Bounded(ArrayQueue<T>),
+    Unbounded(SegQueue<T>),
+}
+
+struct Shared<T> {
+    queue: Queue<T>,
+    receiver_waker: AtomicWaker,
+    /// Woken when a bounded queue has space again.
+    space: Notify,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+impl<T> Shared<T> {
+    fn pop(&self) -> Option<T> {
+        match &self.queue {
+            Queue::Bounded(queue) => {
+                let value = queue.pop()?;
+                self.space.notify_one();
+                Some(value)
+            }
+            Queue::Unbounded(queue) => queue.pop(),
+        }
+    }
+}
+
+/// Creates a channel that holds up to `capacity` values.
+///
+/// `Sender::try_send` on it neither blocks nor allocates, so interrupt
+/// handlers can use it.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    new(Queue::Bounded(ArrayQueue::new(capacity)))
+}
+
+/// Creates a channel without a limit on the number of queued values.
+///
+/// Sending allocates, so it must not be used from interrupt handlers.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    new(Queue::Unbounded(SegQueue::new()))
+}
+
+fn new<T>(queue: Queue<T>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue,
+        receiver_waker: AtomicWaker::new(),
+        space: Notify::new(),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+    });
+    let sender = Sender {
+        shared: shared.clone(),
+    };
+    (sender, Receiver { shared })
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for space if the channel is bounded and full.
+    ///
+    /// Fails if the receiver was dropped.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = match self.try_send(value) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(value)) => return Err(SendError(value)),
+            Err(TrySendError::Full(value)) => value,
+        };
+        loop {
+            // wait in line before trying again, so space freed or the
+            // receiver dropped in between is not missed
+            let mut space = self.shared.space.notified();
+            space.enable();
+            match self.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed(value)) => return Err(SendError(value)),
+                Err(TrySendError::Full(returned)) => value = returned,
+            }
+            space.await;
+        }
+    }
+
+    /// Sends `value` if there is space, without waiting.
+    ///
+    /// On a bounded channel this neither blocks nor allocates, so it is safe
+    /// to call from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+        match &self.shared.queue {
+            Queue::Bounded(queue) => queue.push(value).map_err(TrySendError::Full)?,
+            Queue::Unbounded(queue) => queue.push(value),
+        }
+        self.shared.receiver_waker.wake();
+        Ok(())
+    }
+
+    /// Returns whether the receiver was dropped.
+    pub fn is_closed(&self) -> bool {
+        !self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // let the receiver see that the channel is closed
+            self.shared.receiver_waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value, or returns `None` once all senders are
+    /// dropped and the queue is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|context| self.poll_recv(context)).await
+    }
+
+    pub fn poll_recv(&mut self, context: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.shared.pop() {
+            return Poll::Ready(Some(value));
+        }
+        self.shared.receiver_waker.register(context.waker());
+        // check again, a sender might have pushed before the registration
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    /// Takes the next value without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.shared.pop() {
+            return Ok(value);
+        }
+        if self.shared.senders.load(Ordering::Acquire) == 0 {
+            // values sent right before the last sender was dropped
+            return self.shared.pop().ok_or(TryRecvError::Closed);
+        }
+        Err(TryRecvError::Empty)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+        // fail the senders waiting for space
+        self.shared.space.notify_waiters();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(context)
+    }
+}
@@ -0,0 +1,225 @@
+//! Combinators for running futures concurrently within one task.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Output of `select`: which future completed first, with its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Waits for the first of two futures to complete and drops the other.
+///
+/// `a` is polled first, so it wins if both are ready.
+pub fn select<A: Future, B: Future>(a: A, b: B) -> Select<A, B> {
+    Select { a, b }
+}
+
+/// Future returned by `select`.
+pub struct Select<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A: Future, B: Future> Future for Select<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        // Safety: the futures are never moved out of the pinned `Select`
+        let this = unsafe { self.get_unchecked_mut() };
+        if let Poll::Ready(output) = unsafe { Pin::new_unchecked(&mut this.a) }.poll(context) {
+            return Poll::Ready(Either::Left(output));
+        }
+        if let Poll::Ready(output) = unsafe { Pin::new_unchecked(&mut this.b) }.poll(context) {
+            return Poll::Ready(Either::Right(output));
+        }
+        Poll::Pending
+    }
+}
+
+/// A future that keeps its output once completed.
+enum MaybeDone<F: Future> {
+    Pending(F),
+    Done(F::Output),
+    Taken,
+}
+
+impl<F: Future> MaybeDone<F> {
+    /// Polls the future unless it already completed, and returns whether it
+    /// has.
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> bool {
+        // Safety: the future is not moved until it completed
+        let this = unsafe { self.get_unchecked_mut() };
+        if let MaybeDone::Pending(future) = this {
+            match unsafe { Pin::new_unchecked(future) }.poll(context) {
+                Poll::Ready(output) => *this = MaybeDone::Done(output),
+                Poll::Pending => return false,
+            }
+        }
+        true
+    }
+
+    fn take(self: Pin<&mut Self>) -> F::Output {
+        // Safety: only called once the future completed and was dropped
+        let this = unsafe { self.get_unchecked_mut() };
+        match core::mem::replace(this, MaybeDone::Taken) {
+            MaybeDone::Done(output) => output,
+            _ => panic!("output taken before completion or twice"),
+        }
+    }
+}
+
+/// Waits for both futures to complete and returns both outputs.
+pub fn join<A: Future, B: Future>(a: A, b: B) -> Join<A, B> {
+    Join {
+        a: MaybeDone::Pending(a),
+        b: MaybeDone::Pending(b),
+    }
+}
+
+/// Future returned by `join`.
+pub struct Join<A: Future, B: Future> {
+    a: MaybeDone<A>,
+    b: MaybeDone<B>,
+}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        // Safety: the fields are never moved out of the pinned `Join`
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let mut b = unsafe { Pin::new_unchecked(&mut this.b) };
+        // poll both even if the first one is pending, to make progress on both
+        let a_done = a.as_mut().poll(context);
+        let b_done = b.as_mut().poll(context);
+        if a_done && b_done {
+            Poll::Ready((a.take(), b.take()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Waits for the first of several futures to complete, drops the others and
+/// runs the branch of the one that completed. Only usable in async code.
+///
+/// ```ignore
+/// select! {
+///     key = next_key() => handle(key),
+///     () = sleep(TIMEOUT) => return,
+/// }
+/// ```
+///
+/// The futures are polled in order, so earlier branches win if several are
+/// ready. The branches run after the futures were dropped, so they may use
+/// what the futures borrowed.
+#[macro_export]
+macro_rules! select {
+    (@future $future:expr) => { $future };
+    (@future $future:expr, $($rest:expr),+) => {
+        $crate::task::combinator::select($future, $crate::select!(@future $($rest),+))
+    };
+    (@match $output:expr; $pat:pat => $body:expr) => {
+        match $output {
+            $pat => $body,
+        }
+    };
+    (@match $output:expr; $pat:pat => $body:expr, $($rest:pat => $rest_body:expr),+) => {
+        match $output {
+            $crate::task::combinator::Either::Left($pat) => $body,
+            $crate::task::combinator::Either::Right(rest) => {
+                $crate::select!(@match rest; $($rest => $rest_body),+)
+            }
+        }
+    };
+    ($($pat:pat = $future:expr => $body:expr),+ $(,)?) => {{
+        let output = $crate::select!(@future $($future),+).await;
+        $crate::select!(@match output; $($pat => $body),+)
+    }};
+}
+
+/// Waits for all of several futures to complete and returns their outputs
+/// as a tuple. Only usable in async code.
+#[macro_export]
+macro_rules! join {
+    (@future $future:expr) => { $future };
+    (@future $future:expr, $($rest:expr),+) => {
+        $crate::task::combinator::join($future, $crate::join!(@future $($rest),+))
+    };
+    // only counts the futures, which were already moved into the `Join`s
+    (@flatten [$($done:ident)*] $output:ident; $last:expr) => {
+        ($($done,)* $output,)
+    };
+    (@flatten [$($done:ident)*] $output:ident; $first:expr, $($rest:expr),+) => {{
+        let (value, rest) = $output;
+        $crate::join!(@flatten [$($done)* value] rest; $($rest),+)
+    }};
+    ($($future:expr),+ $(,)?) => {{
+        let output = $crate::join!(@future $($future),+).await;
+        $crate::join!(@flatten [] output; $($future),+)
+    }};
+}
+
+#[cfg(test)]
+use super::{executor::Executor, yield_now};
+
+#[test_case]
+fn select_returns_first_output() {
+    let mut executor = Executor::new();
+    let result = executor.block_on(select(core::future::pending::<()>(), async { 7 }));
+    assert_eq!(result, Either::Right(7));
+    let result = executor.block_on(select(async { 1 }, async { 2 }));
+    assert_eq!(result, Either::Left(1));
+}
+
+#[test_case]
+fn join_waits_for_both() {
+    let mut executor = Executor::new();
+    let result = executor.block_on(join(
+        async {
+            for _ in 0..3 {
+                yield_now().await;
+            }
+            "slow"
+        },
+        async { "fast" },
+    ));
+    assert_eq!(result, ("slow", "fast"));
+}
+
+#[test_case]
+fn select_macro_runs_first_branch() {
+    let mut executor = Executor::new();
+    let result = executor.block_on(async {
+        crate::select! {
+            () = core::future::pending() => "pending",
+            n = async {
+                yield_now().await;
+                1
+            } => if n == 1 { "slow" } else { "wrong" },
+            n = async { 2 } => if n == 2 { "fast" } else { "wrong" },
+        }
+    });
+    assert_eq!(result, "fast");
+}
+
+#[test_case]
+fn join_macro_returns_all_outputs() {
+    let mut executor = Executor::new();
+    let result = executor.block_on(async {
+        crate::join!(
+            async {
+                yield_now().await;
+                1
+            },
+            async { "two" },
+            async { 3.0 },
+        )
+    });
+    assert_eq!(result, (1, "two", 3.0));
+}
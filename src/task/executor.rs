@@ -1,29 +1,190 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+#[cfg(test)]
+use super::{yield_now, JoinError};
+use super::{JoinHandle, Priority, Task, TaskId};
+use crate::allocator::slab::{SlabBox, SlabCache};
+use crate::smp::{self, MAX_CPUS};
+use crate::time;
+use alloc::{
+    alloc::handle_alloc_error,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use arrayvec::ArrayVec;
+use core::alloc::Layout;
+use core::future::Future;
+#[cfg(test)]
+use core::task::Poll;
+use core::task::{Context, Waker};
+use crossbeam_queue::SegQueue;
+use ready_queue::{Header, ReadyQueue};
+use spin::Mutex;
 
+mod ready_queue;
+pub mod registry;
+
+/// Number of times a task is polled per round. Wakes beyond that are
+/// deferred to the next round, so a task that keeps waking itself cannot
+/// hold up the others.
+const POLL_BUDGET: u32 = 4;
+
+/// Number of polls of higher priority tasks after which a ready task of a
+/// lower priority is polled anyway.
+const STARVATION_LIMIT: u32 = 16;
+
+/// Runs tasks to completion, by priority.
+///
+/// Tasks are run in rounds. In each round, ready tasks are polled highest
+/// priority first, FIFO within a priority, each at most `POLL_BUDGET` times.
+/// `STARVATION_LIMIT` keeps lower priorities from waiting forever while
+/// higher priority tasks stay ready.
+///
+/// An executor stays on the CPU it was created on. Executors created with
+/// `with_work_stealing`, one per CPU, share their load: when one runs out of
+/// tasks, it takes tasks that were spawned through the `Spawner` of another
+/// but have not started yet.
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    tasks: BTreeMap<TaskId, SlabBox<'static, Task>>,
+    /// Tasks woken since they were last taken into a run queue.
+    ready_queue: Arc<ReadyQueue>,
     waker_cache: BTreeMap<TaskId, Waker>,
+    spawner: Spawner,
+    run_queues: [VecDeque<Arc<Header>>; Priority::COUNT],
+    /// Tasks woken after using up their budget, run in the next round.
+    deferred: Vec<Arc<Header>>,
+    /// Polls of higher priority tasks since each priority was last served.
+    waiting: [u32; Priority::COUNT],
+    round: u64,
+    /// Whether the executor takes part in work stealing.
+    steals: bool,
+}
+
+/// Slots for the tasks of all executors, which are created and dropped often.
+static TASKS: SlabCache<Task> = SlabCache::new();
+
+/// Spawn queues of the executors that take part in work stealing, by CPU.
+static STEAL_QUEUES: Mutex<[Option<Arc<SegQueue<Spawned>>>; MAX_CPUS]> =
+    Mutex::new([const { None }; MAX_CPUS]);
+
+/// Returns the spawner of the executor that is running tasks on this CPU.
+pub(super) fn current_spawner() -> Option<Spawner> {
+    smp::current().spawner.lock().clone()
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            ready_queue: Arc::new(ReadyQueue::new()),
             waker_cache: BTreeMap::new(),
+            spawner: Spawner {
+                queue: Arc::new(SegQueue::new()),
+                cpu: smp::current().index(),
+            },
+            run_queues: Default::default(),
+            deferred: Vec::new(),
+            waiting: [0; Priority::COUNT],
+            round: 0,
+            steals: false,
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    /// Makes the executor steal tasks from the other CPUs' work stealing
+    /// executors when it runs out of tasks, and lets them steal from it.
+    ///
+    /// Only tasks spawned through a `Spawner` can be stolen, and only before
+    /// their first poll: after that, their wakers point to the ready queue
+    /// of the executor that polled them.
+    ///
+    /// Panics if another executor on this CPU already takes part.
+    pub fn with_work_stealing(mut self) -> Self {
+        let mut queues = STEAL_QUEUES.lock();
+        let slot = &mut queues[self.spawner.cpu];
+        assert!(slot.is_none(), "CPU already has a work stealing executor");
+        *slot = Some(self.spawner.queue.clone());
+        self.steals = true;
+        drop(queues);
+        self
+    }
+
+    /// Returns a handle that spawns tasks onto this executor from anywhere,
+    /// including from its own tasks.
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+
+    /// Spawns `future` as a new task and returns a handle to its output.
+    ///
+    /// Dropping the handle detaches the task, it still runs to completion.
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_with_priority(Priority::default(), future)
+    }
+
+    /// Like `spawn`, but names the task in the `registry`.
+    pub fn spawn_named<F>(&mut self, name: &'static str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn_task(task.with_name(name));
+        handle
+    }
+
+    /// Like `spawn`, but schedules the task with the given priority.
+    pub fn spawn_with_priority<F>(&mut self, priority: Priority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn_task(task.with_priority(priority));
+        handle
+    }
+
+    fn spawn_task(&mut self, task: Task) {
+        let header = Arc::new(Header::new(task.id, self.ready_queue.clone()));
+        let task = TASKS
+            .alloc(task)
+            .unwrap_or_else(|_| handle_alloc_error(Layout::new::<Task>()));
+        self.insert(task, header);
+    }
+
+    /// Like `spawn`, but gives the task back instead of aborting when its
+    /// slot or queue header cannot be allocated.
+    ///
+    /// Use with `Task::try_new` or `Task::joinable` to reject work under
+    /// memory pressure. Note that registering the task can still allocate a
+    /// node in `tasks`.
+    pub fn try_spawn(&mut self, task: Task) -> Result<(), Task> {
+        let task = TASKS.alloc(task)?;
+        match Arc::try_new(Header::new(task.id, self.ready_queue.clone())) {
+            Ok(header) => {
+                self.insert(task, header);
+                Ok(())
+            }
+            Err(_) => Err(SlabBox::into_inner(task)),
+        }
+    }
+
+    /// Registers `task` and schedules its first poll.
+    fn insert(&mut self, task: SlabBox<'static, Task>, header: Arc<Header>) {
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
+        if self.tasks.contains_key(&task_id) {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        registry::register(task_id, task.name, task.priority, header.clone());
+        let waker = Waker::from(header);
+        if let Some(abort) = &task.abort {
+            abort.register(&waker);
+        }
+        self.tasks.insert(task_id, task);
+        waker.wake_by_ref();
+        self.waker_cache.insert(task_id, waker);
     }
 
     pub fn run(&mut self) -> ! {
@@ -33,70 +194,617 @@ impl Executor {
         }
     }
 
+    /// Runs the executor until `future` completes and returns its output.
+    ///
+    /// Other spawned tasks run alongside it and stay in the executor
+    /// afterwards.
+    pub fn block_on<F>(&mut self, future: F) -> F::Output
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let mut handle = self.spawn(future);
+        loop {
+            self.run_ready_tasks();
+            if let Some(output) = handle.try_join() {
+                return output.expect("block_on task was cancelled");
+            }
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Moves the tasks submitted through `Spawner`s into `tasks`.
+    fn spawn_pending(&mut self) {
+        while let Some(Spawned(task)) = self.spawner.queue.pop() {
+            self.spawn_task(task);
+        }
+    }
+
     fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+        {
+            let mut current = smp::current().spawner.lock();
+            if !current.as_ref().is_some_and(|s| s.is_same(&self.spawner)) {
+                *current = Some(self.spawner.clone());
             }
         }
+        self.spawn_pending();
+        super::timer::fire_expired();
+
+        self.round += 1;
+        // tasks deferred in the last round have a new budget now
+        let deferred = core::mem::take(&mut self.deferred);
+        for header in deferred {
+            self.enqueue(header);
+        }
+        loop {
+            for header in self.ready_queue.take_all() {
+                self.enqueue(header);
+            }
+            match self.next_task() {
+                Some(header) => self.poll_task(header),
+                None => break,
+            }
+        }
+    }
+
+    /// Adds a woken task to the run queue of its priority, or defers it to
+    /// the next round if it used up its budget.
+    fn enqueue(&mut self, header: Arc<Header>) {
+        let task = match self.tasks.get(&header.id) {
+            Some(task) => task,
+            None => return, // task no longer exists
+        };
+        if task.round == self.round && task.round_polls >= POLL_BUDGET {
+            self.deferred.push(header);
+        } else {
+            self.run_queues[task.priority.index()].push_back(header);
+        }
+    }
+
+    /// Picks the next task to poll from the run queues.
+    fn next_task(&mut self) -> Option<Arc<Header>> {
+        let ready = |index: &usize| !self.run_queues[*index].is_empty();
+        let starving = (0..Priority::COUNT)
+            .filter(ready)
+            .find(|&index| self.waiting[index] >= STARVATION_LIMIT);
+        let index = starving.or_else(|| (0..Priority::COUNT).find(ready))?;
+        for lower in index + 1..Priority::COUNT {
+            if self.run_queues[lower].is_empty() {
+                self.waiting[lower] = 0;
+            } else {
+                self.waiting[lower] += 1;
+            }
+        }
+        self.waiting[index] = 0;
+        self.run_queues[index].pop_front()
+    }
+
+    fn poll_task(&mut self, header: Arc<Header>) {
+        // wakes from now on queue the task again
+        header.unschedule();
+        let task_id = header.id;
+        let task = match self.tasks.get_mut(&task_id) {
+            Some(task) => task,
+            None => return, // task no longer exists
+        };
+        if task.abort.as_ref().is_some_and(|abort| abort.is_aborted()) {
+            // drops the future, which reports the cancellation to joiners
+            self.remove(task_id);
+            return;
+        }
+        if task.round != self.round {
+            task.round = self.round;
+            task.round_polls = 0;
+        }
+        task.round_polls += 1;
+        let mut context = Context::from_waker(&self.waker_cache[&task_id]);
+        let start = time::ticks();
+        let poll = task.poll(&mut context);
+        registry::record_poll(task_id, time::ticks() - start);
+        if poll.is_ready() {
+            self.remove(task_id);
+        }
+    }
+
+    /// Removes a task along with its cached waker and registry entry.
+    fn remove(&mut self, task_id: TaskId) {
+        self.tasks.remove(&task_id);
+        self.waker_cache.remove(&task_id);
+        registry::unregister(task_id);
+    }
+
+    /// Moves up to half of the tasks in the spawn queue of another work
+    /// stealing executor into this one, trying the CPUs after this one in
+    /// turn. Returns whether a task was stolen.
+    fn steal(&mut self) -> bool {
+        if !self.steals {
+            return false;
+        }
+        let cpu = self.spawner.cpu;
+        let victims: ArrayVec<_, MAX_CPUS> = {
+            let queues = STEAL_QUEUES.lock();
+            (1..MAX_CPUS)
+                .filter_map(|offset| queues[(cpu + offset) % MAX_CPUS].clone())
+                .collect()
+        };
+        for queue in victims {
+            let mut stolen = 0;
+            for _ in 0..queue.len().div_ceil(2) {
+                let Some(Spawned(task)) = queue.pop() else {
+                    break;
+                };
+                self.spawn_task(task);
+                stolen += 1;
+            }
+            if stolen > 0 {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns whether there is a task to steal. Takes no locks that
+    /// interrupt handlers take, so it can be called with interrupts disabled.
+    fn can_steal(&self) -> bool {
+        self.steals
+            && STEAL_QUEUES
+                .lock()
+                .iter()
+                .flatten()
+                .any(|queue| !queue.is_empty())
     }
 
-    fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+    fn sleep_if_idle(&mut self) {
+        use x86_64::instructions::interrupts;
 
+        if self.steal() {
+            return;
+        }
         interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
+        let cpu = smp::current();
+        cpu.enter_idle();
+        let idle = self.ready_queue.is_empty()
+            && self.spawner.queue.is_empty()
+            && self.deferred.is_empty()
+            && !self.can_steal();
+        if !idle {
             interrupts::enable();
+        } else if cpu.index() == 0 {
+            // lets other threads run, if any
+            crate::thread::idle();
+        } else {
+            // threads only run on the bootstrap processor
+            interrupts::enable_and_hlt();
         }
+        cpu.leave_idle();
     }
 }
 
-struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+/// A cloneable handle for spawning tasks onto an `Executor`.
+///
+/// Unlike `Executor::spawn` it needs no `&mut Executor`, so running tasks and
+/// other threads can use it. Spawned tasks are queued in a lock-free queue
+/// and moved into the executor on its next loop iteration.
+#[derive(Clone)]
+pub struct Spawner {
+    queue: Arc<SegQueue<Spawned>>,
+    /// CPU of the executor.
+    cpu: usize,
+}
+
+impl Spawner {
+    /// Spawns `future` onto the executor and returns a handle to its output.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with_priority(Priority::default(), future)
+    }
+
+    /// Like `spawn`, but names the task in the `registry`.
+    pub fn spawn_named<F>(&self, name: &'static str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.push(task.with_name(name));
+        handle
+    }
+
+    /// Like `spawn`, but schedules the task with the given priority.
+    pub fn spawn_with_priority<F>(&self, priority: Priority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.push(task.with_priority(priority));
+        handle
+    }
+
+    /// Queues `task` and wakes the executor's CPU, or if that one is busy,
+    /// an idle CPU that can steal the task.
+    fn push(&self, task: Task) {
+        self.queue.push(Spawned(task));
+        if !smp::wake(self.cpu) {
+            smp::wake_any();
+        }
+    }
+
+    fn is_same(&self, other: &Spawner) -> bool {
+        Arc::ptr_eq(&self.queue, &other.queue)
+    }
 }
 
-impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+/// A task whose future is `Send`.
+struct Spawned(Task);
+
+// Safety: `Spawner::spawn` only accepts `Send` futures, and the other fields
+// of `Task` are `Send`.
+unsafe impl Send for Spawned {}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        if self.steals {
+            STEAL_QUEUES.lock()[self.spawner.cpu] = None;
+        }
+        // queued headers own a reference to the queue
+        drop(self.ready_queue.take_all());
+        for &task_id in self.tasks.keys() {
+            registry::unregister(task_id);
+        }
     }
+}
+
+#[test_case]
+fn join_handle_returns_output() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { 6 * 7 });
+    assert_eq!(executor.block_on(handle), Ok(42));
+}
 
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+#[test_case]
+fn tasks_can_await_each_other() {
+    use alloc::vec::Vec;
+
+    let mut executor = Executor::new();
+    let handles: Vec<_> = (0..10u64)
+        .map(|n| executor.spawn(async move { n * n }))
+        .collect();
+    let sum = executor.block_on(async move {
+        let mut sum = 0;
+        for handle in handles {
+            sum += handle.await.unwrap();
+        }
+        sum
+    });
+    assert_eq!(sum, 285);
+}
+
+#[test_case]
+fn join_handle_waits_for_pending_task() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async {
+        for _ in 0..5 {
+            yield_now().await;
+        }
+        "done"
+    });
+    assert!(!handle.is_finished());
+    assert_eq!(executor.block_on(handle), Ok("done"));
+}
+
+#[test_case]
+fn dropped_handle_detaches_task() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static RAN: AtomicBool = AtomicBool::new(false);
+    let mut executor = Executor::new();
+    drop(executor.spawn(async { RAN.store(true, Ordering::Relaxed) }));
+    executor.block_on(async {});
+    assert!(RAN.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn abort_drops_future_and_cancels_joiner() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static DROPPED: AtomicBool = AtomicBool::new(false);
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            DROPPED.store(true, Ordering::Relaxed);
+        }
     }
+
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async {
+        let _guard = Guard;
+        core::future::pending::<()>().await;
+    });
+    let abort = handle.abort_handle();
+    let result = executor.block_on(async move {
+        yield_now().await;
+        abort.abort();
+        handle.await
+    });
+    assert_eq!(result, Err(JoinError::Cancelled));
+    assert!(DROPPED.load(Ordering::Relaxed));
+    assert!(executor.tasks.is_empty());
+    assert!(executor.waker_cache.is_empty());
+}
+
+#[test_case]
+fn abort_before_first_poll() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static POLLED: AtomicBool = AtomicBool::new(false);
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { POLLED.store(true, Ordering::Relaxed) });
+    handle.abort();
+    assert_eq!(executor.block_on(handle), Err(JoinError::Cancelled));
+    assert!(!POLLED.load(Ordering::Relaxed));
 }
 
-impl Wake for TaskWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_task();
+#[test_case]
+fn task_can_abort_itself() {
+    use super::AbortHandle;
+    use spin::Mutex;
+
+    let slot: Arc<Mutex<Option<AbortHandle>>> = Arc::new(Mutex::new(None));
+    let own = slot.clone();
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async move {
+        own.lock().take().unwrap().abort();
+        // the abort takes effect once the task yields
+        yield_now().await;
+        true
+    });
+    *slot.lock() = Some(handle.abort_handle());
+    assert_eq!(executor.block_on(handle), Err(JoinError::Cancelled));
+}
+
+#[test_case]
+fn abort_after_completion_keeps_output() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { 5 });
+    let abort = handle.abort_handle();
+    executor.block_on(async {});
+    abort.abort();
+    assert_eq!(executor.block_on(handle), Ok(5));
+}
+
+#[test_case]
+fn spawner_is_send_and_clone() {
+    fn assert_send_clone<T: Send + Sync + Clone>() {}
+    assert_send_clone::<Spawner>();
+}
+
+#[test_case]
+fn tasks_can_spawn_tasks() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let result = executor.block_on(async move {
+        let inner = spawner.clone();
+        let outer = spawner.spawn(async move { inner.spawn(async { 20 }).await.unwrap() + 1 });
+        outer.await.unwrap() * 2
+    });
+    assert_eq!(result, 42);
+}
+
+#[test_case]
+fn global_spawn_uses_running_executor() {
+    let mut executor = Executor::new();
+    let result = executor.block_on(async {
+        let handles: alloc::vec::Vec<_> =
+            (0..20u64).map(|n| super::spawn(async move { n })).collect();
+        let mut sum = 0;
+        for handle in handles {
+            sum += handle.await.unwrap();
+        }
+        sum
+    });
+    assert_eq!(result, 190);
+}
+
+#[test_case]
+fn spawner_before_run() {
+    let mut executor = Executor::new();
+    let handle = executor.spawner().spawn(async { "queued" });
+    assert_eq!(executor.block_on(handle), Ok("queued"));
+}
+
+#[test_case]
+fn many_tasks_woken_at_once() {
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    // more tasks than the heap holds at once, in waves that are each larger
+    // than the old fixed-size queue
+    const WAVE: usize = 150;
+    const WAVES: usize = 20;
+
+    let mut executor = Executor::new();
+    for _ in 0..WAVES {
+        let waiters: Arc<Mutex<Vec<Waker>>> = Arc::new(Mutex::new(Vec::new()));
+        let open = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicUsize::new(0));
+        for _ in 0..WAVE {
+            let (waiters, open, finished) = (waiters.clone(), open.clone(), finished.clone());
+            executor.spawn(core::future::poll_fn(move |context| {
+                if open.load(Ordering::Relaxed) {
+                    finished.fetch_add(1, Ordering::Relaxed);
+                    return Poll::Ready(());
+                }
+                waiters.lock().push(context.waker().clone());
+                Poll::Pending
+            }));
+        }
+        let done = finished.clone();
+        executor.block_on(async move {
+            while waiters.lock().len() < WAVE {
+                yield_now().await;
+            }
+            open.store(true, Ordering::Relaxed);
+            let wakers = core::mem::take(&mut *waiters.lock());
+            for waker in wakers {
+                // the second wake must not queue the task twice
+                waker.wake_by_ref();
+                waker.wake();
+            }
+            while done.load(Ordering::Relaxed) < WAVE {
+                yield_now().await;
+            }
+        });
+        assert_eq!(finished.load(Ordering::Relaxed), WAVE);
+        assert!(executor.tasks.is_empty());
+        assert!(executor.waker_cache.is_empty());
     }
+}
+
+#[test_case]
+fn repeated_wakes_poll_once() {
+    let mut executor = Executor::new();
+    let mut polls = 0;
+    let handle = executor.spawn(core::future::poll_fn(move |context| {
+        polls += 1;
+        if polls == 1 {
+            for _ in 0..10_000 {
+                context.waker().wake_by_ref();
+            }
+            return Poll::Pending;
+        }
+        Poll::Ready(polls)
+    }));
+    assert_eq!(executor.block_on(handle), Ok(2));
+}
 
-    fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+#[test_case]
+fn higher_priorities_run_first() {
+    use alloc::vec::Vec;
+
+    let order: Arc<Mutex<Vec<Priority>>> = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    let handles: Vec<_> = [
+        Priority::Background,
+        Priority::Interactive,
+        Priority::BottomHalf,
+    ]
+    .into_iter()
+    .map(|priority| {
+        let order = order.clone();
+        executor.spawn_with_priority(priority, async move { order.lock().push(priority) })
+    })
+    .collect();
+    executor.block_on(async move {
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    });
+    assert_eq!(
+        *order.lock(),
+        [
+            Priority::BottomHalf,
+            Priority::Interactive,
+            Priority::Background
+        ]
+    );
+}
+
+/// Spawns a task that keeps waking itself until `stop` is set, counting its
+/// polls in `polls`.
+#[cfg(test)]
+fn spawn_chatty(
+    executor: &mut Executor,
+    stop: &Arc<core::sync::atomic::AtomicBool>,
+    polls: &Arc<core::sync::atomic::AtomicU32>,
+) {
+    use core::sync::atomic::Ordering;
+
+    let (stop, polls) = (stop.clone(), polls.clone());
+    executor.spawn_with_priority(Priority::Interactive, async move {
+        while !stop.load(Ordering::Relaxed) {
+            polls.fetch_add(1, Ordering::Relaxed);
+            yield_now().await;
+        }
+    });
+}
+
+#[test_case]
+fn poll_budget_limits_chatty_task() {
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let polls = Arc::new(AtomicU32::new(0));
+    let mut executor = Executor::new();
+    spawn_chatty(&mut executor, &stop, &polls);
+    let (stop2, polls2) = (stop.clone(), polls.clone());
+    let background = executor.spawn_with_priority(Priority::Background, async move {
+        stop2.store(true, Ordering::Relaxed);
+        polls2.load(Ordering::Relaxed)
+    });
+    let polls_before = executor.block_on(background).unwrap();
+    assert!(polls_before <= POLL_BUDGET);
+}
+
+#[test_case]
+fn lower_priorities_do_not_starve() {
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let polls = Arc::new(AtomicU32::new(0));
+    let mut executor = Executor::new();
+    // together they could fill a round with many times the starvation limit
+    for _ in 0..40 {
+        spawn_chatty(&mut executor, &stop, &polls);
     }
+    let (stop2, polls2) = (stop.clone(), polls.clone());
+    let background = executor.spawn_with_priority(Priority::Background, async move {
+        stop2.store(true, Ordering::Relaxed);
+        polls2.load(Ordering::Relaxed)
+    });
+    let polls_before = executor.block_on(background).unwrap();
+    assert!(polls_before <= STARVATION_LIMIT);
+}
+
+#[test_case]
+fn registry_lists_tasks() {
+    use registry::TaskState;
+
+    let mut executor = Executor::new();
+    let waiting = executor.spawn_named("waiting", core::future::pending::<()>());
+    let waiting_id = waiting.id();
+    let (state, info) = executor.block_on(async move {
+        yield_now().await;
+        let own = registry::task(super::current_task().unwrap()).unwrap();
+        (own.state, registry::task(waiting_id).unwrap())
+    });
+    assert_eq!(state, TaskState::Running);
+    assert_eq!(info.name, Some("waiting"));
+    assert_eq!(info.priority, Priority::Interactive);
+    assert_eq!(info.state, TaskState::Pending);
+    assert_eq!(info.polls, 1);
+    // finished tasks are removed, the pending one is removed with the executor
+    assert_eq!(registry::tasks().len(), 1);
+    drop(executor);
+    assert!(registry::task(waiting_id).is_none());
+}
+
+#[test_case]
+fn registry_records_slow_polls() {
+    let mut executor = Executor::new();
+    let slow = executor.spawn_named("slow", async {
+        let start = time::ticks();
+        while time::ticks() < start + registry::SLOW_POLL_TICKS {
+            core::hint::spin_loop();
+        }
+        core::future::pending::<()>().await;
+    });
+    let slow_id = slow.id();
+    let info = executor.block_on(async move { registry::task(slow_id).unwrap() });
+    assert_eq!(info.polls, 1);
+    assert!(info.longest_poll_ticks >= registry::SLOW_POLL_TICKS);
+    assert_eq!(info.poll_ticks, info.longest_poll_ticks);
 }
@@ -0,0 +1,136 @@
+//! The executor's queue of tasks that are ready to be polled.
+//!
+//! Every task has a `Header` that is both its waker and a node of an
+//! intrusive list, so waking a task never allocates and is safe from
+//! interrupt handlers. The `scheduled` flag keeps a task in the queue at most
+//! once, which bounds the queue by the number of tasks instead of a fixed
+//! capacity. The executor may run on another CPU than the waker, so pushing
+//! also wakes that CPU if it is idle.
+
+use super::TaskId;
+use crate::smp;
+use alloc::{sync::Arc, task::Wake};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+pub(super) struct Header {
+    pub(super) id: TaskId,
+    /// Set while the header is in the queue.
+    scheduled: AtomicBool,
+    /// Next header in the queue, an `Arc<Header>` turned into a raw pointer.
+    next: AtomicPtr<Header>,
+    queue: Arc<ReadyQueue>,
+}
+
+impl Header {
+    pub(super) fn new(id: TaskId, queue: Arc<ReadyQueue>) -> Self {
+        Header {
+            id,
+            scheduled: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+            queue,
+        }
+    }
+
+    /// Returns whether the task is queued to be polled.
+    pub(super) fn is_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::Acquire)
+    }
+
+    /// Allows the task to be queued again, called before polling it.
+    pub(super) fn unschedule(&self) {
+        self.scheduled.store(false, Ordering::Release);
+    }
+}
+
+impl Wake for Header {
+    fn wake(self: Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            let queue = self.queue.clone();
+            queue.push(self);
+        }
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.clone().wake();
+    }
+}
+
+/// A lock-free multi-producer, single-consumer queue of task headers.
+pub(super) struct ReadyQueue {
+    /// Most recently pushed header, linked to the older ones.
+    head: AtomicPtr<Header>,
+    /// CPU of the executor that owns the queue.
+    cpu: usize,
+}
+
+impl ReadyQueue {
+    /// Creates a queue for an executor on the current CPU.
+    pub(super) fn new() -> Self {
+        ReadyQueue {
+            head: AtomicPtr::new(ptr::null_mut()),
+            cpu: smp::current().index(),
+        }
+    }
+
+    fn push(&self, header: Arc<Header>) {
+        let node = Arc::into_raw(header) as *mut Header;
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next.store(head, Ordering::Relaxed) };
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        smp::wake(self.cpu);
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire).is_null()
+    }
+
+    /// Removes all queued headers and returns them, oldest first.
+    ///
+    /// Must only be called by the executor that owns the queue.
+    pub(super) fn take_all(&self) -> Batch {
+        let mut node = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+        // the list is newest first, reverse it in place
+        let mut reversed = ptr::null_mut();
+        while !node.is_null() {
+            let next = unsafe { (*node).next.load(Ordering::Relaxed) };
+            unsafe { (*node).next.store(reversed, Ordering::Relaxed) };
+            reversed = node;
+            node = next;
+        }
+        Batch { next: reversed }
+    }
+}
+
+/// Headers taken from a `ReadyQueue`.
+pub(super) struct Batch {
+    next: *mut Header,
+}
+
+impl Iterator for Batch {
+    type Item = Arc<Header>;
+
+    fn next(&mut self) -> Option<Arc<Header>> {
+        if self.next.is_null() {
+            return None;
+        }
+        // the queue owned one reference, which is passed on to the caller
+        let header = unsafe { Arc::from_raw(self.next) };
+        self.next = header.next.load(Ordering::Relaxed);
+        Some(header)
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
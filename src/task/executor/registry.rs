@@ -0,0 +1,136 @@
+//! A list of the tasks of all executors with their poll statistics, for
+//! commands like `tasks` or `top`.
+//!
+//! Poll times are measured in timer ticks (see `time::ticks`), so polls
+//! shorter than a tick mostly count as zero.
+
+use super::ready_queue::Header;
+use crate::serial_println;
+use crate::task::{current_task, Priority, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::fmt;
+use spin::Mutex;
+
+/// Polls taking at least this many ticks print a warning on serial.
+pub const SLOW_POLL_TICKS: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Being polled right now.
+    Running,
+    /// Woken and waiting to be polled.
+    Ready,
+    /// Waiting to be woken.
+    Pending,
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            TaskState::Running => "running",
+            TaskState::Ready => "ready",
+            TaskState::Pending => "pending",
+        };
+        f.write_str(state)
+    }
+}
+
+/// A snapshot of a task, see `tasks`.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<&'static str>,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub polls: u64,
+    /// Ticks spent in all polls so far.
+    pub poll_ticks: u64,
+    /// Ticks spent in the longest single poll.
+    pub longest_poll_ticks: u64,
+}
+
+struct Entry {
+    name: Option<&'static str>,
+    priority: Priority,
+    header: Arc<Header>,
+    polls: u64,
+    poll_ticks: u64,
+    longest_poll_ticks: u64,
+}
+
+impl Entry {
+    fn info(&self, id: TaskId) -> TaskInfo {
+        let state = if current_task() == Some(id) {
+            TaskState::Running
+        } else if self.header.is_scheduled() {
+            TaskState::Ready
+        } else {
+            TaskState::Pending
+        };
+        TaskInfo {
+            id,
+            name: self.name,
+            priority: self.priority,
+            state,
+            polls: self.polls,
+            poll_ticks: self.poll_ticks,
+            longest_poll_ticks: self.longest_poll_ticks,
+        }
+    }
+}
+
+static REGISTRY: Mutex<BTreeMap<TaskId, Entry>> = Mutex::new(BTreeMap::new());
+
+pub(super) fn register(
+    id: TaskId,
+    name: Option<&'static str>,
+    priority: Priority,
+    header: Arc<Header>,
+) {
+    let entry = Entry {
+        name,
+        priority,
+        header,
+        polls: 0,
+        poll_ticks: 0,
+        longest_poll_ticks: 0,
+    };
+    REGISTRY.lock().insert(id, entry);
+}
+
+pub(super) fn unregister(id: TaskId) {
+    REGISTRY.lock().remove(&id);
+}
+
+/// Adds a poll that took `ticks` to the statistics of task `id`.
+pub(super) fn record_poll(id: TaskId, ticks: u64) {
+    let name = {
+        let mut registry = REGISTRY.lock();
+        let Some(entry) = registry.get_mut(&id) else {
+            return;
+        };
+        entry.polls += 1;
+        entry.poll_ticks += ticks;
+        entry.longest_poll_ticks = entry.longest_poll_ticks.max(ticks);
+        entry.name
+    };
+    if ticks >= SLOW_POLL_TICKS {
+        serial_println!(
+            "WARNING: task {} ({}) blocked the executor for {} ticks in one poll",
+            id.as_u64(),
+            name.unwrap_or("unnamed"),
+            ticks
+        );
+    }
+}
+
+/// Returns a snapshot of all tasks of all executors, ordered by id.
+pub fn tasks() -> Vec<TaskInfo> {
+    let registry = REGISTRY.lock();
+    registry.iter().map(|(&id, entry)| entry.info(id)).collect()
+}
+
+/// Returns a snapshot of task `id`, if it exists.
+pub fn task(id: TaskId) -> Option<TaskInfo> {
+    REGISTRY.lock().get(&id).map(|entry| entry.info(id))
+}
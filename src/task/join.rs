@@ -0,0 +1,228 @@
+//! Handles for awaiting the output of spawned tasks and for cancelling them.
+
+use super::{Task, TaskId};
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// Why a task did not produce an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted, or dropped along with its executor.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+enum State<T> {
+    /// The task has not finished yet; holds the waker of the joining task.
+    Running(Option<Waker>),
+    Finished(Result<T, JoinError>),
+    /// The output was already taken by the handle.
+    Taken,
+}
+
+/// State shared between a task and its `JoinHandle`.
+struct Packet<T> {
+    state: Mutex<State<T>>,
+}
+
+impl<T> Packet<T> {
+    fn complete(&self, output: Result<T, JoinError>) {
+        let waker = {
+            let mut state = self.state.lock();
+            match &mut *state {
+                State::Running(waker) => {
+                    let waker = waker.take();
+                    *state = State::Finished(output);
+                    waker
+                }
+                _ => None,
+            }
+        };
+        // wake outside of the lock, the joiner might run right away
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Completes the packet as cancelled if the task's future is dropped before
+/// it finished.
+struct Completion<T> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        self.packet.complete(Err(JoinError::Cancelled));
+    }
+}
+
+/// Abort request shared between a task and its `AbortHandle`s.
+pub(super) struct AbortState {
+    aborted: AtomicBool,
+    /// Waker of the task, registered by the executor before its first poll.
+    waker: AtomicWaker,
+}
+
+impl AbortState {
+    pub(super) fn register(&self, waker: &Waker) {
+        self.waker.register(waker);
+    }
+
+    pub(super) fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+}
+
+/// A handle that cancels a spawned task.
+///
+/// Aborting only sets a flag and wakes the task, so it is safe from inside
+/// other tasks and from interrupt handlers. The executor then drops the
+/// task's future instead of polling it, and any joiner gets
+/// `JoinError::Cancelled`. Aborting a finished task has no effect.
+#[derive(Clone)]
+pub struct AbortHandle {
+    id: TaskId,
+    state: Arc<AbortState>,
+}
+
+impl AbortHandle {
+    /// Returns the id of the task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Requests cancellation of the task.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.waker.wake();
+    }
+}
+
+impl fmt::Debug for AbortHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AbortHandle").field("id", &self.id).finish()
+    }
+}
+
+/// An owned permission to await the output of a spawned task.
+///
+/// The handle is a future that resolves to the task's output, or to
+/// `JoinError::Cancelled` if the task was aborted. Dropping it detaches the
+/// task: it keeps running and its output is discarded.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    packet: Arc<Packet<T>>,
+    abort: Arc<AbortState>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the id of the task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Requests cancellation of the task, see `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.abort_handle().abort();
+    }
+
+    /// Returns a handle that can cancel the task without joining it.
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle {
+            id: self.id,
+            state: self.abort.clone(),
+        }
+    }
+
+    /// Returns whether the task has finished running or was cancelled.
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.packet.state.lock(), State::Running(_))
+    }
+
+    /// Takes the output of the task if it has finished, without waiting.
+    ///
+    /// Panics if the output was already taken.
+    pub fn try_join(&mut self) -> Option<Result<T, JoinError>> {
+        let mut state = self.packet.state.lock();
+        match core::mem::replace(&mut *state, State::Taken) {
+            State::Finished(output) => Some(output),
+            State::Taken => panic!("JoinHandle polled after completion"),
+            running => {
+                *state = running;
+                None
+            }
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.packet.state.lock();
+        match core::mem::replace(&mut *state, State::Taken) {
+            State::Running(_) => {
+                *state = State::Running(Some(context.waker().clone()));
+                Poll::Pending
+            }
+            State::Finished(output) => Poll::Ready(output),
+            State::Taken => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("id", &self.id)
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+async fn run_and_complete<F: Future>(future: F, completion: Completion<F::Output>) {
+    let output = future.await;
+    completion.packet.complete(Ok(output));
+}
+
+impl Task {
+    /// Creates a task that runs `future` and a handle to await its output.
+    pub fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let packet = Arc::new(Packet {
+            state: Mutex::new(State::Running(None)),
+        });
+        let abort = Arc::new(AbortState {
+            aborted: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+        let completion = Completion {
+            packet: packet.clone(),
+        };
+        let mut task = Task::new(run_and_complete(future, completion));
+        task.abort = Some(abort.clone());
+        let handle = JoinHandle {
+            id: task.id,
+            packet,
+            abort,
+        };
+        (task, handle)
+    }
+}
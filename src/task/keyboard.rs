@@ -1,40 +1,78 @@
-use crate::{exit_qemu, print, println, QemuExitCode};
+use super::channel::{mpsc, TrySendError};
+use super::timer::{interval, sleep};
+use crate::{exit_qemu, print, println, select, QemuExitCode};
 use conquer_once::spin::OnceCell;
 use core::pin::Pin;
 use core::task::{Context, Poll};
+use core::time::Duration;
+use spin::Mutex;
 
-use crossbeam_queue::ArrayQueue;
-use futures_util::task::AtomicWaker;
 use futures_util::{Stream, StreamExt};
+use lazy_static::lazy_static;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
 
-static WAKER: AtomicWaker = AtomicWaker::new();
+/// Number of scancodes buffered until a task reads them.
+const SCANCODE_CAPACITY: usize = 100;
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+struct Scancodes {
+    sender: mpsc::Sender<u8>,
+    receiver: Mutex<mpsc::Receiver<u8>>,
+}
+
+static SCANCODES: OnceCell<Scancodes> = OnceCell::uninit();
 
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
+    if let Ok(scancodes) = SCANCODES.try_get() {
+        if let Err(TrySendError::Full(_)) = scancodes.sender.try_send(scancode) {
             println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
         }
     } else {
         println!("WARNING: scancode queue uninitialized");
     }
 }
 
+lazy_static! {
+    /// Decoder state for keys read with `try_read_key`.
+    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
+        Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore)
+    );
+}
+
+/// Decodes the scancodes waiting in the queue up to the next character,
+/// without blocking.
+///
+/// For the `read_key` system call, which runs while the executor is stopped.
+/// Returns `None` if no character is waiting or no `ScancodeStream` exists.
+pub(crate) fn try_read_key() -> Option<char> {
+    let mut receiver = SCANCODES.try_get().ok()?.receiver.lock();
+    let mut keyboard = KEYBOARD.lock();
+    while let Ok(scancode) = receiver.try_recv() {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(DecodedKey::Unicode(character)) = keyboard.process_keyevent(key_event) {
+                return Some(character);
+            }
+        }
+    }
+    None
+}
+
 pub struct ScancodeStream {
     _private: (),
 }
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
+        SCANCODES
+            .try_init_once(|| {
+                let (sender, receiver) = mpsc::channel(SCANCODE_CAPACITY);
+                Scancodes {
+                    sender,
+                    receiver: Mutex::new(receiver),
+                }
+            })
             .expect("ScancodeStream::new should only be called once");
         ScancodeStream { _private: () }
     }
@@ -44,26 +82,30 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
-
-        // fast path
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
+        let scancodes = SCANCODES.try_get().expect("scancode queue not initialized");
+        scancodes.receiver.lock().poll_recv(cx)
+    }
+}
 
-        WAKER.register(&cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
+/// Reads scancodes until they decode to a key, or returns `None` if the
+/// stream ends.
+pub async fn next_key(
+    scancodes: &mut ScancodeStream,
+    keyboard: &mut Keyboard<layouts::Us104Key, ScancodeSet1>,
+) -> Option<DecodedKey> {
+    while let Some(scancode) = scancodes.next().await {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                return Some(key);
             }
-            None => Poll::Pending,
         }
     }
+    None
 }
 
+/// How long `print_keypresses` waits for the first key.
+const FIRST_KEY_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub async fn print_keypresses() {
     let mut scancodes = ScancodeStream::new();
     let mut keyboard = Keyboard::new(
@@ -72,18 +114,38 @@ pub async fn print_keypresses() {
         HandleControl::Ignore,
     );
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => {
-                        if keyboard.get_modifiers().lalt && key == KeyCode::F4 {
-                            print!("Exiting Qemu");
-                            exit_qemu(QemuExitCode::Success)
-                        }
-                    }
+    println!("Press a key within {} seconds", FIRST_KEY_TIMEOUT.as_secs());
+    let mut deadline = sleep(FIRST_KEY_TIMEOUT);
+    let mut countdown = interval(Duration::from_secs(1));
+    loop {
+        select! {
+            key = next_key(&mut scancodes, &mut keyboard) => match key {
+                Some(key) => {
+                    print_key(&keyboard, key);
+                    break;
                 }
+                None => return,
+            },
+            () = &mut deadline => {
+                println!("No key pressed, still listening");
+                break;
+            },
+            _ = countdown.tick() => print!("."),
+        }
+    }
+
+    while let Some(key) = next_key(&mut scancodes, &mut keyboard).await {
+        print_key(&keyboard, key);
+    }
+}
+
+fn print_key(keyboard: &Keyboard<layouts::Us104Key, ScancodeSet1>, key: DecodedKey) {
+    match key {
+        DecodedKey::Unicode(character) => print!("{}", character),
+        DecodedKey::RawKey(key) => {
+            if keyboard.get_modifiers().lalt && key == KeyCode::F4 {
+                print!("Exiting Qemu");
+                exit_qemu(QemuExitCode::Success)
             }
         }
     }
@@ -1,17 +1,52 @@
-use alloc::boxed::Box;
+use crate::allocator::fallible::{try_box, AllocError};
+use crate::smp;
+use alloc::{boxed::Box, sync::Arc};
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 
+pub mod channel;
+pub mod combinator;
 pub mod executor;
+mod join;
 pub mod keyboard;
 pub mod simple_executor;
-pub mod sleep;
+pub mod sync;
+pub mod timer;
+
+pub use join::{AbortHandle, JoinError, JoinHandle};
+
+/// How urgently a task is polled relative to other ready tasks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    /// Work deferred from interrupt handlers, such as decoding scancodes.
+    BottomHalf,
+    /// Tasks a user is waiting on, such as the shell.
+    #[default]
+    Interactive,
+    /// Long running work, such as rendering text.
+    Background,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
 
 pub struct Task {
     id: TaskId, // new
 
     future: Pin<Box<dyn Future<Output = ()>>>,
+    /// Set for tasks that can be cancelled through an `AbortHandle`.
+    abort: Option<Arc<join::AbortState>>,
+    name: Option<&'static str>,
+    priority: Priority,
+    /// Executor round in which `round_polls` was counted.
+    round: u64,
+    round_polls: u32,
 }
 
 impl Task {
@@ -19,20 +54,111 @@ impl Task {
         Task {
             id: TaskId::new(), // new
             future: Box::pin(future),
+            abort: None,
+            name: None,
+            priority: Priority::default(),
+            round: 0,
+            round_polls: 0,
         }
     }
 
+    /// Like `new`, but fails instead of aborting if the future cannot be
+    /// moved to the heap.
+    pub fn try_new(future: impl Future<Output = ()> + 'static) -> Result<Task, AllocError> {
+        let future: Box<dyn Future<Output = ()>> = try_box(future)?;
+        Ok(Task {
+            id: TaskId::new(),
+            future: Box::into_pin(future),
+            abort: None,
+            name: None,
+            priority: Priority::default(),
+            round: 0,
+            round_polls: 0,
+        })
+    }
+
+    /// Names the task in the registry, see `executor::registry`.
+    pub fn with_name(mut self, name: &'static str) -> Task {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Sets the priority the task is scheduled with.
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let current_task = &smp::current().current_task;
+        current_task.store(self.id.0, Ordering::Relaxed);
+        let poll = self.future.as_mut().poll(context);
+        current_task.store(NO_TASK, Ordering::Relaxed);
+        poll
+    }
+}
+
+/// Spawns `future` onto the executor that is running the current task.
+///
+/// Outside of a task this uses the executor that ran tasks on this CPU last.
+/// Panics if no executor has run on this CPU yet.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    executor::current_spawner()
+        .expect("task::spawn called without a running executor")
+        .spawn(future)
+}
+
+/// Lets other ready tasks run before continuing.
+pub async fn yield_now() {
+    let mut yielded = false;
+    core::future::poll_fn(|context| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// Value of `PerCpu::current_task` outside of a poll.
+pub(crate) const NO_TASK: u64 = u64::MAX;
+
+/// Returns the id of the task currently being polled on this CPU, if any.
+pub fn current_task() -> Option<TaskId> {
+    match smp::current().current_task.load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some(TaskId(id)),
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
 }
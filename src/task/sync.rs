@@ -0,0 +1,187 @@
+//! Synchronization primitives for tasks.
+//!
+//! Waiting tasks are parked with their wakers instead of spinning, and are
+//! served in FIFO order. The primitives use short `spin` critical sections
+//! internally, so they must not be used from interrupt handlers.
+
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit};
+
+#[cfg(test)]
+use super::{executor::Executor, yield_now};
+#[cfg(test)]
+use alloc::{sync::Arc, vec::Vec};
+
+#[test_case]
+fn mutex_can_be_held_across_await() {
+    let counter = Arc::new(Mutex::new(0));
+    let mut executor = Executor::new();
+    let handles: Vec<_> = (0..10)
+        .map(|_| {
+            let counter = counter.clone();
+            executor.spawn(async move {
+                let mut value = counter.lock().await;
+                let read = *value;
+                yield_now().await;
+                *value = read + 1;
+            })
+        })
+        .collect();
+    executor.block_on(async move {
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    });
+    assert_eq!(Arc::try_unwrap(counter).ok().unwrap().into_inner(), 10);
+}
+
+#[test_case]
+fn mutex_is_handed_over_in_order() {
+    let lock = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    let guard = lock.try_lock().unwrap();
+    let handles: Vec<_> = (0..5)
+        .map(|n| {
+            let lock = lock.clone();
+            executor.spawn(async move { lock.lock().await.push(n) })
+        })
+        .collect();
+    // everyone is queued up behind the held lock
+    executor.block_on(yield_now());
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    executor.block_on(async move {
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    });
+    assert_eq!(*lock.try_lock().unwrap(), [0, 1, 2, 3, 4]);
+}
+
+#[test_case]
+fn rwlock_shares_readers_and_queues_writers() {
+    let lock = Arc::new(RwLock::new(0));
+    let mut executor = Executor::new();
+    let first = lock.try_read().unwrap();
+    let second = lock.try_read().unwrap();
+    let writer = {
+        let lock = lock.clone();
+        executor.spawn(async move { *lock.write().await = 1 })
+    };
+    executor.block_on(yield_now());
+    // a waiting writer holds back new readers
+    assert!(lock.try_read().is_none());
+    let reader = {
+        let lock = lock.clone();
+        executor.spawn(async move { *lock.read().await })
+    };
+    drop((first, second));
+    assert_eq!(executor.block_on(reader), Ok(1));
+    assert!(writer.is_finished());
+}
+
+#[test_case]
+fn semaphore_limits_concurrency() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    let semaphore = Arc::new(Semaphore::new(3));
+    let active = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    let handles: Vec<_> = (0..10)
+        .map(|_| {
+            let (semaphore, active, most) = (semaphore.clone(), active.clone(), most.clone());
+            executor.spawn(async move {
+                let _permit = semaphore.acquire().await;
+                let now = active.fetch_add(1, Ordering::Relaxed) + 1;
+                most.fetch_max(now, Ordering::Relaxed);
+                yield_now().await;
+                active.fetch_sub(1, Ordering::Relaxed);
+            })
+        })
+        .collect();
+    executor.block_on(async move {
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    });
+    assert_eq!(most.load(Ordering::Relaxed), 3);
+    assert_eq!(semaphore.available_permits(), 3);
+}
+
+#[test_case]
+fn semaphore_does_not_starve_large_requests() {
+    let semaphore = Arc::new(Semaphore::new(2));
+    let mut executor = Executor::new();
+    let held = semaphore.try_acquire().unwrap();
+    let large = {
+        let semaphore = semaphore.clone();
+        executor.spawn(async move { semaphore.acquire_many(2).await.forget() })
+    };
+    executor.block_on(yield_now());
+    // a permit is free, but the large request came first
+    assert!(semaphore.try_acquire().is_none());
+    drop(held);
+    executor.block_on(large).unwrap();
+    assert_eq!(semaphore.available_permits(), 0);
+}
+
+#[test_case]
+fn cancelled_acquire_leaves_queue() {
+    let semaphore = Arc::new(Semaphore::new(1));
+    let mut executor = Executor::new();
+    let held = semaphore.try_acquire().unwrap();
+    let waiting = {
+        let semaphore = semaphore.clone();
+        executor.spawn(async move { semaphore.acquire_many(2).await.forget() })
+    };
+    executor.block_on(yield_now());
+    waiting.abort();
+    assert_eq!(executor.block_on(waiting), Err(super::JoinError::Cancelled));
+    drop(held);
+    assert!(semaphore.try_acquire().is_some());
+}
+
+#[test_case]
+fn notify_one_stores_a_permit() {
+    let notify = Arc::new(Notify::new());
+    notify.notify_one();
+    let mut executor = Executor::new();
+    let waiter = notify.clone();
+    executor.block_on(async move { waiter.notified().await });
+}
+
+#[test_case]
+fn notify_wakes_waiters() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    let notify = Arc::new(Notify::new());
+    let woken = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..4 {
+        let (notify, woken) = (notify.clone(), woken.clone());
+        executor.spawn(async move {
+            notify.notified().await;
+            woken.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    executor.block_on(yield_now());
+    notify.notify_one();
+    executor.block_on(yield_now());
+    assert_eq!(woken.load(Ordering::Relaxed), 1);
+    notify.notify_waiters();
+    executor.block_on(yield_now());
+    assert_eq!(woken.load(Ordering::Relaxed), 4);
+    // notify_waiters does not store a permit
+    let late = notify.clone();
+    let late = executor.spawn(async move { late.notified().await });
+    executor.block_on(yield_now());
+    assert!(!late.is_finished());
+}
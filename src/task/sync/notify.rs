@@ -0,0 +1,145 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+/// Wakes tasks waiting for an event, without passing any data.
+///
+/// `notify_one` wakes the task that has waited the longest, or stores a
+/// single permit for the next `notified` if nobody waits. `notify_waiters`
+/// wakes everyone waiting at the time of the call.
+pub struct Notify {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    permit: bool,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+struct Waiter {
+    /// Set, with the state locked, when the waiter is removed by a notify.
+    notified: AtomicBool,
+    /// Whether it was `notify_one` that removed the waiter.
+    by_one: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: spin::Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Waits for a notification.
+    ///
+    /// The future joins the queue of waiters when first polled or enabled,
+    /// so only notifications after that (or a stored permit) complete it.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+
+    /// Wakes the longest waiting task, or lets the next `notified` complete
+    /// right away if none is waiting.
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Some(waiter) => {
+                waiter.by_one.store(true, Ordering::Relaxed);
+                waiter.notified.store(true, Ordering::Release);
+                drop(state);
+                waiter.waker.wake();
+            }
+            None => state.permit = true,
+        }
+    }
+
+    /// Wakes all tasks that are currently waiting.
+    pub fn notify_waiters(&self) {
+        let waiters = core::mem::take(&mut self.state.lock().waiters);
+        for waiter in &waiters {
+            waiter.notified.store(true, Ordering::Release);
+        }
+        for waiter in waiters {
+            waiter.waker.wake();
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+/// Future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl Notified<'_> {
+    /// Joins the queue of waiters without waiting yet, so notifications
+    /// from now on complete the future even before it is first polled.
+    pub fn enable(&mut self) {
+        if self.waiter.is_some() {
+            return;
+        }
+        let waiter = Arc::new(Waiter {
+            notified: AtomicBool::new(false),
+            by_one: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+        let mut state = self.notify.state.lock();
+        if state.permit {
+            state.permit = false;
+            waiter.by_one.store(true, Ordering::Relaxed);
+            waiter.notified.store(true, Ordering::Release);
+        } else {
+            state.waiters.push_back(waiter.clone());
+        }
+        self.waiter = Some(waiter);
+    }
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        self.enable();
+        let waiter = self.waiter.as_ref().unwrap();
+        waiter.waker.register(context.waker());
+        // registered before checking, so a notify in between still wakes us
+        if waiter.notified.load(Ordering::Acquire) {
+            self.waiter = None;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let Some(waiter) = self.waiter.take() else {
+            return;
+        };
+        let mut state = self.notify.state.lock();
+        if !waiter.notified.load(Ordering::Acquire) {
+            state.waiters.retain(|other| !Arc::ptr_eq(other, &waiter));
+        } else if waiter.by_one.load(Ordering::Relaxed) {
+            // don't lose a `notify_one` that was meant for this waiter
+            drop(state);
+            self.notify.notify_one();
+        }
+    }
+}
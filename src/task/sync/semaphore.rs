@@ -0,0 +1,187 @@
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+/// A counting semaphore for tasks.
+///
+/// Waiters are served strictly in the order they started waiting: a task
+/// asking for many permits blocks later tasks that ask for fewer, so it
+/// cannot be starved by them.
+pub struct Semaphore {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+struct Waiter {
+    needed: usize,
+    /// Set, with the state locked, once the permits were handed over.
+    granted: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Waits for a permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits until `permits` permits can be taken at once.
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            waiter: None,
+        }
+    }
+
+    /// Takes a permit if one is available and nobody is waiting.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Takes `permits` permits if they are available and nobody is waiting.
+    pub fn try_acquire_many(&self, permits: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.waiters.is_empty() && state.permits >= permits {
+            state.permits -= permits;
+            Some(SemaphorePermit {
+                semaphore: self,
+                permits,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Adds `permits` permits, waking the waiters that can now proceed.
+    pub fn add_permits(&self, permits: usize) {
+        let mut state = self.state.lock();
+        state.permits += permits;
+        let granted = grant(&mut state);
+        drop(state);
+        // wake outside of the lock, the waiters might run right away
+        for waiter in granted {
+            waiter.waker.wake();
+        }
+    }
+}
+
+/// Hands permits to waiters from the front of the queue while they last.
+fn grant(state: &mut State) -> Vec<Arc<Waiter>> {
+    let mut granted = Vec::new();
+    while let Some(waiter) = state.waiters.front() {
+        if waiter.needed > state.permits {
+            break;
+        }
+        state.permits -= waiter.needed;
+        waiter.granted.store(true, Ordering::Release);
+        granted.extend(state.waiters.pop_front());
+    }
+    granted
+}
+
+/// Permits taken from a `Semaphore`, given back on drop.
+#[must_use = "the permits are released right away if unused"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits taken instead of giving them back.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+/// Future returned by `Semaphore::acquire_many`.
+///
+/// Joins the queue of waiters when first polled. Dropping it leaves the
+/// queue again.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let this = &mut *self;
+        let mut state = this.semaphore.state.lock();
+        if let Some(waiter) = &this.waiter {
+            if !waiter.granted.load(Ordering::Acquire) {
+                waiter.waker.register(context.waker());
+                return Poll::Pending;
+            }
+            this.waiter = None;
+        } else if state.waiters.is_empty() && state.permits >= this.permits {
+            state.permits -= this.permits;
+        } else {
+            let waiter = Arc::new(Waiter {
+                needed: this.permits,
+                granted: AtomicBool::new(false),
+                waker: AtomicWaker::new(),
+            });
+            waiter.waker.register(context.waker());
+            state.waiters.push_back(waiter.clone());
+            this.waiter = Some(waiter);
+            return Poll::Pending;
+        }
+        drop(state);
+        Poll::Ready(SemaphorePermit {
+            semaphore: this.semaphore,
+            permits: this.permits,
+        })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(waiter) = self.waiter.take() else {
+            return;
+        };
+        let mut state = self.semaphore.state.lock();
+        if waiter.granted.load(Ordering::Acquire) {
+            // granted but never polled again, give the permits back
+            state.permits += waiter.needed;
+        } else {
+            state.waiters.retain(|other| !Arc::ptr_eq(other, &waiter));
+        }
+        // leaving the front of the queue can let the waiters behind us in
+        let granted = grant(&mut state);
+        drop(state);
+        for waiter in granted {
+            waiter.waker.wake();
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Sleeping, timeouts and intervals for tasks.
+//!
+//! Deadlines are in timer ticks (see `time::ticks`), so they have the
+//! resolution of one tick. Expired timers are fired by the executor on each
+//! loop iteration, which the timer interrupt triggers while it is idle.
+
+use crate::time::{self, duration_to_ticks};
+use alloc::collections::BTreeMap;
+use core::fmt;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::Stream;
+use spin::Mutex;
+
+/// Wakers of pending `Sleep`s by deadline and timer id.
+static TIMERS: Mutex<BTreeMap<(u64, u64), Waker>> = Mutex::new(BTreeMap::new());
+
+/// Wakes the tasks whose deadline has passed.
+///
+/// Called by the executor, must not be called from interrupt handlers.
+pub(crate) fn fire_expired() {
+    let now = time::ticks();
+    let expired = {
+        let mut timers = TIMERS.lock();
+        match timers.first_key_value() {
+            Some((&(deadline, _), _)) if deadline <= now => {}
+            _ => return,
+        }
+        let pending = timers.split_off(&(now + 1, 0));
+        core::mem::replace(&mut *timers, pending)
+    };
+    for waker in expired.into_values() {
+        waker.wake();
+    }
+}
+
+/// Waits for at least `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::ticks() + duration_to_ticks(duration))
+}
+
+/// Waits until `time::ticks` reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+/// Future returned by `sleep` and `sleep_until`.
+pub struct Sleep {
+    deadline: u64,
+    id: u64,
+    /// Whether a waker is in `TIMERS`.
+    registered: bool,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        time::ticks() >= self.deadline
+    }
+
+    /// Changes the deadline, also after the sleep completed.
+    pub fn reset(&mut self, deadline: u64) {
+        self.unregister();
+        self.deadline = deadline;
+    }
+
+    fn unregister(&mut self) {
+        if self.registered {
+            TIMERS.lock().remove(&(self.deadline, self.id));
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.is_elapsed() {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        let key = (self.deadline, self.id);
+        TIMERS.lock().insert(key, context.waker().clone());
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// The deadline of a `timeout` passed before the future completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+/// Runs `future` for at most `duration`.
+///
+/// The future is dropped if it did not complete in time.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// Future returned by `timeout`.
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        // Safety: `future` is never moved out of the pinned `Timeout`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(context) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(context) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// What an `Interval` does when ticks were missed because the task was not
+/// polled in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissedTickBehavior {
+    /// Fires the missed ticks right away, one after another, to catch up.
+    #[default]
+    Burst,
+    /// Fires once and starts the period over from now.
+    Delay,
+    /// Fires once and drops the missed ticks, staying on the original
+    /// schedule.
+    Skip,
+}
+
+impl MissedTickBehavior {
+    /// Returns the deadline after a tick scheduled for `scheduled` fired at
+    /// `now`.
+    fn next(self, scheduled: u64, now: u64, period: u64) -> u64 {
+        match self {
+            MissedTickBehavior::Burst => scheduled + period,
+            MissedTickBehavior::Delay => now + period,
+            MissedTickBehavior::Skip => now + period - (now - scheduled) % period,
+        }
+    }
+}
+
+/// Creates an `Interval` that fires right away and then every `period`.
+///
+/// Panics if `period` is shorter than one tick.
+pub fn interval(period: Duration) -> Interval {
+    let period = duration_to_ticks(period);
+    assert!(period > 0, "interval period must not be zero");
+    Interval {
+        period,
+        sleep: sleep_until(time::ticks()),
+        missed_tick_behavior: MissedTickBehavior::default(),
+    }
+}
+
+/// Fires at a fixed period, see `interval`.
+///
+/// Also a `Stream` of the ticks at which it was scheduled to fire.
+pub struct Interval {
+    /// In ticks.
+    period: u64,
+    sleep: Sleep,
+    missed_tick_behavior: MissedTickBehavior,
+}
+
+impl Interval {
+    pub fn set_missed_tick_behavior(&mut self, behavior: MissedTickBehavior) {
+        self.missed_tick_behavior = behavior;
+    }
+
+    /// Waits for the next tick and returns the tick it was scheduled for.
+    pub async fn tick(&mut self) -> u64 {
+        poll_fn(|context| self.poll_tick(context)).await
+    }
+
+    pub fn poll_tick(&mut self, context: &mut Context) -> Poll<u64> {
+        if Pin::new(&mut self.sleep).poll(context).is_pending() {
+            return Poll::Pending;
+        }
+        let scheduled = self.sleep.deadline();
+        let next = self
+            .missed_tick_behavior
+            .next(scheduled, time::ticks(), self.period);
+        self.sleep.reset(next);
+        Poll::Ready(scheduled)
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u64>> {
+        self.get_mut().poll_tick(context).map(Some)
+    }
+}
+
+#[cfg(test)]
+use super::{executor::Executor, yield_now};
+
+#[test_case]
+fn sleep_waits_for_deadline() {
+    let mut executor = Executor::new();
+    let (start, end) = executor.block_on(async {
+        let start = time::ticks();
+        sleep(time::ticks_to_duration(2)).await;
+        (start, time::ticks())
+    });
+    assert!(end >= start + 2);
+}
+
+#[test_case]
+fn timeout_elapses() {
+    let mut executor = Executor::new();
+    let result = executor.block_on(timeout(
+        time::ticks_to_duration(1),
+        core::future::pending::<()>(),
+    ));
+    assert_eq!(result, Err(Elapsed));
+}
+
+#[test_case]
+fn timeout_returns_output_and_drops_timer() {
+    let mut executor = Executor::new();
+    let result = executor.block_on(timeout(Duration::from_secs(10), async {
+        yield_now().await;
+        5
+    }));
+    assert_eq!(result, Ok(5));
+    assert!(TIMERS.lock().is_empty());
+}
+
+#[test_case]
+fn missed_ticks() {
+    use MissedTickBehavior::*;
+
+    // scheduled for tick 10 with a period of 4, but fired at tick 19
+    assert_eq!(Burst.next(10, 19, 4), 14);
+    assert_eq!(Delay.next(10, 19, 4), 23);
+    assert_eq!(Skip.next(10, 19, 4), 22);
+    // on time
+    assert_eq!(Burst.next(10, 10, 4), 14);
+    assert_eq!(Delay.next(10, 10, 4), 14);
+    assert_eq!(Skip.next(10, 10, 4), 14);
+}
+
+#[test_case]
+fn interval_fires_every_period() {
+    use futures_util::StreamExt;
+
+    let mut executor = Executor::new();
+    let ticks = executor.block_on(async {
+        let mut interval = interval(time::ticks_to_duration(1));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let first = interval.tick().await;
+        let second = interval.tick().await;
+        let third = interval.next().await.unwrap();
+        [first, second, third]
+    });
+    assert!(ticks[0] < ticks[1] && ticks[1] < ticks[2]);
+}
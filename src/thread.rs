@@ -0,0 +1,556 @@
+//! Preemptive kernel threads.
+//!
+//! `init` turns the code calling it into the "main" thread, `spawn` starts
+//! more, each on its own guard-paged stack. Ready threads run in round-robin
+//! order: the timer interrupt counts down the running thread's time slice
+//! and switches to the next one once it is used up. Threads can also give up
+//! the CPU early with `yield_now` or by blocking in `JoinHandle::join`.
+//!
+//! A switch pushes the callee-saved registers and flags onto the old
+//! thread's stack and pops the new thread's from its own. When it happens in
+//! the timer interrupt, the interrupt frame and the registers saved by the
+//! handler stay on the preempted thread's stack until it is resumed and the
+//! handler returns.
+//!
+//! The scheduler is only touched with interrupts disabled, so it must never
+//! allocate or take other locks: a preempted thread might hold them. Finished
+//! threads are therefore freed later, by `reap` with interrupts enabled.
+//!
+//! Each thread also keeps its own page table and TSS privilege stack, which
+//! `schedule` switches along with the stack. So a thread running a user
+//! program is preempted like any other: the program's interrupts and system
+//! calls run on the thread's own stack, see `usermode`.
+//!
+//! There is a single scheduler, on the bootstrap processor. Spawning,
+//! yielding and joining panic on the other CPUs.
+
+use crate::gdt;
+use crate::memory::address_space;
+use crate::memory::stack::{self, StackInfo};
+use crate::memory::vmm::VmError;
+use crate::{smp, time};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use arrayvec::ArrayVec;
+use core::arch::naked_asm;
+use core::fmt;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+/// Maximum number of threads, including the main and idle threads.
+pub const MAX_THREADS: usize = 64;
+/// Timer interrupts a thread may run before it is preempted.
+pub const TIME_SLICE_TICKS: u64 = 2;
+/// Size in pages of the stack of a spawned thread.
+pub const STACK_PAGES: u64 = 16;
+
+/// Flags a new thread starts with: interrupts disabled, reserved bit 1 set.
+/// `thread_start` enables interrupts.
+const INITIAL_RFLAGS: u64 = 0x2;
+
+type Entry = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    Running,
+    /// Waiting in `join`.
+    Blocked,
+    /// Waiting in `sleep` for the tick in `wake_at`.
+    Sleeping,
+    /// Returned, waiting for `reap` to free the stack.
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// All `MAX_THREADS` threads are in use.
+    TooManyThreads,
+    Stack(VmError),
+}
+
+impl From<VmError> for SpawnError {
+    fn from(error: VmError) -> Self {
+        SpawnError::Stack(error)
+    }
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::TooManyThreads => write!(f, "too many threads"),
+            SpawnError::Stack(error) => write!(f, "failed to allocate stack: {}", error),
+        }
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    state: State,
+    /// `None` for the main thread, which keeps the stack it was started on.
+    stack: Option<StackInfo>,
+    /// Stack pointer saved by `switch_stacks` while the thread is not running.
+    rsp: u64,
+    /// `gdt::privilege_stack` while the thread is not running.
+    privilege_stack: VirtAddr,
+    /// `address_space::active` while the thread is not running.
+    page_table: (PhysFrame, u16),
+    /// Thread blocked in `join` on this one.
+    joiner: Option<ThreadId>,
+    /// `time::ticks` at which a sleeping thread is woken.
+    wake_at: u64,
+}
+
+struct Scheduler {
+    /// Boxed so that `rsp` stays in place while `switch_stacks` writes it.
+    threads: ArrayVec<Box<Thread>, MAX_THREADS>,
+    /// Runnable threads in round-robin order, without the running one and
+    /// the idle thread.
+    ready: ArrayQueue<ThreadId>,
+    current: ThreadId,
+    /// Runs when no other thread is ready.
+    idle: ThreadId,
+    /// Timer interrupts left in the current time slice.
+    slice: u64,
+}
+
+impl Scheduler {
+    fn thread_mut(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads
+            .iter_mut()
+            .find(|thread| thread.id == id)
+            .expect("unknown thread")
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        self.thread_mut(id).state = State::Ready;
+        // every thread is queued at most once
+        self.ready.push(id).expect("ready queue full");
+    }
+}
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// Starts scheduling, with the calling code as the main thread.
+///
+/// Must be called once, after the heap and `memory::vmm` are set up.
+pub fn init() -> Result<(), SpawnError> {
+    let main = Box::new(Thread {
+        id: ThreadId::new(),
+        state: State::Running,
+        stack: None,
+        rsp: 0,
+        privilege_stack: gdt::privilege_stack(),
+        page_table: address_space::active(),
+        joiner: None,
+        wake_at: 0,
+    });
+    let idle = new_thread(
+        "idle",
+        Box::new(|| loop {
+            interrupts::enable_and_hlt();
+        }),
+    )?;
+
+    let scheduler = Scheduler {
+        current: main.id,
+        idle: idle.id,
+        threads: [main, idle].into_iter().collect(),
+        ready: ArrayQueue::new(MAX_THREADS),
+        slice: TIME_SLICE_TICKS,
+    };
+    interrupts::without_interrupts(|| {
+        let mut current = SCHEDULER.lock();
+        assert!(current.is_none(), "thread::init called twice");
+        *current = Some(scheduler);
+    });
+    Ok(())
+}
+
+/// Returns the id of the running thread, `None` before `init`.
+pub fn current() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().as_ref().map(|s| s.current))
+}
+
+/// Starts a thread running `f`.
+///
+/// Panics if the thread cannot be created, see `try_spawn`.
+pub fn spawn<F, T>(name: &'static str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    try_spawn(name, f).expect("failed to spawn thread")
+}
+
+/// Like `spawn`, but fails instead of panicking if there are too many threads
+/// or no stack can be allocated.
+///
+/// `name` names the thread's stack, e.g. in stack overflow reports.
+pub fn try_spawn<F, T>(name: &'static str, f: F) -> Result<JoinHandle<T>, SpawnError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    assert_on_bsp();
+    reap();
+    let packet = Arc::new(Mutex::new(None));
+    let result = packet.clone();
+    let thread = new_thread(
+        name,
+        Box::new(move || {
+            let value = f();
+            *result.lock() = Some(value);
+        }),
+    )?;
+    let id = thread.id;
+
+    let rejected = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("thread::init not called");
+        match scheduler.threads.try_push(thread) {
+            Ok(()) => {
+                scheduler.make_ready(id);
+                None
+            }
+            Err(error) => Some(error.element()),
+        }
+    });
+    if let Some(thread) = rejected {
+        unsafe { discard_unstarted(*thread) };
+        return Err(SpawnError::TooManyThreads);
+    }
+    Ok(JoinHandle { id, packet })
+}
+
+/// Lets the next ready thread run, if there is one.
+pub fn yield_now() {
+    assert_on_bsp();
+    interrupts::without_interrupts(|| unsafe { schedule() });
+}
+
+/// Lets another thread run if one is ready, otherwise waits for the next
+/// interrupt.
+///
+/// For idle loops like the executor's: must be called with interrupts
+/// disabled, so that checking for work and halting is atomic, and returns
+/// with interrupts enabled. Before `init`, only halts.
+pub fn idle() {
+    assert_on_bsp();
+    let others_ready = SCHEDULER
+        .lock()
+        .as_ref()
+        .is_some_and(|scheduler| !scheduler.ready.is_empty());
+    if others_ready {
+        unsafe { schedule() };
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Blocks the current thread for at least `duration`, letting other threads
+/// run in the meantime.
+///
+/// Before `init`, halts the CPU like `time::busy_sleep`.
+pub fn sleep(duration: Duration) {
+    if current().is_none() {
+        return time::busy_sleep(duration);
+    }
+    assert_on_bsp();
+    let wake_at = time::ticks() + time::duration_to_ticks(duration);
+    interrupts::without_interrupts(|| {
+        if wake_at <= time::ticks() {
+            return;
+        }
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut().expect("thread::init not called");
+            let current = scheduler.current;
+            let thread = scheduler.thread_mut(current);
+            thread.state = State::Sleeping;
+            thread.wake_at = wake_at;
+        }
+        unsafe { schedule() };
+    });
+}
+
+/// Wakes the sleeping threads that are due, counts down the time slice and
+/// switches threads once it is used up.
+///
+/// Called by the timer interrupt handler, after the end of interrupt and
+/// `time::tick`.
+pub(crate) fn tick() {
+    let preempt = {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else {
+            return;
+        };
+        let now = time::ticks();
+        for thread in scheduler.threads.iter_mut() {
+            if thread.state == State::Sleeping && thread.wake_at <= now {
+                thread.state = State::Ready;
+                // every thread is queued at most once
+                scheduler.ready.push(thread.id).expect("ready queue full");
+            }
+        }
+        scheduler.slice = scheduler.slice.saturating_sub(1);
+        let expired = scheduler.slice == 0 || scheduler.current == scheduler.idle;
+        expired && !scheduler.ready.is_empty()
+    };
+    if preempt {
+        unsafe { schedule() };
+    }
+}
+
+/// A spawned thread, to wait for its result.
+///
+/// Dropping the handle detaches the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    packet: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Returns whether the thread has returned.
+    pub fn is_finished(&self) -> bool {
+        self.packet.lock().is_some()
+    }
+
+    /// Blocks the calling thread until the thread returns, and returns its
+    /// result.
+    pub fn join(self) -> T {
+        loop {
+            if let Some(result) = self.packet.lock().take() {
+                reap();
+                return result;
+            }
+            wait_for(self.id);
+        }
+    }
+}
+
+/// Blocks the current thread until `id` finishes.
+fn wait_for(id: ThreadId) {
+    assert_on_bsp();
+    interrupts::without_interrupts(|| {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut().expect("thread::init not called");
+            let current = scheduler.current;
+            match scheduler.threads.iter_mut().find(|thread| thread.id == id) {
+                Some(thread) if thread.state != State::Finished => thread.joiner = Some(current),
+                _ => return,
+            }
+            scheduler.thread_mut(current).state = State::Blocked;
+        }
+        unsafe { schedule() };
+    });
+}
+
+fn assert_on_bsp() {
+    assert_eq!(
+        smp::current().index(),
+        0,
+        "threads only run on the bootstrap processor"
+    );
+}
+
+/// Frees the stacks of finished threads.
+///
+/// Freeing takes the VMM and allocator locks, which a preempted thread may
+/// hold, so this must run with interrupts enabled.
+fn reap() {
+    loop {
+        let finished = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut()?;
+            let current = scheduler.current;
+            let index = scheduler
+                .threads
+                .iter()
+                .position(|thread| thread.state == State::Finished && thread.id != current)?;
+            Some(scheduler.threads.swap_remove(index))
+        });
+        let Some(thread) = finished else {
+            break;
+        };
+        if let Some(stack) = thread.stack {
+            unsafe { stack::free_stack(stack) };
+        }
+    }
+}
+
+/// Creates a thread that calls `entry` once it is switched to.
+fn new_thread(name: &'static str, entry: Entry) -> Result<Box<Thread>, VmError> {
+    let stack = stack::allocate_stack(name, STACK_PAGES)?;
+    let entry = Box::into_raw(Box::new(entry));
+
+    // popped by `switch_stacks`: flags, r15, r14, r13, r12, rbx, rbp, return
+    // address, leaving the stack 16-byte aligned for `thread_trampoline`
+    let frame: [u64; 8] = [
+        INITIAL_RFLAGS,
+        0,
+        0,
+        0,
+        entry as u64,
+        0,
+        0,
+        thread_trampoline as *const () as u64,
+    ];
+    let rsp = stack.top - core::mem::size_of_val(&frame) as u64;
+    unsafe { rsp.as_mut_ptr::<[u64; 8]>().write(frame) };
+
+    Ok(Box::new(Thread {
+        id: ThreadId::new(),
+        state: State::Ready,
+        stack: Some(stack),
+        rsp: rsp.as_u64(),
+        privilege_stack: VirtAddr::zero(),
+        page_table: address_space::kernel_active(),
+        joiner: None,
+        wake_at: 0,
+    }))
+}
+
+/// Frees a thread created by `new_thread` that never ran.
+unsafe fn discard_unstarted(thread: Thread) {
+    let frame = (thread.rsp as *const [u64; 8]).read();
+    drop(Box::from_raw(frame[4] as *mut Entry));
+    if let Some(stack) = thread.stack {
+        stack::free_stack(stack);
+    }
+}
+
+/// Switches from the current thread to the next ready one, or to the idle
+/// thread if the current one cannot continue. Keeps running the current
+/// thread if it can and no other thread is ready.
+///
+/// ## Safety
+///
+/// Interrupts must be disabled.
+unsafe fn schedule() {
+    let (old_rsp, new_rsp) = {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else {
+            return;
+        };
+        let current = scheduler.current;
+        let running = scheduler.thread_mut(current).state == State::Running;
+        let next = match scheduler.ready.pop() {
+            Some(next) => next,
+            None if running => {
+                scheduler.slice = TIME_SLICE_TICKS;
+                return;
+            }
+            None => scheduler.idle,
+        };
+
+        if running && current == scheduler.idle {
+            scheduler.thread_mut(current).state = State::Ready;
+        } else if running {
+            scheduler.make_ready(current);
+        }
+        scheduler.current = next;
+        scheduler.slice = TIME_SLICE_TICKS;
+        let next = scheduler.thread_mut(next);
+        next.state = State::Running;
+        let (new_rsp, privilege_stack, page_table) =
+            (next.rsp, next.privilege_stack, next.page_table);
+        let old = scheduler.thread_mut(current);
+        old.privilege_stack = gdt::privilege_stack();
+        old.page_table = address_space::active();
+        gdt::set_privilege_stack(privilege_stack);
+        address_space::restore(page_table);
+        (addr_of_mut!(old.rsp), new_rsp)
+    };
+    switch_stacks(old_rsp, new_rsp);
+}
+
+/// Saves the callee-saved registers and flags on the current stack and its
+/// stack pointer to `old_rsp`, then restores them from the stack at
+/// `new_rsp` and returns there.
+#[unsafe(naked)]
+unsafe extern "C" fn switch_stacks(old_rsp: *mut u64, new_rsp: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "pushfq",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "popfq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+/// First code of a new thread, with its entry in `r12`.
+#[unsafe(naked)]
+unsafe extern "C" fn thread_trampoline() -> ! {
+    naked_asm!(
+        "mov rdi, r12",
+        "xor ebp, ebp",
+        "call {start}",
+        "ud2",
+        start = sym thread_start,
+    )
+}
+
+extern "C" fn thread_start(entry: *mut Entry) -> ! {
+    // freeing the box takes the allocator lock, which the preempted thread
+    // may hold
+    interrupts::enable();
+    let entry = unsafe { *Box::from_raw(entry) };
+    entry();
+    finish()
+}
+
+/// Ends the current thread and wakes its joiner.
+fn finish() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("thread::init not called");
+        let current = scheduler.current;
+        let thread = scheduler.thread_mut(current);
+        thread.state = State::Finished;
+        if let Some(joiner) = thread.joiner.take() {
+            scheduler.make_ready(joiner);
+        }
+    }
+    unsafe { schedule() };
+    unreachable!("finished thread was resumed");
+}
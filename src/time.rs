@@ -0,0 +1,61 @@
+//! Uptime, counted in timer interrupts.
+//!
+//! The PIT is left at the rate the firmware programs it to: the largest
+//! divisor, about 18.2 interrupts per second.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Input clock of the programmable interval timer, in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// Divisor of PIT channel 0, 0 meaning 65536.
+const PIT_DIVISOR: u64 = 65_536;
+
+/// Timer interrupts since `init`.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of timer interrupts so far.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Converts a number of ticks to the time they take.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos =
+        u128::from(ticks) * u128::from(PIT_DIVISOR) * 1_000_000_000 / u128::from(PIT_FREQUENCY);
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Converts a duration to the number of ticks that cover it, rounding up.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let period = u128::from(PIT_DIVISOR) * 1_000_000_000;
+    let ticks = (duration.as_nanos() * u128::from(PIT_FREQUENCY)).div_ceil(period);
+    ticks as u64
+}
+
+/// Returns the time since interrupts were enabled, with a resolution of one
+/// tick (about 55 ms).
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// Waits for at least `duration` with interrupts enabled.
+///
+/// Halts the CPU in between, so it must not be used from async tasks.
+pub fn busy_sleep(duration: Duration) {
+    use x86_64::instructions::interrupts;
+
+    let deadline = ticks() + duration_to_ticks(duration);
+    let were_enabled = interrupts::are_enabled();
+    while ticks() < deadline {
+        interrupts::enable_and_hlt();
+    }
+    if !were_enabled {
+        interrupts::disable();
+    }
+}
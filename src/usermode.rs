@@ -0,0 +1,242 @@
+//! Running code in user mode (ring 3).
+//!
+//! `run` saves the kernel's callee-saved registers and stack pointer, then
+//! `iretq`s to the program's entry point. The program runs until it makes the
+//! `exit` system call or an exception kills it, in which case the exception
+//! handler sees that it came from ring 3 and calls `kill`. Either way, the
+//! kernel stack in use is thrown away and `run` returns on the saved one.
+//!
+//! Interrupts, exceptions and system calls in user mode run on the stack `run`
+//! was called on (or their IST stack), never on the program's stack:
+//! `enter_user` points the TSS privilege stack right below its saved context.
+//! The scheduler switches the privilege stack and the page table with the
+//! thread, so every thread can run a program of its own and is preempted
+//! while doing so like any other thread.
+
+pub mod loader;
+pub mod programs;
+
+use crate::memory::address_space::{self, AddressSpace, USER_SPACE_END};
+use crate::memory::vmm::{self, VmError};
+use crate::serial_println;
+use crate::{gdt, smp};
+use core::arch::naked_asm;
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+/// Top of the stack mapped by `map_stack`, leaving an unmapped page below the
+/// kernel window.
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - vmm::PAGE_SIZE;
+
+/// Interrupts enabled, reserved bit 1 set.
+const USER_RFLAGS: u64 = 0x202;
+
+/// Index in the context saved by `enter_user` of the pointer to the slot for
+/// the exit reason, above the padding, flags and six callee-saved registers.
+const EXIT_SLOT: usize = 8;
+
+/// The exceptions that kill a user program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivideError,
+    InvalidOpcode,
+    SegmentNotPresent,
+    StackSegmentFault,
+    GeneralProtectionFault,
+    PageFault,
+}
+
+/// A fault that killed a user program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub exception: Exception,
+    pub instruction_pointer: VirtAddr,
+    pub error_code: Option<u64>,
+    /// The accessed address, for page faults.
+    pub address: Option<VirtAddr>,
+}
+
+/// How a user program ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// The program ended itself with the `exit` system call.
+    Exited(u64),
+    /// The program was killed because of an exception.
+    Killed(Fault),
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exit::Exited(code) => write!(f, "exited with code {}", code),
+            Exit::Killed(fault) => {
+                write!(
+                    f,
+                    "killed by {:?} at {:?}",
+                    fault.exception, fault.instruction_pointer
+                )?;
+                if let Some(error_code) = fault.error_code {
+                    write!(f, ", error code {:#x}", error_code)?;
+                }
+                if let Some(address) = fault.address {
+                    write!(f, ", accessing {:?}", address)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Maps a user stack of `pages` pages ending at `USER_STACK_TOP` and returns
+/// its top.
+pub fn map_stack(space: &mut AddressSpace, pages: u64) -> Result<VirtAddr, VmError> {
+    let top = Page::containing_address(VirtAddr::new(USER_STACK_TOP));
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    space.map_user(Page::range(top - pages, top), flags)?;
+    Ok(top.start_address())
+}
+
+/// Runs user code at `entry` with the stack pointer at `stack_top` in `space`
+/// until the program ends.
+///
+/// Each thread can run one program at a time, on the bootstrap processor.
+pub fn run(space: &AddressSpace, entry: VirtAddr, stack_top: VirtAddr) -> Exit {
+    assert_eq!(
+        smp::current().index(),
+        0,
+        "user programs only run on the bootstrap processor"
+    );
+    assert!(
+        gdt::privilege_stack().is_null(),
+        "this thread already runs a user program"
+    );
+    let selectors = gdt::selectors();
+    let mut exit = None;
+
+    unsafe {
+        space.activate();
+        enter_user(
+            entry.as_u64(),
+            stack_top.as_u64(),
+            u64::from(selectors.user_code.0),
+            u64::from(selectors.user_data.0),
+            &mut exit,
+        );
+        address_space::activate_kernel();
+        gdt::set_privilege_stack(VirtAddr::zero());
+    }
+    exit.expect("user program returned without exit reason")
+}
+
+/// Returns whether the interrupted code ran in user mode.
+pub fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
+/// Kills the running program because of `exception` and returns to `run`.
+///
+/// Called by the exception handlers for faults in user mode.
+pub fn kill(
+    exception: Exception,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+    address: Option<VirtAddr>,
+) -> ! {
+    let fault = Fault {
+        exception,
+        instruction_pointer: stack_frame.instruction_pointer,
+        error_code,
+        address,
+    };
+    serial_println!("user program {}", Exit::Killed(fault));
+    exit(Exit::Killed(fault))
+}
+
+/// Ends the current thread's program and continues in `run`, on the kernel
+/// stack.
+pub(crate) fn exit(reason: Exit) -> ! {
+    let context = gdt::privilege_stack();
+    assert!(!context.is_null(), "no user program running");
+    unsafe {
+        let slot = context.as_ptr::<*mut Option<Exit>>().add(EXIT_SLOT).read();
+        *slot = Some(reason);
+        exit_to_kernel(context.as_u64())
+    }
+}
+
+/// Saves the kernel context and enters user mode.
+///
+/// Returns once `exit` has stored the reason through `exit` and called
+/// `exit_to_kernel`. Meanwhile the privilege stack points at the saved
+/// context, which is how `exit` finds both.
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user(
+    entry: u64,
+    stack_top: u64,
+    code_selector: u64,
+    data_selector: u64,
+    exit: *mut Option<Exit>,
+) {
+    naked_asm!(
+        // callee-saved registers, restored by `exit_to_kernel`, below the
+        // slot for the exit reason
+        "push r8",
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "pushfq",
+        // interrupts and system calls from the program run below, on a
+        // 16-byte aligned stack
+        "sub rsp, 8",
+        "mov [rip + {tss} + {rsp0}], rsp",
+        // interrupt stack frame for `iretq`
+        "push rcx",
+        "push rsi",
+        "push {rflags}",
+        "push rdx",
+        "push rdi",
+        // don't leak kernel data to the program
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        rflags = const USER_RFLAGS,
+        tss = sym gdt::TSS,
+        rsp0 = const gdt::PRIVILEGE_STACK_OFFSET,
+    )
+}
+
+/// Switches to the kernel context saved by `enter_user` and returns from it.
+#[unsafe(naked)]
+unsafe extern "C" fn exit_to_kernel(context: u64) -> ! {
+    naked_asm!(
+        "mov rsp, rdi",
+        "add rsp, 8",
+        "popfq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "add rsp, 8",
+        "ret",
+    )
+}
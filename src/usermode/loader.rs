@@ -0,0 +1,152 @@
+//! Loading ELF executables into fresh address spaces.
+//!
+//! The initial stack follows the System V layout, without environment or
+//! auxiliary vector: `rsp` is 16-byte aligned and points to `argc`, followed
+//! by the `argv` pointers, a null pointer ending `argv` and another one for
+//! the empty environment. The argument strings are stored above.
+
+use super::{map_stack, run, Exit};
+use crate::elf::{Elf, ElfError};
+use crate::memory::address_space::{AddressSpace, USER_SPACE_END, USER_SPACE_START};
+use crate::memory::vmm::VmError;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+/// Size in pages of the stack of a loaded program.
+pub const STACK_PAGES: u64 = 16;
+/// Limit on the size of the arguments with their pointers.
+pub const ARGUMENTS_MAX: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// A segment is outside the user range.
+    SegmentOutOfRange {
+        address: u64,
+    },
+    /// The arguments are larger than `ARGUMENTS_MAX`.
+    ArgumentsTooLarge,
+    Vm(VmError),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> Self {
+        LoadError::Elf(error)
+    }
+}
+
+impl From<VmError> for LoadError {
+    fn from(error: VmError) -> Self {
+        LoadError::Vm(error)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Elf(error) => write!(f, "invalid executable: {}", error),
+            LoadError::SegmentOutOfRange { address } => {
+                write!(f, "segment at {:#x} is outside the user range", address)
+            }
+            LoadError::ArgumentsTooLarge => write!(f, "arguments are too large"),
+            LoadError::Vm(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// A program loaded into its own address space, ready to run.
+pub struct Program {
+    space: AddressSpace,
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+}
+
+impl Program {
+    /// Loads the executable `elf` and sets up its stack with `args` as
+    /// `argv`.
+    pub fn load(elf: &[u8], args: &[&str]) -> Result<Program, LoadError> {
+        let elf = Elf::parse(elf)?;
+        let mut space = AddressSpace::new()?;
+
+        for segment in elf.segments() {
+            if segment.address < USER_SPACE_START || segment.end() > USER_SPACE_END {
+                return Err(LoadError::SegmentOutOfRange {
+                    address: segment.address,
+                });
+            }
+            let mut flags = PageTableFlags::empty();
+            if segment.writable {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if !segment.executable {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            let start = Page::containing_address(VirtAddr::new(segment.address));
+            let end = Page::containing_address(VirtAddr::new(segment.end() - 1));
+            space.map_user(Page::range(start, end + 1), flags)?;
+            // the frames are zeroed, which takes care of the rest of `mem_size`
+            space.write(VirtAddr::new(segment.address), elf.segment_data(&segment))?;
+        }
+
+        let stack_top = map_stack(&mut space, STACK_PAGES)?;
+        let stack_pointer = push_arguments(&mut space, stack_top, args)?;
+        Ok(Program {
+            space,
+            entry: VirtAddr::new(elf.entry()),
+            stack_pointer,
+        })
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        self.entry
+    }
+
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        &mut self.space
+    }
+
+    /// Runs the program until it exits or is killed.
+    pub fn run(&self) -> Exit {
+        run(&self.space, self.entry, self.stack_pointer)
+    }
+}
+
+/// Loads and runs the executable `elf` with `args` as `argv`.
+pub fn exec(elf: &[u8], args: &[&str]) -> Result<Exit, LoadError> {
+    Ok(Program::load(elf, args)?.run())
+}
+
+/// Writes `args` to the stack ending at `stack_top` and returns the initial
+/// stack pointer.
+fn push_arguments(
+    space: &mut AddressSpace,
+    stack_top: VirtAddr,
+    args: &[&str],
+) -> Result<VirtAddr, LoadError> {
+    let strings_size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    // argc, argv, the null pointers ending argv and the environment
+    let table_size = (args.len() + 3) * 8;
+    if strings_size + table_size + 15 > ARGUMENTS_MAX {
+        return Err(LoadError::ArgumentsTooLarge);
+    }
+
+    let strings_start = stack_top - strings_size as u64;
+    let stack_pointer = (strings_start - table_size as u64).align_down(16u64);
+
+    let mut table = Vec::with_capacity(args.len() + 3);
+    table.push(args.len() as u64);
+    let mut string = strings_start;
+    for arg in args {
+        table.push(string.as_u64());
+        space.write(string, arg.as_bytes())?;
+        space.write(string + arg.len() as u64, &[0])?;
+        string += arg.len() as u64 + 1;
+    }
+    table.extend_from_slice(&[0, 0]);
+
+    let bytes: Vec<u8> = table.iter().flat_map(|value| value.to_le_bytes()).collect();
+    space.write(stack_pointer, &bytes)?;
+    Ok(stack_pointer)
+}
@@ -0,0 +1,16 @@
+//! User programs built into the kernel.
+//!
+//! The sources are in `user/`, whose `build.sh` regenerates the binaries.
+
+/// Prints a greeting and exits with code 0.
+pub static HELLO: &[u8] = include_bytes!("../../user/bin/hello");
+
+/// Prints its arguments, one per line, and exits with the argument count.
+pub static ARGS: &[u8] = include_bytes!("../../user/bin/args");
+
+/// Exits with 42, computed from initialized and zeroed data.
+pub static DATA: &[u8] = include_bytes!("../../user/bin/data");
+
+/// Writes to read-only data with argument `w` and executes data with
+/// argument `x`, exiting with 0 otherwise.
+pub static FAULT: &[u8] = include_bytes!("../../user/bin/fault");
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Runs the heap allocation tests against every global allocator.
+set -e
+
+for allocator in alloc-fixed-block alloc-linked-list alloc-bump alloc-external; do
+    echo "== $allocator"
+    cargo test --test heap_allocation --no-default-features --features "$allocator"
+done
@@ -0,0 +1,161 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use slate::memory::address_space::{self, AddressSpace, USER_SPACE_START};
+use slate::memory::vmm::{self, VmError};
+use slate::memory::{self, GlobalFrameAllocator};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::BootInfoFrameAllocator;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::WRITABLE;
+
+fn user_pages(first: u64, count: u64) -> PageRange {
+    let start = Page::containing_address(VirtAddr::new(USER_SPACE_START)) + first;
+    Page::range(start, start + count)
+}
+
+fn user_ptr(page: u64) -> *mut u64 {
+    VirtAddr::new(USER_SPACE_START + page * vmm::PAGE_SIZE).as_mut_ptr()
+}
+
+#[test_case]
+fn user_pages_are_only_mapped_in_their_space() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map_user(user_pages(0, 2), FLAGS).unwrap();
+    assert!(space.translate(VirtAddr::new(USER_SPACE_START)).is_some());
+    assert_eq!(vmm::translate(VirtAddr::new(USER_SPACE_START)), None);
+}
+
+#[test_case]
+fn kernel_stays_mapped_after_switching() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map_user(user_pages(0, 1), FLAGS).unwrap();
+    unsafe { space.activate() };
+    assert!(space.is_active());
+
+    // code, stack, heap and user pages are all accessible
+    let boxed = Box::new(41);
+    assert_eq!(*boxed + 1, 42);
+    unsafe {
+        assert_eq!(user_ptr(0).read_volatile(), 0);
+        user_ptr(0).write_volatile(7);
+        assert_eq!(user_ptr(0).read_volatile(), 7);
+    }
+
+    address_space::activate_kernel();
+    assert!(!space.is_active());
+}
+
+#[test_case]
+fn spaces_are_isolated() {
+    let mut first = AddressSpace::new().unwrap();
+    let mut second = AddressSpace::new().unwrap();
+    first.map_user(user_pages(0, 1), FLAGS).unwrap();
+    second.map_user(user_pages(0, 1), FLAGS).unwrap();
+
+    unsafe {
+        first.activate();
+        user_ptr(0).write_volatile(1);
+        second.activate();
+        assert_eq!(user_ptr(0).read_volatile(), 0);
+        user_ptr(0).write_volatile(2);
+        first.activate();
+        assert_eq!(user_ptr(0).read_volatile(), 1);
+    }
+    address_space::activate_kernel();
+}
+
+#[test_case]
+fn unmapped_user_page_is_gone_after_switching_back() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map_user(user_pages(0, 1), FLAGS).unwrap();
+    unsafe {
+        space.activate();
+        user_ptr(0).write_volatile(3);
+    }
+    address_space::activate_kernel();
+
+    // the stale translation must not survive while the space is inactive
+    space.unmap_user(user_pages(0, 1)).unwrap();
+    space.map_user(user_pages(0, 1), FLAGS).unwrap();
+    unsafe {
+        space.activate();
+        assert_eq!(user_ptr(0).read_volatile(), 0);
+    }
+    address_space::activate_kernel();
+}
+
+#[test_case]
+fn new_kernel_mappings_are_shared() {
+    let space = AddressSpace::new().unwrap();
+    let region = vmm::reserve("shared", 1).unwrap();
+    vmm::map_region(&region, FLAGS).unwrap();
+
+    unsafe {
+        space.activate();
+        let ptr: *mut u64 = region.start.as_mut_ptr();
+        ptr.write_volatile(5);
+        assert_eq!(ptr.read_volatile(), 5);
+    }
+    address_space::activate_kernel();
+    vmm::release(&region).unwrap();
+}
+
+#[test_case]
+fn mapping_outside_user_range_fails() {
+    let mut space = AddressSpace::new().unwrap();
+    let kernel_page = Page::containing_address(VirtAddr::new(vmm::KERNEL_SPACE_START));
+    assert_eq!(
+        space.map_user(Page::range(kernel_page, kernel_page + 1), FLAGS),
+        Err(VmError::OutOfBounds)
+    );
+}
+
+#[test_case]
+fn drop_frees_frames() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map_user(user_pages(0, 4), FLAGS).unwrap();
+    unsafe { space.activate() };
+    let p4 = space.p4_frame();
+
+    // switches back to the kernel's table before freeing
+    drop(space);
+    assert!(vmm::translate(VirtAddr::new(USER_SPACE_START)).is_none());
+
+    // the level 4 table is freed last
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    assert_eq!(frame, p4);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use slate::memory::vmm::{self, Region};
+use slate::memory::{self, GlobalFrameAllocator};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::BootInfoFrameAllocator;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::WRITABLE;
+
+/// Far more than the test VM's physical memory.
+const LARGE: u64 = 256 * 1024;
+
+fn page_ptr(region: &Region, page: u64) -> *mut u64 {
+    (region.start + page * vmm::PAGE_SIZE).as_mut_ptr()
+}
+
+fn committed_pages(region: &Region) -> usize {
+    region
+        .page_range()
+        .filter(|page| vmm::translate(page.start_address()).is_some())
+        .count()
+}
+
+#[test_case]
+fn reserving_commits_nothing() {
+    let region = vmm::reserve_lazy("lazy", LARGE, FLAGS).unwrap();
+    assert_eq!(vmm::translate(region.start), None);
+    assert_eq!(vmm::translate(region.end() - 1u64), None);
+    vmm::release(&region).unwrap();
+}
+
+#[test_case]
+fn first_touch_maps_zeroed_page() {
+    let region = vmm::reserve_lazy("lazy", 64, FLAGS).unwrap();
+    unsafe {
+        assert_eq!(page_ptr(&region, 10).read_volatile(), 0);
+        page_ptr(&region, 20).add(7).write_volatile(42);
+        assert_eq!(page_ptr(&region, 20).add(7).read_volatile(), 42);
+    }
+    assert_eq!(committed_pages(&region), 2);
+    assert!(vmm::translate(VirtAddr::from_ptr(page_ptr(&region, 10))).is_some());
+    vmm::release(&region).unwrap();
+}
+
+#[test_case]
+fn writes_to_every_page_persist() {
+    let region = vmm::reserve_lazy("lazy", 16, FLAGS).unwrap();
+    for page in 0..region.pages {
+        unsafe { page_ptr(&region, page).write_volatile(page) };
+    }
+    for page in 0..region.pages {
+        assert_eq!(unsafe { page_ptr(&region, page).read_volatile() }, page);
+    }
+    assert_eq!(committed_pages(&region), 16);
+    vmm::release(&region).unwrap();
+}
+
+#[test_case]
+fn release_frees_committed_frames() {
+    let region = vmm::reserve_lazy("lazy", 4, FLAGS).unwrap();
+    unsafe { page_ptr(&region, 3).write_volatile(1) };
+    let phys = vmm::translate(VirtAddr::from_ptr(page_ptr(&region, 3))).unwrap();
+    vmm::release(&region).unwrap();
+
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    assert_eq!(frame.start_address(), phys);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn unmapped_pages_are_committed_again() {
+    let region = vmm::reserve_lazy("lazy", 2, FLAGS).unwrap();
+    unsafe { page_ptr(&region, 0).write_volatile(7) };
+    vmm::unmap_region(&region).unwrap();
+    assert_eq!(committed_pages(&region), 0);
+    assert_eq!(unsafe { page_ptr(&region, 0).read_volatile() }, 0);
+    vmm::release(&region).unwrap();
+}
@@ -0,0 +1,152 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use slate::elf::{Elf, ElfError};
+use slate::memory::{self, vmm};
+use slate::usermode::loader::{self, LoadError, Program};
+use slate::usermode::{programs, Exception, Exit};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::BootInfoFrameAllocator;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+    slate::gdt::init_stacks().expect("stack setup failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+fn killed_by(exit: Exit) -> Exception {
+    match exit {
+        Exit::Killed(fault) => fault.exception,
+        exit => panic!("program was not killed: {}", exit),
+    }
+}
+
+#[test_case]
+fn hello_exits_with_zero() {
+    assert_eq!(
+        loader::exec(programs::HELLO, &["hello"]),
+        Ok(Exit::Exited(0))
+    );
+}
+
+#[test_case]
+fn args_are_passed() {
+    assert_eq!(
+        loader::exec(programs::ARGS, &["args", "one", "two"]),
+        Ok(Exit::Exited(3))
+    );
+    assert_eq!(loader::exec(programs::ARGS, &[]), Ok(Exit::Exited(0)));
+}
+
+#[test_case]
+fn data_and_bss_are_loaded() {
+    assert_eq!(
+        loader::exec(programs::DATA, &["data"]),
+        Ok(Exit::Exited(42))
+    );
+}
+
+#[test_case]
+fn segment_permissions_are_enforced() {
+    assert_eq!(
+        loader::exec(programs::FAULT, &["fault"]),
+        Ok(Exit::Exited(0))
+    );
+    let exit = loader::exec(programs::FAULT, &["fault", "w"]).unwrap();
+    assert_eq!(killed_by(exit), Exception::PageFault);
+    let exit = loader::exec(programs::FAULT, &["fault", "x"]).unwrap();
+    assert_eq!(killed_by(exit), Exception::PageFault);
+}
+
+#[test_case]
+fn segments_are_mapped_at_their_addresses() {
+    let elf = Elf::parse(programs::DATA).unwrap();
+    let mut program = Program::load(programs::DATA, &[]).unwrap();
+    assert_eq!(program.entry().as_u64(), elf.entry());
+    for segment in elf.segments() {
+        let space = program.address_space();
+        assert!(space.translate(VirtAddr::new(segment.address)).is_some());
+        assert!(space.translate(VirtAddr::new(segment.end() - 1)).is_some());
+    }
+    // nothing is mapped in the kernel's own address space
+    assert_eq!(vmm::translate(program.entry()), None);
+}
+
+#[test_case]
+fn program_can_run_twice() {
+    let program = Program::load(programs::ARGS, &["args", "again"]).unwrap();
+    assert_eq!(program.run(), Exit::Exited(2));
+    assert_eq!(program.run(), Exit::Exited(2));
+}
+
+#[test_case]
+fn invalid_headers_are_rejected() {
+    assert_eq!(
+        loader::exec(&programs::HELLO[..40], &[]),
+        Err(LoadError::Elf(ElfError::Truncated))
+    );
+
+    let mut elf = Vec::from(programs::HELLO);
+    elf[1] = b'X';
+    assert_eq!(Elf::parse(&elf).err(), Some(ElfError::BadMagic));
+
+    let mut elf = Vec::from(programs::HELLO);
+    elf[4] = 1; // 32-bit
+    assert_eq!(Elf::parse(&elf).err(), Some(ElfError::UnsupportedFormat));
+
+    let mut elf = Vec::from(programs::HELLO);
+    elf[16] = 3; // shared object
+    assert_eq!(Elf::parse(&elf).err(), Some(ElfError::NotExecutable));
+
+    let mut elf = Vec::from(programs::HELLO);
+    elf[24..32].copy_from_slice(&0x1000u64.to_le_bytes());
+    assert_eq!(Elf::parse(&elf).err(), Some(ElfError::BadEntryPoint));
+}
+
+#[test_case]
+fn segment_outside_file_is_rejected() {
+    let mut elf = Vec::from(programs::HELLO);
+    let first_segment = u64::from_le_bytes(elf[32..40].try_into().unwrap()) as usize;
+    // p_filesz and p_memsz of the first program header
+    let size = (elf.len() as u64).to_le_bytes();
+    elf[first_segment + 32..first_segment + 40].copy_from_slice(&size);
+    elf[first_segment + 40..first_segment + 48].copy_from_slice(&size);
+    assert_eq!(
+        Elf::parse(&elf).err(),
+        Some(ElfError::BadSegment { index: 0 })
+    );
+}
+
+#[test_case]
+fn too_many_arguments_are_rejected() {
+    let args = ["argument"; 512];
+    assert_eq!(
+        loader::exec(programs::ARGS, &args).err(),
+        Some(LoadError::ArgumentsTooLarge)
+    );
+}
@@ -0,0 +1,90 @@
+#![feature(abi_x86_interrupt)]
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use slate::memory::stack;
+use slate::{exit_qemu, hlt_loop, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::memory::{self, vmm, BootInfoFrameAllocator};
+
+    serial_print!("guard_page::overflow_hits_guard_page...\t");
+
+    slate::gdt::init();
+    slate::smp::init_bsp();
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    slate::gdt::init_stacks().expect("stack setup failed");
+    let test_stack = stack::allocate_stack("test", 4).expect("stack setup failed");
+
+    unsafe { stack::switch_to(test_stack, overflow) }
+}
+
+fn overflow() -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(slate::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    let owner = Cr2::read().ok().and_then(stack::guard_page_owner);
+    match owner {
+        Some(stack) if stack.name == "test" => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        _ => {
+            serial_println!("[failed]\n");
+            serial_println!(
+                "Error: page fault outside of the guard page: {:?}",
+                Cr2::read()
+            );
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    hlt_loop()
+}
@@ -11,20 +11,23 @@ use alloc::vec::Vec;
 use bootloader::{entry_point, BootInfo};
 use core::hint::black_box;
 use core::panic::PanicInfo;
-use slate::allocator::HEAP_SIZE;
+use slate::allocator::fallible::{try_box, try_push, try_vec_with_capacity};
+use slate::allocator::{heap_stats, leak, HEAP_SIZE};
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use slate::allocator;
-    use slate::memory::{self, BootInfoFrameAllocator};
+    use slate::memory::{self, vmm, BootInfoFrameAllocator};
     use x86_64::VirtAddr;
 
     slate::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
 
     test_main();
     loop {}
@@ -61,6 +64,8 @@ fn many_boxes() {
     }
 }
 
+// A bump allocator can only reuse memory once every allocation is freed.
+#[cfg(not(feature = "alloc-bump"))]
 #[test_case]
 fn many_boxes_long_lived() {
     let long_lived = Box::new(1); // new
@@ -71,3 +76,69 @@ fn many_boxes_long_lived() {
     black_box(&long_lived);
     assert_eq!(*long_lived, 1); // new
 }
+
+#[test_case]
+fn stats_track_bytes_allocated() {
+    let before = heap_stats();
+    let value = Box::new([0u8; 100]);
+    let during = heap_stats();
+    assert_eq!(during.bytes_allocated, before.bytes_allocated + 100);
+    assert!(during.peak_bytes_allocated >= during.bytes_allocated);
+    assert_eq!(during.allocations, before.allocations + 1);
+    drop(value);
+    let after = heap_stats();
+    assert_eq!(after.bytes_allocated, before.bytes_allocated);
+    assert_eq!(after.live_allocations(), before.live_allocations());
+}
+
+#[test_case]
+fn leak_tracking_reports_live_allocations() {
+    leak::enable();
+    let generation = leak::checkpoint();
+    let freed = Box::new(1u64);
+    let leaked = Box::new(2u64);
+    drop(freed);
+
+    // the callback must not allocate, so only remember the last address
+    let mut live = 0;
+    let mut live_addr = 0;
+    leak::for_each_since(generation, |a| {
+        live += 1;
+        live_addr = a.addr;
+    });
+    leak::disable();
+
+    assert_eq!(live, 1);
+    assert_eq!(live_addr, &*leaked as *const u64 as usize);
+}
+
+#[test_case]
+fn fallible_allocation_fails_gracefully() {
+    assert!(try_vec_with_capacity::<u8>(HEAP_SIZE * 2).is_err());
+    let failed_before = heap_stats().failed_allocations;
+
+    let mut vec = try_vec_with_capacity(4).unwrap();
+    for i in 0..100 {
+        assert_eq!(try_push(&mut vec, i), Ok(()));
+    }
+    assert_eq!(vec.len(), 100);
+    assert_eq!(heap_stats().failed_allocations, failed_before);
+}
+
+// The bump allocator cannot reuse the blocks while `blocks` is alive.
+#[cfg(not(feature = "alloc-bump"))]
+#[test_case]
+fn cached_blocks_are_reclaimed_when_heap_is_full() {
+    let mut blocks = Vec::with_capacity(HEAP_SIZE / 1024);
+    while let Ok(block) = try_box([0u8; 1000]) {
+        if try_push(&mut blocks, block).is_err() {
+            break;
+        }
+    }
+    assert!(try_box([0u8; 1000]).is_err());
+    drop(blocks);
+
+    // the freed blocks are cached per size class and must be merged again
+    let large = try_vec_with_capacity::<u8>(HEAP_SIZE / 2);
+    assert!(large.is_ok());
+}
@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::panic::PanicInfo;
+use slate::allocator::debug::{self, DebugAllocator, HeapError};
+use slate::allocator::linked_list::LinkedListAllocator;
+use slate::allocator::Locked;
+use slate::hlt_loop;
+use spin::Mutex;
+
+const HEAP_SIZE: usize = 64 * 1024;
+
+#[repr(align(4096))]
+struct Heap([u8; HEAP_SIZE]);
+
+static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+
+static INNER: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+static ALLOCATOR: DebugAllocator<Locked<LinkedListAllocator>> = DebugAllocator::new(&INNER);
+
+static LAST_ERROR: Mutex<Option<HeapError>> = Mutex::new(None);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe {
+        let heap_start = core::ptr::addr_of_mut!(HEAP.0) as usize;
+        INNER.lock().init(heap_start, HEAP_SIZE);
+    }
+    debug::set_error_handler(|error| *LAST_ERROR.lock() = Some(*error));
+
+    test_main();
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+fn take_error() -> Option<HeapError> {
+    LAST_ERROR.lock().take()
+}
+
+#[test_case]
+fn valid_usage_reports_nothing() {
+    let layout = Layout::from_size_align(100, 16).unwrap();
+    unsafe {
+        for _ in 0..200 {
+            let ptr = ALLOCATOR.alloc(layout);
+            assert_eq!(ptr as usize % 16, 0);
+            ptr.write_bytes(0x11, 100);
+            ALLOCATOR.dealloc(ptr, layout);
+        }
+    }
+    assert!(ALLOCATOR.verify_quarantine());
+    ALLOCATOR.flush_quarantine();
+    assert_eq!(take_error(), None);
+}
+
+#[test_case]
+fn overflow_is_detected() {
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    unsafe {
+        let ptr = ALLOCATOR.alloc(layout);
+        ptr.add(16).write(0);
+        ALLOCATOR.dealloc(ptr, layout);
+        assert_eq!(
+            take_error(),
+            Some(HeapError::CanaryCorrupted {
+                addr: ptr as usize,
+                corrupted_at: ptr as usize + 16,
+            })
+        );
+    }
+}
+
+#[test_case]
+fn underflow_is_detected() {
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    unsafe {
+        let ptr = ALLOCATOR.alloc(layout);
+        ptr.sub(1).write(0);
+        ALLOCATOR.dealloc(ptr, layout);
+        assert_eq!(
+            take_error(),
+            Some(HeapError::CanaryCorrupted {
+                addr: ptr as usize,
+                corrupted_at: ptr as usize - 1,
+            })
+        );
+    }
+}
+
+#[test_case]
+fn double_free_is_detected() {
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        let ptr = ALLOCATOR.alloc(layout);
+        ALLOCATOR.dealloc(ptr, layout);
+        assert_eq!(take_error(), None);
+        ALLOCATOR.dealloc(ptr, layout);
+        assert_eq!(
+            take_error(),
+            Some(HeapError::DoubleFree { addr: ptr as usize })
+        );
+    }
+}
+
+#[test_case]
+fn layout_mismatch_is_detected() {
+    let allocated = Layout::from_size_align(32, 8).unwrap();
+    let freed = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = ALLOCATOR.alloc(allocated);
+        ALLOCATOR.dealloc(ptr, freed);
+        assert_eq!(
+            take_error(),
+            Some(HeapError::LayoutMismatch {
+                addr: ptr as usize,
+                allocated,
+                freed,
+            })
+        );
+    }
+}
+
+#[test_case]
+fn use_after_free_is_detected() {
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        let ptr = ALLOCATOR.alloc(layout);
+        ALLOCATOR.dealloc(ptr, layout);
+        ptr.add(8).write_volatile(1);
+        assert!(!ALLOCATOR.verify_quarantine());
+        assert_eq!(
+            take_error(),
+            Some(HeapError::UseAfterFree {
+                addr: ptr as usize,
+                corrupted_at: ptr as usize + 8,
+            })
+        );
+    }
+}
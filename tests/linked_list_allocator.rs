@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::panic::PanicInfo;
+use slate::allocator::linked_list::{FitStrategy, LinkedListAllocator};
+use slate::allocator::Locked;
+use slate::hlt_loop;
+
+const HEAP_SIZE: usize = 64 * 1024;
+
+#[repr(align(4096))]
+struct Heap([u8; HEAP_SIZE]);
+
+static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+/// Creates an allocator over the static test heap.
+///
+/// Tests run one after another and every test frees everything it allocates,
+/// so reusing the same memory for each allocator is fine.
+fn allocator(strategy: FitStrategy) -> Locked<LinkedListAllocator> {
+    let allocator = Locked::new(LinkedListAllocator::with_strategy(strategy));
+    unsafe {
+        let heap_start = core::ptr::addr_of_mut!(HEAP.0) as usize;
+        allocator.lock().init(heap_start, HEAP_SIZE);
+    }
+    allocator
+}
+
+fn assert_fully_coalesced(allocator: &Locked<LinkedListAllocator>) {
+    let stats = allocator.lock().stats();
+    assert_eq!(stats.free_space.free_regions, Some(1));
+    assert_eq!(stats.free_space.free_bytes, HEAP_SIZE);
+    assert_eq!(stats.free_space.largest_free_region, Some(HEAP_SIZE));
+}
+
+#[test_case]
+fn freed_neighbours_are_merged() {
+    let allocator = allocator(FitStrategy::FirstFit);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let a = allocator.alloc(layout);
+        let b = allocator.alloc(layout);
+        let c = allocator.alloc(layout);
+        allocator.dealloc(a, layout);
+        allocator.dealloc(c, layout);
+        allocator.dealloc(b, layout);
+    }
+    assert_fully_coalesced(&allocator);
+}
+
+#[test_case]
+fn best_fit_uses_smallest_region() {
+    let allocator = allocator(FitStrategy::BestFit);
+    let small = Layout::from_size_align(32, 8).unwrap();
+    let large = Layout::from_size_align(256, 8).unwrap();
+    unsafe {
+        let a = allocator.alloc(large);
+        let guard_a = allocator.alloc(small);
+        let b = allocator.alloc(small);
+        let guard_b = allocator.alloc(small);
+        // free regions are now [a: 256 bytes], [b: 32 bytes], [rest of heap]
+        allocator.dealloc(a, large);
+        allocator.dealloc(b, small);
+
+        let c = allocator.alloc(small);
+        assert_eq!(c, b);
+
+        allocator.dealloc(c, small);
+        allocator.dealloc(guard_b, small);
+        allocator.dealloc(guard_a, small);
+    }
+    assert_fully_coalesced(&allocator);
+}
+
+#[test_case]
+fn realloc_grows_in_place() {
+    let allocator = allocator(FitStrategy::FirstFit);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let a = allocator.alloc(layout);
+        a.write_bytes(0xab, 64);
+        let grown = allocator.realloc(a, layout, 1024);
+        assert_eq!(grown, a);
+        assert_eq!(*grown.add(63), 0xab);
+
+        let grown_layout = Layout::from_size_align(1024, 8).unwrap();
+        let shrunk = allocator.realloc(grown, grown_layout, 32);
+        assert_eq!(shrunk, a);
+        allocator.dealloc(shrunk, Layout::from_size_align(32, 8).unwrap());
+    }
+    assert_fully_coalesced(&allocator);
+}
+
+#[test_case]
+fn realloc_moves_when_blocked() {
+    let allocator = allocator(FitStrategy::FirstFit);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let a = allocator.alloc(layout);
+        let b = allocator.alloc(layout);
+        a.write_bytes(0xcd, 64);
+        let moved = allocator.realloc(a, layout, 128);
+        assert_ne!(moved, a);
+        assert_eq!(*moved.add(63), 0xcd);
+
+        allocator.dealloc(moved, Layout::from_size_align(128, 8).unwrap());
+        allocator.dealloc(b, layout);
+    }
+    assert_fully_coalesced(&allocator);
+}
+
+/// Allocates blocks of varying sizes and alignments, then repeatedly frees
+/// half of them and refills the holes with differently sized blocks. Without
+/// coalescing, the whole-heap allocation at the end fails.
+fn fragmentation_stress(strategy: FitStrategy) {
+    const BLOCKS: usize = 128;
+    let allocator = allocator(strategy);
+    let layout = |i: usize| Layout::from_size_align(16 + (i * 37) % 200, 8 << (i % 3)).unwrap();
+    let mut blocks: [Option<(*mut u8, Layout)>; BLOCKS] = [None; BLOCKS];
+
+    unsafe {
+        for round in 0..8 {
+            for (i, block) in blocks.iter_mut().enumerate() {
+                if block.is_none() {
+                    let layout = layout(i + round);
+                    let ptr = allocator.alloc(layout);
+                    assert!(!ptr.is_null());
+                    *block = Some((ptr, layout));
+                }
+            }
+            for (i, block) in blocks.iter_mut().enumerate() {
+                if (i + round) % 2 == 0 {
+                    if let Some((ptr, layout)) = block.take() {
+                        allocator.dealloc(ptr, layout);
+                    }
+                }
+            }
+        }
+        for (ptr, layout) in blocks.iter_mut().filter_map(Option::take) {
+            allocator.dealloc(ptr, layout);
+        }
+    }
+    assert_fully_coalesced(&allocator);
+
+    let whole_heap = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(whole_heap);
+        assert!(!ptr.is_null());
+        allocator.dealloc(ptr, whole_heap);
+    }
+}
+
+#[test_case]
+fn fragmentation_stress_first_fit() {
+    fragmentation_stress(FitStrategy::FirstFit);
+}
+
+#[test_case]
+fn fragmentation_stress_best_fit() {
+    fragmentation_stress(FitStrategy::BestFit);
+}
@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use slate::memory::mmio::{ioremap, CacheMode};
+use slate::memory::{self, vmm};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::BootInfoFrameAllocator;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    slate::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+/// Physical address of the VGA text buffer, the one device every test VM has.
+///
+/// The physical memory mapping also maps it as write-back, so the tests only
+/// check the cache bits of the new mappings and never access them: accesses
+/// through mappings of different memory types would alias in the caches.
+const VGA_BUFFER: u64 = 0xb8000;
+
+/// Cache bits of a 4 KiB page table entry. The PAT bit is `HUGE_PAGE`.
+const CACHE_FLAGS: PageTableFlags = PageTableFlags::WRITE_THROUGH
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::HUGE_PAGE);
+
+fn cache_flags(addr: VirtAddr) -> PageTableFlags {
+    vmm::page_flags(addr).expect("MMIO range not mapped") & CACHE_FLAGS
+}
+
+#[test_case]
+fn uncached_mapping_disables_caching() {
+    let vga = unsafe { ioremap(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::Uncached) }.unwrap();
+    assert_eq!(
+        vmm::translate(vga.virt_addr()),
+        Some(PhysAddr::new(VGA_BUFFER))
+    );
+    assert_eq!(
+        cache_flags(vga.virt_addr()),
+        PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+    );
+    assert!(vmm::page_flags(vga.virt_addr())
+        .unwrap()
+        .contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn unaligned_start_is_offset_into_page() {
+    let phys = PhysAddr::new(VGA_BUFFER + 160);
+    let line = unsafe { ioremap(phys, 160, CacheMode::WriteThrough) }.unwrap();
+    assert_eq!(line.phys_addr(), phys);
+    assert_eq!(line.virt_addr().as_u64() % 4096, 160);
+    assert_eq!(vmm::translate(line.virt_addr()), Some(phys));
+    assert_eq!(cache_flags(line.virt_addr()), PageTableFlags::WRITE_THROUGH);
+}
+
+#[test_case]
+fn write_combining_mapping() {
+    let vga =
+        unsafe { ioremap(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::WriteCombining) }.unwrap();
+    // PCD alone selects the write-combining PAT entry, both select uncached
+    // if the CPU has no PAT
+    let flags = cache_flags(vga.virt_addr());
+    assert!(
+        flags == PageTableFlags::NO_CACHE
+            || flags == PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+    );
+
+    let addr = vga.virt_addr();
+    drop(vga);
+    assert_eq!(vmm::translate(addr), None);
+}
+
+#[test_case]
+fn drop_unmaps() {
+    let vga = unsafe { ioremap(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::Uncached) }.unwrap();
+    let addr = vga.virt_addr();
+    let regions = vmm::regions().len();
+    drop(vga);
+    assert_eq!(vmm::translate(addr), None);
+    assert_eq!(vmm::regions().len(), regions - 1);
+}
@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use slate::allocator::slab::{SlabCache, SlabHooks};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::{self, vmm, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+#[test_case]
+fn alloc_and_free() {
+    static CACHE: SlabCache<[u64; 4]> = SlabCache::new();
+    let a = CACHE.alloc([1; 4]).unwrap();
+    let b = CACHE.alloc([2; 4]).unwrap();
+    assert_eq!(*a, [1; 4]);
+    assert_eq!(*b, [2; 4]);
+    assert_eq!(CACHE.stats().objects_in_use, 2);
+    drop(a);
+    drop(b);
+    assert_eq!(CACHE.stats().objects_in_use, 0);
+}
+
+#[test_case]
+fn freed_slot_is_reused() {
+    static CACHE: SlabCache<u64> = SlabCache::new();
+    let a = CACHE.alloc(1).unwrap();
+    let addr = &*a as *const u64;
+    drop(a);
+    let b = CACHE.alloc(2).unwrap();
+    assert_eq!(&*b as *const u64, addr);
+}
+
+#[test_case]
+fn drop_runs_destructor_of_value() {
+    static CACHE: SlabCache<Box<u32>> = SlabCache::new();
+    let heap_before = slate::allocator::heap_stats().bytes_allocated;
+    let value = CACHE.alloc(Box::new(7)).unwrap();
+    assert_eq!(**value, 7);
+    drop(value);
+    assert_eq!(slate::allocator::heap_stats().bytes_allocated, heap_before);
+}
+
+#[test_case]
+fn empty_slabs_are_reclaimed() {
+    static CACHE: SlabCache<[u8; 256]> = SlabCache::new();
+    let per_slab = CACHE.stats().objects_per_slab;
+
+    let objects: Vec<_> = (0..per_slab * 4)
+        .map(|_| CACHE.alloc([0; 256]).unwrap())
+        .collect();
+    assert_eq!(CACHE.stats().slabs, 4);
+
+    drop(objects);
+    // one empty slab is kept for future allocations
+    assert_eq!(CACHE.stats().slabs, 1);
+    CACHE.shrink();
+    assert_eq!(CACHE.stats().slabs, 0);
+}
+
+#[test_case]
+fn hooks_run_per_slot() {
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+    static DESTRUCTED: AtomicUsize = AtomicUsize::new(0);
+    static CACHE: SlabCache<u64> = SlabCache::with_hooks(SlabHooks {
+        constructor: Some(|_| {
+            CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+        }),
+        destructor: Some(|_| {
+            DESTRUCTED.fetch_add(1, Ordering::Relaxed);
+        }),
+    });
+
+    let value = CACHE.alloc(0).unwrap();
+    let per_slab = CACHE.stats().objects_per_slab;
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), per_slab);
+    drop(value);
+    CACHE.shrink();
+    assert_eq!(DESTRUCTED.load(Ordering::Relaxed), per_slab);
+}
+
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn global_allocator_uses_slabs() {
+    use slate::allocator::{enable_slabs, HEAP_SIZE, HEAP_START};
+
+    enable_slabs();
+    let value = Box::new(42u64);
+    let addr = &*value as *const u64 as usize;
+    assert!(!(HEAP_START..HEAP_START + HEAP_SIZE).contains(&addr));
+    assert_eq!(*value, 42);
+}
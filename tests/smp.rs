@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::hint::spin_loop;
+use core::panic::PanicInfo;
+use core::time::Duration;
+use slate::memory::{self, vmm};
+use slate::task::channel::oneshot;
+use slate::task::executor::{registry, Executor};
+use slate::{smp, time};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::BootInfoFrameAllocator;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+    smp::init().expect("starting the other CPUs failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+/// Spins on the BSP until `done` returns `true`, failing after a second.
+fn wait_until(done: impl Fn() -> bool) {
+    let deadline = time::ticks() + time::duration_to_ticks(Duration::from_secs(1));
+    while !done() {
+        assert!(time::ticks() < deadline, "timed out");
+        spin_loop();
+    }
+}
+
+#[test_case]
+fn all_cpus_come_online() {
+    // the test VM runs with `-smp 4`
+    assert_eq!(smp::cpu_count(), 4);
+    assert_eq!(smp::current().index(), 0);
+
+    let cpus: Vec<_> = smp::cpus().collect();
+    for (index, cpu) in cpus.iter().enumerate() {
+        assert_eq!(cpu.index(), index);
+        assert!(cpus[..index]
+            .iter()
+            .all(|other| other.apic_id() != cpu.apic_id()));
+    }
+}
+
+#[test_case]
+fn other_cpus_steal_spawned_tasks() {
+    // this executor never runs, so only the APs' executors can run its tasks
+    let executor = Executor::new().with_work_stealing();
+    let spawner = executor.spawner();
+    let mut handles: Vec<_> = (0..8)
+        .map(|_| spawner.spawn(async { smp::current().index() }))
+        .collect();
+
+    wait_until(|| handles.iter().all(|handle| handle.is_finished()));
+    for handle in &mut handles {
+        let cpu = handle.try_join().unwrap().unwrap();
+        assert_ne!(cpu, 0);
+    }
+}
+
+#[test_case]
+fn wakeups_reach_idle_cpus() {
+    let executor = Executor::new().with_work_stealing();
+    let (sender, receiver) = oneshot::channel();
+    let mut handle = executor.spawner().spawn(async move {
+        let value = receiver.await.unwrap();
+        (value, smp::current().index())
+    });
+
+    // wait for the task to block on an AP, which then halts
+    wait_until(|| registry::task(handle.id()).is_some_and(|task| task.polls > 0));
+    sender.send(7u64).unwrap();
+    wait_until(|| handle.is_finished());
+    let (value, cpu) = handle.try_join().unwrap().unwrap();
+    assert_eq!(value, 7);
+    assert_ne!(cpu, 0);
+}
+
+/// Maps, fills and releases a region `rounds` times, checking that the pages
+/// read back what was written.
+fn map_and_release(rounds: u64) -> bool {
+    (0..rounds).all(|round| {
+        let region = vmm::reserve("smp test", 4).unwrap();
+        vmm::map_region(&region, PageTableFlags::WRITABLE).unwrap();
+        let words: *mut u64 = region.start.as_mut_ptr();
+        let count = (region.size() / 8) as usize;
+        let intact = unsafe {
+            (0..count).for_each(|index| words.add(index).write_volatile(round));
+            (0..count).all(|index| words.add(index).read_volatile() == round)
+        };
+        vmm::release(&region).unwrap();
+        intact
+    })
+}
+
+#[test_case]
+fn unmapping_on_all_cpus_at_once() {
+    let executor = Executor::new().with_work_stealing();
+    let spawner = executor.spawner();
+    let mut handles: Vec<_> = (0..3)
+        .map(|_| spawner.spawn(async { map_and_release(50) }))
+        .collect();
+
+    assert!(map_and_release(50));
+    wait_until(|| handles.iter().all(|handle| handle.is_finished()));
+    for handle in &mut handles {
+        assert!(handle.try_join().unwrap().unwrap());
+    }
+}
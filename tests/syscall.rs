@@ -0,0 +1,248 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use slate::allocator::HEAP_START;
+use slate::memory::address_space::{AddressSpace, USER_SPACE_START};
+use slate::memory::{self, vmm};
+use slate::syscall::{Syscall, SyscallError, MAX_SLEEP_MS};
+use slate::time;
+use slate::usermode::{self, Exit};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::BootInfoFrameAllocator;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+    slate::gdt::init_stacks().expect("stack setup failed");
+
+    test_main();
+    slate::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+const CODE: u64 = USER_SPACE_START;
+/// A read-only page after the code, for data passed to system calls.
+const DATA: u64 = USER_SPACE_START + vmm::PAGE_SIZE;
+
+/// Assembles user programs.
+struct Program(Vec<u8>);
+
+impl Program {
+    fn new() -> Program {
+        Program(Vec::new())
+    }
+
+    fn mov_eax(mut self, value: u32) -> Program {
+        self.0.push(0xb8);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn mov_edi(mut self, value: u32) -> Program {
+        self.0.push(0xbf);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn mov_esi(mut self, value: u32) -> Program {
+        self.0.push(0xbe);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn mov_rdi(mut self, value: u64) -> Program {
+        self.0.extend_from_slice(&[0x48, 0xbf]);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn mov_rdi_rax(mut self) -> Program {
+        self.0.extend_from_slice(&[0x48, 0x89, 0xc7]);
+        self
+    }
+
+    fn syscall(self, call: Syscall) -> Program {
+        self.syscall_number(call as u32)
+    }
+
+    fn syscall_number(self, number: u32) -> Program {
+        let mut program = self.mov_eax(number);
+        program.0.extend_from_slice(&[0x0f, 0x05]);
+        program
+    }
+
+    /// Exits with the result of the previous call as exit code.
+    fn exit_with_result(self) -> Vec<u8> {
+        self.mov_rdi_rax().exit()
+    }
+
+    /// Exits with the code in `rdi`, then `ud2` in case exit returns.
+    fn exit(self) -> Vec<u8> {
+        let mut program = self.syscall(Syscall::Exit);
+        program.0.extend_from_slice(&[0x0f, 0x0b]);
+        program.0
+    }
+}
+
+fn run(code: &[u8], data: &[u8]) -> Exit {
+    let mut space = AddressSpace::new().unwrap();
+    let code_page = Page::containing_address(VirtAddr::new(CODE));
+    space
+        .map_user(
+            Page::range(code_page, code_page + 2),
+            PageTableFlags::empty(),
+        )
+        .unwrap();
+    space.write(VirtAddr::new(CODE), code).unwrap();
+    space.write(VirtAddr::new(DATA), data).unwrap();
+    let stack_top = usermode::map_stack(&mut space, 1).unwrap();
+    usermode::run(&space, VirtAddr::new(CODE), stack_top)
+}
+
+#[test_case]
+fn exit_returns_code() {
+    let code = Program::new().mov_edi(42).exit();
+    assert_eq!(run(&code, &[]), Exit::Exited(42));
+}
+
+#[test_case]
+fn write_prints_text() {
+    let text = b"hello from user mode\n";
+    let code = Program::new()
+        .mov_rdi(DATA)
+        .mov_esi(text.len() as u32)
+        .syscall(Syscall::Write)
+        .exit_with_result();
+    assert_eq!(run(&code, text), Exit::Exited(text.len() as u64));
+}
+
+#[test_case]
+fn write_rejects_kernel_pointer() {
+    let code = Program::new()
+        .mov_rdi(HEAP_START as u64)
+        .mov_esi(8)
+        .syscall(Syscall::Write)
+        .exit_with_result();
+    assert_eq!(
+        run(&code, &[]),
+        Exit::Exited(SyscallError::BadAddress.encode())
+    );
+}
+
+#[test_case]
+fn write_rejects_unmapped_range() {
+    // the data page is followed by an unmapped one
+    let code = Program::new()
+        .mov_rdi(DATA + vmm::PAGE_SIZE - 4)
+        .mov_esi(8)
+        .syscall(Syscall::Write)
+        .exit_with_result();
+    assert_eq!(
+        run(&code, &[]),
+        Exit::Exited(SyscallError::BadAddress.encode())
+    );
+}
+
+#[test_case]
+fn write_rejects_invalid_utf8() {
+    let code = Program::new()
+        .mov_rdi(DATA)
+        .mov_esi(2)
+        .syscall(Syscall::Write)
+        .exit_with_result();
+    assert_eq!(
+        run(&code, &[0xc3, 0x28]),
+        Exit::Exited(SyscallError::InvalidArgument.encode())
+    );
+}
+
+#[test_case]
+fn read_key_without_input_would_block() {
+    let code = Program::new().syscall(Syscall::ReadKey).exit_with_result();
+    assert_eq!(
+        run(&code, &[]),
+        Exit::Exited(SyscallError::WouldBlock.encode())
+    );
+}
+
+#[test_case]
+fn sleep_waits() {
+    let duration = Duration::from_millis(200);
+    let code = Program::new()
+        .mov_edi(duration.as_millis() as u32)
+        .syscall(Syscall::Sleep)
+        .exit_with_result();
+    let start = time::ticks();
+    assert_eq!(run(&code, &[]), Exit::Exited(0));
+    assert!(time::ticks() - start >= time::duration_to_ticks(duration));
+}
+
+#[test_case]
+fn sleep_rejects_long_durations() {
+    let code = Program::new()
+        .mov_edi(MAX_SLEEP_MS as u32 + 1)
+        .syscall(Syscall::Sleep)
+        .exit_with_result();
+    assert_eq!(
+        run(&code, &[]),
+        Exit::Exited(SyscallError::InvalidArgument.encode())
+    );
+}
+
+#[test_case]
+fn uptime_is_kernel_uptime() {
+    let code = Program::new().syscall(Syscall::Uptime).exit_with_result();
+    let before = time::uptime().as_millis() as u64;
+    let Exit::Exited(uptime) = run(&code, &[]) else {
+        panic!("uptime program was killed");
+    };
+    let after = time::uptime().as_millis() as u64;
+    assert!((before..=after).contains(&uptime));
+}
+
+#[test_case]
+fn unknown_call_fails() {
+    let code = Program::new().syscall_number(99).exit_with_result();
+    assert_eq!(
+        run(&code, &[]),
+        Exit::Exited(SyscallError::UnknownCall.encode())
+    );
+}
+
+#[test_case]
+fn registers_are_preserved() {
+    let code = Program::new().mov_edi(7).syscall(Syscall::Uptime).exit();
+    assert_eq!(run(&code, &[]), Exit::Exited(7));
+}
+
+#[test_case]
+fn decode_separates_errors() {
+    assert_eq!(SyscallError::decode(5), Ok(5));
+    assert_eq!(
+        SyscallError::decode(SyscallError::WouldBlock.encode()),
+        Err(SyscallError::WouldBlock)
+    );
+}
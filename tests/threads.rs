@@ -0,0 +1,217 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use slate::memory::address_space::{AddressSpace, USER_SPACE_START};
+use slate::memory::{self, vmm};
+use slate::usermode::{self, Exit};
+use slate::{thread, time};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::BootInfoFrameAllocator;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+    slate::gdt::init_stacks().expect("stack setup failed");
+    thread::init().expect("thread setup failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_result() {
+    let handle = thread::spawn("worker", || (1..=10u64).sum::<u64>());
+    assert_eq!(handle.join(), 55);
+}
+
+#[test_case]
+fn threads_have_their_own_stacks() {
+    let handles: Vec<_> = (0..4u64)
+        .map(|n| {
+            thread::spawn("worker", move || {
+                let local = [n; 64];
+                thread::yield_now();
+                local.iter().sum::<u64>()
+            })
+        })
+        .collect();
+    let results: Vec<u64> = handles.into_iter().map(|handle| handle.join()).collect();
+    assert_eq!(results, [0, 64, 128, 192]);
+}
+
+#[test_case]
+fn busy_threads_are_preempted() {
+    // neither the workers nor this loop ever yield, so only the timer can
+    // switch between them
+    let stop = Arc::new(AtomicBool::new(false));
+    let counters: Vec<_> = (0..3).map(|_| Arc::new(AtomicU64::new(0))).collect();
+    let handles: Vec<_> = counters
+        .iter()
+        .map(|counter| {
+            let (counter, stop) = (counter.clone(), stop.clone());
+            thread::spawn("spinner", move || {
+                while !stop.load(Ordering::Relaxed) {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    while counters
+        .iter()
+        .any(|counter| counter.load(Ordering::Relaxed) == 0)
+    {
+        core::hint::spin_loop();
+    }
+    stop.store(true, Ordering::Relaxed);
+    for handle in handles {
+        handle.join();
+    }
+}
+
+#[test_case]
+fn yield_lets_other_threads_run() {
+    let flag = Arc::new(AtomicBool::new(false));
+    let setter = flag.clone();
+    let handle = thread::spawn("setter", move || setter.store(true, Ordering::Relaxed));
+    while !flag.load(Ordering::Relaxed) {
+        thread::yield_now();
+    }
+    handle.join();
+}
+
+#[test_case]
+fn sleeping_threads_let_others_run() {
+    let duration = Duration::from_millis(100);
+    let count = Arc::new(AtomicU64::new(0));
+    let counter = count.clone();
+    let start = time::ticks();
+    let handle = thread::spawn("sleeper", move || {
+        thread::sleep(duration);
+        counter.load(Ordering::Relaxed)
+    });
+    while !handle.is_finished() {
+        count.fetch_add(1, Ordering::Relaxed);
+        thread::yield_now();
+    }
+    assert!(handle.join() > 0);
+    assert!(time::ticks() - start >= time::duration_to_ticks(duration));
+}
+
+#[test_case]
+fn join_after_finish() {
+    let handle = thread::spawn("quick", || 7);
+    while !handle.is_finished() {
+        thread::yield_now();
+    }
+    assert_eq!(handle.join(), 7);
+}
+
+#[test_case]
+fn detached_threads_finish() {
+    let done = Arc::new(AtomicBool::new(false));
+    let setter = done.clone();
+    drop(thread::spawn("detached", move || {
+        setter.store(true, Ordering::Relaxed)
+    }));
+    while !done.load(Ordering::Relaxed) {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn finished_threads_are_freed() {
+    // more threads than there are stacks, one after the other
+    for n in 0..(memory::stack::MAX_STACKS as u64 * 2) {
+        assert_eq!(thread::spawn("short", move || n).join(), n);
+    }
+}
+
+#[test_case]
+fn current_differs_between_threads() {
+    let main = thread::current().unwrap();
+    let handle = thread::spawn("worker", thread::current);
+    let worker = handle.id();
+    assert_eq!(handle.join(), Some(worker));
+    assert_ne!(main, worker);
+    assert_eq!(thread::current(), Some(main));
+}
+
+/// Calls `uptime` until 300 ms have passed, then exits with code 0.
+const SPIN: &[u8] = &[
+    0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, SYS_UPTIME
+    0x0f, 0x05, // syscall
+    0x48, 0x8d, 0x98, 0x2c, 0x01, 0x00, 0x00, // lea rbx, [rax + 300]
+    0xb8, 0x04, 0x00, 0x00, 0x00, // 1: mov eax, SYS_UPTIME
+    0x0f, 0x05, // syscall
+    0x48, 0x39, 0xd8, // cmp rax, rbx
+    0x72, 0xf4, // jb 1b
+    0x31, 0xff, // xor edi, edi
+    0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, SYS_EXIT
+    0x0f, 0x05, // syscall
+    0x0f, 0x0b, // ud2
+];
+
+/// Runs `SPIN` in an address space of its own.
+fn run_spin() -> Exit {
+    let mut space = AddressSpace::new().unwrap();
+    let entry = VirtAddr::new(USER_SPACE_START);
+    let page = Page::containing_address(entry);
+    space
+        .map_user(Page::range(page, page + 1), PageTableFlags::empty())
+        .unwrap();
+    space.write(entry, SPIN).unwrap();
+    let stack_top = usermode::map_stack(&mut space, 1).unwrap();
+    usermode::run(&space, entry, stack_top)
+}
+
+#[test_case]
+fn user_programs_are_preempted() {
+    let started = Arc::new(AtomicBool::new(false));
+    let setter = started.clone();
+    let handle = thread::spawn("user", move || {
+        setter.store(true, Ordering::Relaxed);
+        run_spin()
+    });
+    while !started.load(Ordering::Relaxed) {
+        thread::yield_now();
+    }
+    // only the timer can switch back here before the program ends
+    thread::yield_now();
+    assert!(!handle.is_finished());
+    assert_eq!(handle.join(), Exit::Exited(0));
+}
+
+#[test_case]
+fn threads_run_user_programs_at_once() {
+    let handles: Vec<_> = (0..2).map(|_| thread::spawn("user", run_spin)).collect();
+    for handle in handles {
+        assert_eq!(handle.join(), Exit::Exited(0));
+    }
+}
@@ -0,0 +1,148 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use slate::allocator::HEAP_START;
+use slate::memory::address_space::{AddressSpace, USER_SPACE_START};
+use slate::memory::{self, vmm};
+use slate::usermode::{self, Exception, Exit, Fault};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::BootInfoFrameAllocator;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+    slate::gdt::init_stacks().expect("stack setup failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+const ENTRY: u64 = USER_SPACE_START;
+
+/// `ud2`
+const UD2: &[u8] = &[0x0f, 0x0b];
+
+/// Sets up a space with `code` on a read-only page at `ENTRY`, runs it and
+/// returns the fault that killed it.
+fn run(code: &[u8], stack_pages: u64) -> Fault {
+    let mut space = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(ENTRY));
+    space
+        .map_user(Page::range(page, page + 1), PageTableFlags::empty())
+        .unwrap();
+    space.write(VirtAddr::new(ENTRY), code).unwrap();
+    let stack_top = usermode::map_stack(&mut space, stack_pages).unwrap();
+
+    match usermode::run(&space, VirtAddr::new(ENTRY), stack_top) {
+        Exit::Killed(fault) => fault,
+        exit => panic!("program was not killed: {}", exit),
+    }
+}
+
+fn at(offset: u64) -> VirtAddr {
+    VirtAddr::new(ENTRY + offset)
+}
+
+#[test_case]
+fn invalid_opcode_kills_program() {
+    let fault = run(UD2, 1);
+    assert_eq!(fault.exception, Exception::InvalidOpcode);
+    assert_eq!(fault.instruction_pointer, at(0));
+}
+
+#[test_case]
+fn program_survives_timer_interrupts() {
+    // mov ecx, 0x1000_0000; 1: dec ecx; jnz 1b; ud2
+    let code = [
+        0xb9, 0x00, 0x00, 0x00, 0x10, 0xff, 0xc9, 0x75, 0xfc, 0x0f, 0x0b,
+    ];
+    let fault = run(&code, 1);
+    assert_eq!(fault.exception, Exception::InvalidOpcode);
+    assert_eq!(fault.instruction_pointer, at(9));
+}
+
+#[test_case]
+fn program_can_use_its_stack() {
+    // push rax; pop rax; ud2
+    let fault = run(&[0x50, 0x58, 0x0f, 0x0b], 1);
+    assert_eq!(fault.exception, Exception::InvalidOpcode);
+    assert_eq!(fault.instruction_pointer, at(2));
+}
+
+#[test_case]
+fn divide_by_zero_kills_program() {
+    // xor ecx, ecx; div ecx
+    let fault = run(&[0x31, 0xc9, 0xf7, 0xf1], 1);
+    assert_eq!(fault.exception, Exception::DivideError);
+    assert_eq!(fault.instruction_pointer, at(2));
+}
+
+#[test_case]
+fn privileged_instruction_kills_program() {
+    // hlt
+    let fault = run(&[0xf4], 1);
+    assert_eq!(fault.exception, Exception::GeneralProtectionFault);
+    assert_eq!(fault.error_code, Some(0));
+}
+
+#[test_case]
+fn kernel_memory_is_not_accessible() {
+    // mov rax, [HEAP_START]
+    let mut code = [0x48, 0xa1, 0, 0, 0, 0, 0, 0, 0, 0];
+    code[2..].copy_from_slice(&(HEAP_START as u64).to_le_bytes());
+    let fault = run(&code, 1);
+    assert_eq!(fault.exception, Exception::PageFault);
+    assert_eq!(fault.instruction_pointer, at(0));
+    assert_eq!(fault.address, Some(VirtAddr::new(HEAP_START as u64)));
+}
+
+#[test_case]
+fn code_is_read_only() {
+    // mov byte [rip - 7], 0 (the instruction itself)
+    let fault = run(&[0xc6, 0x05, 0xf9, 0xff, 0xff, 0xff, 0x00], 1);
+    assert_eq!(fault.exception, Exception::PageFault);
+    assert_eq!(fault.address, Some(at(0)));
+}
+
+#[test_case]
+fn unmapped_stack_kills_program() {
+    // push rax
+    let fault = run(&[0x50], 0);
+    assert_eq!(fault.exception, Exception::PageFault);
+    assert_eq!(
+        fault.address,
+        Some(VirtAddr::new(usermode::USER_STACK_TOP - 8))
+    );
+}
+
+#[test_case]
+fn kernel_keeps_running_after_kill() {
+    run(UD2, 1);
+    run(UD2, 1);
+    let boxed = Box::new(41);
+    assert_eq!(*boxed + 1, 42);
+    assert!(vmm::translate(VirtAddr::new(ENTRY)).is_none());
+}
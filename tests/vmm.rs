@@ -0,0 +1,167 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(slate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use slate::allocator::HEAP_START;
+use slate::memory::vmm::{self, VmError, KERNEL_SPACE_END, KERNEL_SPACE_START};
+use slate::memory::{self, GlobalFrameAllocator};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use slate::allocator;
+    use slate::memory::BootInfoFrameAllocator;
+
+    slate::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+    vmm::init(mapper);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    slate::test_panic_handler(info)
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::WRITABLE;
+
+#[test_case]
+fn reserved_regions_do_not_overlap() {
+    let a = vmm::reserve("a", 3).unwrap();
+    let b = vmm::reserve("b", 5).unwrap();
+    assert!(a.end() <= b.start || b.end() <= a.start);
+    for region in [a, b] {
+        assert!(region.start.as_u64() >= KERNEL_SPACE_START);
+        assert!(region.end().as_u64() <= KERNEL_SPACE_END);
+    }
+
+    let regions = vmm::regions();
+    assert!(regions.windows(2).all(|w| w[0].end() <= w[1].start));
+    assert!(regions.iter().any(|r| r.name == "heap"));
+
+    vmm::release(&a).unwrap();
+    vmm::release(&b).unwrap();
+}
+
+#[test_case]
+fn reserve_at_rejects_overlap() {
+    let result = vmm::reserve_at("clash", VirtAddr::new(HEAP_START as u64), 1);
+    match result {
+        Err(VmError::Overlap { existing }) => assert_eq!(existing.name, "heap"),
+        other => panic!("expected overlap, got {:?}", other),
+    }
+}
+
+#[test_case]
+fn reserve_at_rejects_addresses_outside_window() {
+    let result = vmm::reserve_at("low", VirtAddr::new(0x1000), 1);
+    assert_eq!(result, Err(VmError::OutOfBounds));
+    let result = vmm::reserve_at("high", VirtAddr::new(KERNEL_SPACE_END - 4096), 2);
+    assert_eq!(result, Err(VmError::OutOfBounds));
+}
+
+#[test_case]
+fn released_range_is_reused() {
+    let first = vmm::reserve("first", 4).unwrap();
+    vmm::release(&first).unwrap();
+    let second = vmm::reserve("second", 4).unwrap();
+    assert_eq!(first.start, second.start);
+    vmm::release(&second).unwrap();
+}
+
+#[test_case]
+fn mapped_region_is_zeroed_and_writable() {
+    let region = vmm::reserve("map", 2).unwrap();
+    vmm::map_region(&region, FLAGS).unwrap();
+
+    let ptr: *mut u64 = region.start.as_mut_ptr();
+    let words = (region.size() / 8) as usize;
+    unsafe {
+        for i in 0..words {
+            assert_eq!(ptr.add(i).read_volatile(), 0);
+            ptr.add(i).write_volatile(i as u64);
+        }
+        for i in 0..words {
+            assert_eq!(ptr.add(i).read_volatile(), i as u64);
+        }
+    }
+    assert!(vmm::translate(region.start).is_some());
+
+    vmm::release(&region).unwrap();
+}
+
+#[test_case]
+fn unmap_frees_frames_and_removes_mapping() {
+    let region = vmm::reserve("unmap", 1).unwrap();
+    vmm::map_region(&region, FLAGS).unwrap();
+    let phys = vmm::translate(region.start).unwrap();
+
+    vmm::unmap_region(&region).unwrap();
+    assert_eq!(vmm::translate(region.start), None);
+
+    // the freed frame is the first one handed out again
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    assert_eq!(frame.start_address(), phys);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+
+    vmm::release(&region).unwrap();
+}
+
+#[test_case]
+fn mapping_twice_fails() {
+    let region = vmm::reserve("twice", 2).unwrap();
+    vmm::map_region(&region, FLAGS).unwrap();
+    assert_eq!(
+        vmm::map_region(&region, FLAGS),
+        Err(VmError::AlreadyMapped {
+            page: Page::containing_address(region.start)
+        })
+    );
+    // the pages mapped the first time are still intact
+    assert!(vmm::translate(region.start).is_some());
+    vmm::release(&region).unwrap();
+}
+
+#[test_case]
+fn mapping_outside_regions_fails() {
+    let page = Page::containing_address(VirtAddr::new(KERNEL_SPACE_END - 4096));
+    assert_eq!(
+        vmm::map_pages(Page::range(page, page + 1), FLAGS),
+        Err(VmError::NotReserved)
+    );
+}
+
+#[test_case]
+fn map_region_to_physical_memory() {
+    let vga = PhysAddr::new(0xb8000);
+    let region = vmm::reserve("vga", 1).unwrap();
+    vmm::map_region_to(&region, vga, FLAGS).unwrap();
+    assert_eq!(vmm::translate(region.start), Some(vga));
+
+    let through_region: *const u16 = region.start.as_ptr();
+    let through_offset: *const u16 = memory::phys_to_virt(vga).as_ptr();
+    unsafe {
+        assert_eq!(
+            through_region.read_volatile(),
+            through_offset.read_volatile()
+        );
+    }
+
+    // the VGA buffer is not owned by the region and must not be freed
+    vmm::release(&region).unwrap();
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    assert_ne!(frame.start_address(), vga);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
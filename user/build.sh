@@ -0,0 +1,15 @@
+#!/bin/sh
+# Builds the user programs embedded in the kernel (see `usermode::programs`)
+# into bin/. The binaries are checked in, so this is only needed after
+# changing a program.
+set -e
+cd "$(dirname "$0")"
+mkdir -p bin
+for source in *.S; do
+    name="${source%.S}"
+    as --64 -o "bin/$name.o" "$source"
+    ld -static -nostdlib --build-id=none -z max-page-size=4096 -z noexecstack \
+        -T link.ld -o "bin/$name" "bin/$name.o"
+    strip "bin/$name"
+    rm "bin/$name.o"
+done